@@ -0,0 +1,348 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, SyncSender, TrySendError};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use chrono::{DateTime, Local, SecondsFormat};
+use serde::{Serialize, Serializer};
+use tracing::warn;
+
+use crate::config::{AccessLogConfig, AccessLogFormat};
+
+/// What the access log records about one request
+#[derive(Debug, Serialize)]
+pub struct AccessLogEntry {
+    #[serde(serialize_with = "rfc3339")]
+    pub time: DateTime<Local>,
+    /// Address of the downstream connection
+    pub client_addr: Option<String>,
+    /// Identity of the client (see `clients.identify_by`)
+    pub client: String,
+    pub method: String,
+    pub path: String,
+    pub protocol: String,
+    /// Response status, unset if no response was sent
+    pub status: Option<u16>,
+    /// Response body bytes sent to the client
+    pub bytes: usize,
+    /// From sending the request upstream until the response finished,
+    /// unset if it never went upstream
+    #[serde(rename = "upstream_latency_ms", serialize_with = "millis")]
+    pub upstream_latency: Option<Duration>,
+    /// Time spent waiting for a token, zero if the connection already held one
+    #[serde(rename = "token_wait_ms", serialize_with = "millis")]
+    pub token_wait: Option<Duration>,
+    pub token_id: Option<usize>,
+    /// Permission profile of the pool the token came from
+    pub pool: &'static str,
+    pub referer: Option<String>,
+    pub user_agent: Option<String>,
+}
+
+fn rfc3339<S: Serializer>(time: &DateTime<Local>, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&time.to_rfc3339_opts(SecondsFormat::Millis, false))
+}
+
+fn millis<S: Serializer>(duration: &Option<Duration>, serializer: S) -> Result<S::Ok, S::Error> {
+    match duration {
+        Some(duration) => serializer.serialize_f64(duration.as_secs_f64() * 1000.0),
+        None => serializer.serialize_none(),
+    }
+}
+
+impl AccessLogEntry {
+    /// Apache combined log format, followed by tpp's own fields as `key=value`
+    fn combined(&self) -> String {
+        format!(
+            "{} - {} [{}] \"{} {} {}\" {} {} {} {} upstream_latency={} token_wait={} token={} pool={}",
+            self.client_addr.as_deref().unwrap_or("-"),
+            unquoted(&self.client),
+            self.time.format("%d/%b/%Y:%H:%M:%S %z"),
+            self.method,
+            self.path,
+            self.protocol,
+            self.status.map_or("-".to_string(), |s| s.to_string()),
+            if self.bytes == 0 {
+                "-".to_string()
+            } else {
+                self.bytes.to_string()
+            },
+            quoted(self.referer.as_deref()),
+            quoted(self.user_agent.as_deref()),
+            seconds(self.upstream_latency),
+            seconds(self.token_wait),
+            self.token_id.map_or("-".to_string(), |id| id.to_string()),
+            self.pool,
+        )
+    }
+}
+
+/// Quote a header value for the combined format, "-" if missing
+fn quoted(value: Option<&str>) -> String {
+    match value {
+        Some(value) => format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\"")),
+        None => "\"-\"".to_string(),
+    }
+}
+
+/// Escape a value for an unquoted field of the combined format, so spaces or
+/// quotes cannot split it, "-" if empty
+fn unquoted(value: &str) -> String {
+    if value.is_empty() {
+        return "-".to_string();
+    }
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '"' | '\\' => {
+                escaped.push('\\');
+                escaped.push(c);
+            }
+            c if c.is_ascii_whitespace() || c.is_control() => {
+                escaped.push_str(&format!("\\x{:02x}", c as u32));
+            }
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn seconds(duration: Option<Duration>) -> String {
+    duration.map_or("-".to_string(), |d| format!("{:.3}", d.as_secs_f64()))
+}
+
+enum Sink {
+    Stdout,
+    File(RotatingFile),
+}
+
+impl Sink {
+    fn write_line(&mut self, line: &str) -> io::Result<()> {
+        match self {
+            Sink::Stdout => writeln!(io::stdout().lock(), "{}", line),
+            Sink::File(file) => file.write_line(line),
+        }
+    }
+}
+
+/// Lines waiting for the writer; more are dropped rather than holding up
+/// requests
+const QUEUED_LINES: usize = 8192;
+
+/// Writes an access log line per request to stdout or a rotating file
+///
+/// Lines are written by a dedicated thread, so logging a request never blocks
+/// on I/O.
+pub struct AccessLog {
+    format: AccessLogFormat,
+    lines: Option<SyncSender<String>>,
+    writer: Option<JoinHandle<()>>,
+    /// Lines dropped because the writer fell behind
+    dropped: AtomicU64,
+}
+
+impl AccessLog {
+    pub fn new(config: &AccessLogConfig) -> io::Result<Self> {
+        let sink = match config.path {
+            Some(ref path) => Sink::File(RotatingFile::open(
+                PathBuf::from(path),
+                config.max_file_bytes,
+                config.max_files,
+            )?),
+            None => Sink::Stdout,
+        };
+        let (lines, queued) = mpsc::sync_channel(QUEUED_LINES);
+        let writer = thread::Builder::new()
+            .name("access-log".to_string())
+            .spawn(move || write_lines(sink, queued))?;
+        Ok(Self {
+            format: config.format,
+            lines: Some(lines),
+            writer: Some(writer),
+            dropped: AtomicU64::new(0),
+        })
+    }
+
+    fn format(&self, entry: &AccessLogEntry) -> String {
+        match self.format {
+            AccessLogFormat::Json => serde_json::to_string(entry).unwrap_or_default(),
+            AccessLogFormat::Combined => entry.combined(),
+        }
+    }
+
+    /// Queue the line for a finished request
+    pub fn log(&self, entry: &AccessLogEntry) {
+        let Some(ref lines) = self.lines else {
+            return;
+        };
+        if let Err(TrySendError::Full(_)) = lines.try_send(self.format(entry)) {
+            // Warn once per power of two, not for every line
+            let dropped = self.dropped.fetch_add(1, Ordering::Relaxed) + 1;
+            if dropped.is_power_of_two() {
+                warn!("Access log is behind, {} lines dropped", dropped);
+            }
+        }
+    }
+}
+
+impl Drop for AccessLog {
+    /// Write the queued lines before returning
+    fn drop(&mut self) {
+        drop(self.lines.take());
+        if let Some(writer) = self.writer.take() {
+            let _ = writer.join();
+        }
+    }
+}
+
+fn write_lines(mut sink: Sink, queued: mpsc::Receiver<String>) {
+    for line in queued {
+        if let Err(e) = sink.write_line(&line) {
+            warn!("Failed to write access log: {}", e);
+        }
+    }
+}
+
+/// File that is moved aside once it grows past a size limit
+struct RotatingFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    max_bytes: u64,
+    max_files: usize,
+}
+
+impl RotatingFile {
+    fn open(path: PathBuf, max_bytes: u64, max_files: usize) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let size = file.metadata()?.len();
+        Ok(Self {
+            path,
+            file,
+            size,
+            max_bytes,
+            max_files,
+        })
+    }
+
+    fn write_line(&mut self, line: &str) -> io::Result<()> {
+        let line = format!("{}\n", line);
+        if self.size > 0 && self.size + line.len() as u64 > self.max_bytes {
+            self.rotate()?;
+        }
+        self.file.write_all(line.as_bytes())?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+
+    /// Shift each rotated file one number up, dropping the oldest, move the
+    /// current file to `<path>.1` and start a new one
+    fn rotate(&mut self) -> io::Result<()> {
+        if self.max_files == 0 {
+            self.file = File::create(&self.path)?;
+        } else {
+            for n in (1..self.max_files).rev() {
+                let from = self.rotated(n);
+                if from.exists() {
+                    fs::rename(&from, self.rotated(n + 1))?;
+                }
+            }
+            fs::rename(&self.path, self.rotated(1))?;
+            self.file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&self.path)?;
+        }
+        self.size = 0;
+        Ok(())
+    }
+
+    fn rotated(&self, n: usize) -> PathBuf {
+        let mut name = self.path.clone().into_os_string();
+        name.push(format!(".{}", n));
+        name.into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn entry() -> AccessLogEntry {
+        AccessLogEntry {
+            time: Local.with_ymd_and_hms(2025, 10, 18, 9, 30, 0).unwrap(),
+            client_addr: Some("10.0.0.7:51234".to_string()),
+            client: "etl-service".to_string(),
+            method: "POST".to_string(),
+            path: "/api/executeCode".to_string(),
+            protocol: "HTTP/1.1".to_string(),
+            status: Some(200),
+            bytes: 512,
+            upstream_latency: Some(Duration::from_millis(42)),
+            token_wait: Some(Duration::ZERO),
+            token_id: Some(3),
+            pool: "read_write",
+            referer: None,
+            user_agent: Some("curl/8.5.0".to_string()),
+        }
+    }
+
+    #[test]
+    fn test_formats() {
+        let line = entry().combined();
+        assert!(line.starts_with("10.0.0.7:51234 - etl-service [18/Oct/2025:09:30:00 "));
+        assert!(line.ends_with(
+            "] \"POST /api/executeCode HTTP/1.1\" 200 512 \"-\" \"curl/8.5.0\" \
+             upstream_latency=0.042 token_wait=0.000 token=3 pool=read_write"
+        ));
+
+        // A client id from a header stays one field
+        let line = AccessLogEntry {
+            client: "etl \"prod\"".to_string(),
+            ..entry()
+        }
+        .combined();
+        assert!(line.starts_with("10.0.0.7:51234 - etl\\x20\\\"prod\\\" [18/Oct/2025"));
+
+        let json: serde_json::Value = serde_json::to_value(entry()).unwrap();
+        assert_eq!(json["client"], "etl-service");
+        assert_eq!(json["status"], 200);
+        assert_eq!(json["upstream_latency_ms"], 42.0);
+        assert_eq!(json["token_wait_ms"], 0.0);
+        assert_eq!(json["token_id"], 3);
+        assert!(json["referer"].is_null());
+    }
+
+    #[test]
+    fn test_file_rotates_by_size() {
+        let dir = std::env::temp_dir().join(format!("tpp-access-log-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("access.log");
+        let config = AccessLogConfig {
+            enabled: true,
+            format: AccessLogFormat::Combined,
+            path: Some(path.to_string_lossy().to_string()),
+            max_file_bytes: 300,
+            max_files: 2,
+        };
+
+        let log = AccessLog::new(&config).unwrap();
+        for _ in 0..5 {
+            log.log(&entry());
+        }
+        drop(log);
+
+        // Each line is over 150 bytes: one per file, the oldest two dropped
+        let lines = |name: &str| fs::read_to_string(dir.join(name)).unwrap().lines().count();
+        assert_eq!(lines("access.log"), 1);
+        assert_eq!(lines("access.log.1"), 1);
+        assert_eq!(lines("access.log.2"), 1);
+        assert!(!dir.join("access.log.3").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
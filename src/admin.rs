@@ -0,0 +1,578 @@
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use axum::extract::{ConnectInfo, OriginalUri, Path, Request, State};
+use axum::http::{header, StatusCode};
+use axum::middleware::{self, Next};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tracing::{info, warn};
+
+use crate::config::{Config, Profile};
+use crate::drain::Drain;
+use crate::telemetry::{
+    check_log_filter, log_filter, log_filter_reverts_in, revert_log_filter, set_log_filter,
+    set_log_filter_for,
+};
+use crate::token_acquirer::TokenAcquirer;
+use crate::token_pool::{ClientQuotas, TokenPool};
+
+/// Log target of the audit trail of admin actions
+const AUDIT_TARGET: &str = "tpp::audit";
+
+/// Operational control over the running proxy, served under `/admin` on the
+/// health server
+pub struct Admin {
+    /// Key admin requests must present as a bearer token
+    api_key: String,
+    pools: BTreeMap<Profile, Arc<TokenPool>>,
+    acquirer: TokenAcquirer,
+    drain: Arc<Drain>,
+    /// Config file reloaded on request (environment variables if unset)
+    config_path: Option<PathBuf>,
+    /// Keeps concurrent resizes from acquiring tokens twice
+    resizing: tokio::sync::Mutex<()>,
+}
+
+impl Admin {
+    pub fn new(api_key: String, acquirer: TokenAcquirer, drain: Arc<Drain>) -> Self {
+        Self {
+            api_key,
+            pools: BTreeMap::new(),
+            acquirer,
+            drain,
+            config_path: None,
+            resizing: tokio::sync::Mutex::new(()),
+        }
+    }
+
+    /// Control the token pool of a permission profile
+    pub fn with_pool(mut self, profile: Profile, pool: Arc<TokenPool>) -> Self {
+        self.pools.insert(profile, pool);
+        self
+    }
+
+    /// Reload configuration from this file instead of the environment
+    pub fn with_config_path(mut self, path: PathBuf) -> Self {
+        self.config_path = Some(path);
+        self
+    }
+
+    fn pool(&self, name: &str) -> Result<(Profile, &Arc<TokenPool>), AdminError> {
+        name.parse::<Profile>()
+            .ok()
+            .and_then(|profile| self.pools.get(&profile).map(|pool| (profile, pool)))
+            .ok_or_else(|| AdminError::not_found(format!("unknown pool '{}'", name)))
+    }
+
+    /// Grow the pool with newly acquired tokens or shrink it to `size`
+    async fn resize(
+        &self,
+        profile: Profile,
+        pool: &TokenPool,
+        size: usize,
+    ) -> Result<usize, AdminError> {
+        if size == 0 {
+            return Err(AdminError::bad_request("pool size must be > 0"));
+        }
+        if size <= pool.reserved_high() {
+            return Err(AdminError::bad_request(
+                "pool size must be greater than 'priority.reserved_high'",
+            ));
+        }
+
+        let _resizing = self.resizing.lock().await;
+        let tokens = self.acquire_growth(pool, size).await?;
+        Ok(self.apply_size(profile, pool, size, tokens))
+    }
+
+    /// Acquire the tokens growing `pool` to `size` takes, none if it is that
+    /// large already
+    async fn acquire_growth(
+        &self,
+        pool: &TokenPool,
+        size: usize,
+    ) -> Result<Vec<String>, AdminError> {
+        let from = pool.total();
+        if size <= from {
+            return Ok(Vec::new());
+        }
+        self.acquirer
+            .acquire_n(&pool.credential(), size - from)
+            .await
+            .map_err(|e| AdminError::new(StatusCode::BAD_GATEWAY, e.to_string()))
+    }
+
+    /// Add the acquired `tokens` to `pool`, or shrink it to `size`
+    fn apply_size(
+        &self,
+        profile: Profile,
+        pool: &TokenPool,
+        size: usize,
+        tokens: Vec<String>,
+    ) -> usize {
+        let from = pool.total();
+        if !tokens.is_empty() {
+            pool.add_tokens(tokens);
+        } else if size < from {
+            pool.shrink_to(size);
+        }
+
+        let to = pool.total();
+        info!(target: AUDIT_TARGET, pool = %profile, from, to, "Pool resized");
+        to
+    }
+}
+
+/// Error answered as `{"error": "..."}`
+#[derive(Debug)]
+pub struct AdminError {
+    status: StatusCode,
+    message: String,
+}
+
+impl AdminError {
+    fn new(status: StatusCode, message: impl Into<String>) -> Self {
+        Self {
+            status,
+            message: message.into(),
+        }
+    }
+
+    fn bad_request(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::BAD_REQUEST, message)
+    }
+
+    fn not_found(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::NOT_FOUND, message)
+    }
+}
+
+impl IntoResponse for AdminError {
+    fn into_response(self) -> Response {
+        (self.status, Json(json!({ "error": self.message }))).into_response()
+    }
+}
+
+type AdminResult = Result<Json<Value>, AdminError>;
+
+/// Compare keys without returning early on the first mismatching byte
+fn keys_match(presented: &[u8], expected: &[u8]) -> bool {
+    presented.len() == expected.len()
+        && presented
+            .iter()
+            .zip(expected)
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+/// Reject requests without the API key and audit every admin request
+async fn authorize(State(admin): State<Arc<Admin>>, request: Request, next: Next) -> Response {
+    let caller = request
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map_or_else(|| "unknown".to_string(), |info| info.0.to_string());
+    let method = request.method().clone();
+    let path = request.extensions().get::<OriginalUri>().map_or_else(
+        || request.uri().path().to_string(),
+        |uri| uri.path().to_string(),
+    );
+
+    let presented = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    if !presented.is_some_and(|key| keys_match(key.as_bytes(), admin.api_key.as_bytes())) {
+        warn!(
+            target: AUDIT_TARGET,
+            %caller,
+            %method,
+            %path,
+            "Rejected admin request without a valid API key"
+        );
+        return AdminError::new(StatusCode::UNAUTHORIZED, "missing or invalid admin API key")
+            .into_response();
+    }
+
+    let response = next.run(request).await;
+    info!(
+        target: AUDIT_TARGET,
+        %caller,
+        %method,
+        %path,
+        status = response.status().as_u16(),
+        "Admin action"
+    );
+    response
+}
+
+/// Force a refresh of one token
+async fn refresh_token(
+    State(admin): State<Arc<Admin>>,
+    Path((pool, id)): Path<(String, usize)>,
+) -> AdminResult {
+    let (profile, pool) = admin.pool(&pool)?;
+    if !pool.mark_needs_refresh(id) {
+        return Err(AdminError::not_found(format!("unknown token #{}", id)));
+    }
+    Ok(Json(
+        json!({ "pool": profile, "token": id, "refresh": "pending" }),
+    ))
+}
+
+/// Force a refresh of every token of a pool
+async fn refresh_pool(State(admin): State<Arc<Admin>>, Path(pool): Path<String>) -> AdminResult {
+    let (profile, pool) = admin.pool(&pool)?;
+    let marked = pool.mark_all_need_refresh();
+    Ok(Json(
+        json!({ "pool": profile, "tokens": marked, "refresh": "pending" }),
+    ))
+}
+
+/// Withhold a token from clients
+async fn quarantine_token(
+    State(admin): State<Arc<Admin>>,
+    Path((pool, id)): Path<(String, usize)>,
+) -> AdminResult {
+    let (profile, pool) = admin.pool(&pool)?;
+    if !pool.quarantine(id) {
+        return Err(AdminError::not_found(format!("unknown token #{}", id)));
+    }
+    Ok(Json(
+        json!({ "pool": profile, "token": id, "quarantined": true }),
+    ))
+}
+
+/// Hand a quarantined token to clients again
+async fn unquarantine_token(
+    State(admin): State<Arc<Admin>>,
+    Path((pool, id)): Path<(String, usize)>,
+) -> AdminResult {
+    let (profile, pool) = admin.pool(&pool)?;
+    if !pool.unquarantine(id) {
+        return Err(AdminError::not_found(format!(
+            "token #{} is not quarantined",
+            id
+        )));
+    }
+    Ok(Json(
+        json!({ "pool": profile, "token": id, "quarantined": false }),
+    ))
+}
+
+#[derive(Deserialize)]
+struct ResizeRequest {
+    size: usize,
+}
+
+/// Grow or shrink a pool
+async fn resize_pool(
+    State(admin): State<Arc<Admin>>,
+    Path(pool): Path<String>,
+    Json(request): Json<ResizeRequest>,
+) -> AdminResult {
+    let (profile, pool) = admin.pool(&pool)?;
+    let size = admin.resize(profile, pool, request.size).await?;
+    Ok(Json(json!({ "pool": profile, "size": size })))
+}
+
+/// Refuse new requests so the instance can be taken out of rotation
+async fn start_drain(State(admin): State<Arc<Admin>>) -> Json<Value> {
+    if admin.drain.start() {
+        warn!(target: AUDIT_TARGET, "Draining, new requests are refused");
+    }
+    Json(json!({ "draining": true }))
+}
+
+/// Accept requests again after draining
+async fn stop_drain(State(admin): State<Arc<Admin>>) -> Json<Value> {
+    if admin.drain.stop() {
+        info!(target: AUDIT_TARGET, "Drain cancelled, accepting requests");
+    }
+    Json(json!({ "draining": false }))
+}
+
+async fn get_log_filter() -> Json<Value> {
+    Json(json!({
+        "filter": log_filter(),
+        "reverts_in_seconds": log_filter_reverts_in().map(|left| left.as_secs()),
+    }))
+}
+
+#[derive(Deserialize)]
+struct LogFilterRequest {
+    filter: String,
+    /// Revert to the previous filter after this long (permanent if unset)
+    duration_seconds: Option<u64>,
+}
+
+/// Replace the log filter, e.g. `info,tpp=debug`, permanently or for a while
+async fn put_log_filter(Json(request): Json<LogFilterRequest>) -> AdminResult {
+    let previous = log_filter();
+    match request.duration_seconds {
+        Some(0) => return Err(AdminError::bad_request("duration_seconds must be > 0")),
+        Some(secs) => set_log_filter_for(&request.filter, Duration::from_secs(secs)),
+        None => set_log_filter(&request.filter),
+    }
+    .map_err(AdminError::bad_request)?;
+    info!(
+        target: AUDIT_TARGET,
+        from = previous.as_deref().unwrap_or("-"),
+        to = %request.filter,
+        duration_seconds = request.duration_seconds,
+        "Log filter changed"
+    );
+    Ok(Json(json!({
+        "filter": request.filter,
+        "reverts_in_seconds": request.duration_seconds,
+    })))
+}
+
+/// End a temporary log filter early
+async fn delete_log_filter() -> AdminResult {
+    if !revert_log_filter() {
+        return Err(AdminError::not_found("no temporary log filter in effect"));
+    }
+    info!(target: AUDIT_TARGET, to = log_filter().as_deref().unwrap_or("-"), "Log filter reverted");
+    Ok(Json(json!({ "filter": log_filter() })))
+}
+
+/// Re-read the configuration and apply the settings that can change at
+/// runtime: the log filter, client quotas, reserved high-priority tokens and
+/// pool sizes
+async fn reload_config(State(admin): State<Arc<Admin>>) -> AdminResult {
+    let config = match admin.config_path {
+        Some(ref path) => Config::from_file(path),
+        None => Config::from_env(),
+    }
+    .map_err(|e| AdminError::bad_request(e.to_string()))?;
+
+    let filter = config.telemetry.log_filter.as_deref().unwrap_or("info");
+    check_log_filter(filter).map_err(AdminError::bad_request)?;
+
+    // Every token needed is acquired before anything is applied, so a
+    // failure leaves the running configuration as it was
+    let _resizing = admin.resizing.lock().await;
+    let mut growth = Vec::new();
+    for (profile, pool) in &admin.pools {
+        let size = match profile {
+            Profile::ReadWrite => config.token.pool_size,
+            _ => config
+                .profiles
+                .get(profile)
+                .and_then(|p| p.pool_size)
+                .unwrap_or(config.token.pool_size),
+        };
+        growth.push((
+            *profile,
+            pool,
+            size,
+            admin.acquire_growth(pool, size).await?,
+        ));
+    }
+
+    let mut sizes = BTreeMap::new();
+    for (profile, pool, size, tokens) in growth {
+        pool.set_quotas(ClientQuotas::from_config(&config.clients));
+        pool.set_reserved_high(config.priority.reserved_high);
+        sizes.insert(profile, admin.apply_size(profile, pool, size, tokens));
+    }
+    set_log_filter(filter).map_err(AdminError::bad_request)?;
+
+    info!(target: AUDIT_TARGET, "Configuration reloaded");
+    Ok(Json(
+        json!({ "reloaded": true, "log_filter": filter, "pools": sizes }),
+    ))
+}
+
+/// Create the admin router, every route requiring the API key
+pub fn admin_router(admin: Arc<Admin>) -> Router {
+    Router::new()
+        .route("/pools/:pool/refresh", post(refresh_pool))
+        .route("/pools/:pool/resize", post(resize_pool))
+        .route("/pools/:pool/tokens/:id/refresh", post(refresh_token))
+        .route(
+            "/pools/:pool/tokens/:id/quarantine",
+            post(quarantine_token).delete(unquarantine_token),
+        )
+        .route("/drain", post(start_drain).delete(stop_drain))
+        .route(
+            "/log-filter",
+            get(get_log_filter)
+                .put(put_log_filter)
+                .delete(delete_log_filter),
+        )
+        .route("/reload", post(reload_config))
+        .layer(middleware::from_fn_with_state(admin.clone(), authorize))
+        .with_state(admin)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Credential;
+    use tokio::net::TcpListener;
+
+    async fn spawn_admin(admin: Admin) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let app = Router::new().nest("/admin", admin_router(Arc::new(admin)));
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        format!("http://{}/admin", addr)
+    }
+
+    fn pool(size: usize) -> Arc<TokenPool> {
+        let tokens = (0..size).map(|i| format!("token-{}", i)).collect();
+        TokenPool::new(
+            tokens,
+            Credential {
+                username: "admin".to_string(),
+                password: "123456".to_string(),
+            },
+        )
+    }
+
+    fn admin(pool: &Arc<TokenPool>, drain: &Arc<Drain>) -> Admin {
+        Admin::new(
+            "secret".to_string(),
+            TokenAcquirer::new("http://127.0.0.1:1"),
+            drain.clone(),
+        )
+        .with_pool(Profile::ReadWrite, pool.clone())
+    }
+
+    #[tokio::test]
+    async fn test_requests_need_api_key() {
+        let pool = pool(1);
+        let base = spawn_admin(admin(&pool, &Arc::new(Drain::default()))).await;
+        let client = reqwest::Client::new();
+
+        let resp = client.post(format!("{}/drain", base)).send().await.unwrap();
+        assert_eq!(resp.status(), 401);
+        let resp = client
+            .post(format!("{}/drain", base))
+            .bearer_auth("wrong")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), 401);
+
+        let resp = client
+            .post(format!("{}/pools/read_write/tokens/0/quarantine", base))
+            .bearer_auth("secret")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), 200);
+        assert_eq!(pool.available(), 0);
+
+        let resp = client
+            .post(format!("{}/pools/admin/tokens/0/quarantine", base))
+            .bearer_auth("secret")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), 404);
+    }
+
+    #[tokio::test]
+    async fn test_drain_refresh_and_resize() {
+        let pool = pool(3);
+        let drain = Arc::new(Drain::default());
+        let base = spawn_admin(admin(&pool, &drain)).await;
+        let client = reqwest::Client::new();
+        let post = |path: &str| {
+            client
+                .post(format!("{}{}", base, path))
+                .bearer_auth("secret")
+        };
+
+        assert_eq!(post("/drain").send().await.unwrap().status(), 200);
+        assert!(drain.is_draining());
+
+        let resp = post("/pools/read_write/refresh").send().await.unwrap();
+        assert_eq!(resp.status(), 200);
+        assert_eq!(pool.get_tokens_needing_refresh().len(), 3);
+
+        let resp = post("/pools/read_write/tokens/7/refresh")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), 404);
+
+        // Shrinking must leave tokens beyond the high-priority reservation
+        pool.set_reserved_high(1);
+        let resp = post("/pools/read_write/resize")
+            .json(&json!({ "size": 1 }))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), 400);
+        assert_eq!(pool.total(), 3);
+        pool.set_reserved_high(0);
+
+        let resp = post("/pools/read_write/resize")
+            .json(&json!({ "size": 1 }))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), 200);
+        assert_eq!(resp.json::<Value>().await.unwrap()["size"], 1);
+        assert_eq!(pool.total(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_failed_reload_changes_nothing() {
+        let path = std::env::temp_dir().join(format!("tpp-reload-{}.yaml", std::process::id()));
+        std::fs::write(
+            &path,
+            r#"
+listen: "127.0.0.1:0"
+upstream:
+  host: "127.0.0.1"
+  port: 1
+credential:
+  username: "writer"
+  password: "pass1"
+token:
+  pool_size: 5
+profiles:
+  read_only:
+    credential:
+      username: "reader"
+      password: "pass2"
+    pool_size: 2
+priority:
+  reserved_high: 1
+"#,
+        )
+        .unwrap();
+        let writers = pool(3);
+        let readers = pool(3);
+        let admin = admin(&writers, &Arc::new(Drain::default()))
+            .with_pool(Profile::ReadOnly, readers.clone())
+            .with_config_path(path.clone());
+        let base = spawn_admin(admin).await;
+
+        // Growing the read_write pool fails, so the read_only pool is not
+        // shrunk and the reservation not applied either
+        let resp = reqwest::Client::new()
+            .post(format!("{}/reload", base))
+            .bearer_auth("secret")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), 502);
+        assert_eq!((writers.total(), readers.total()), (3, 3));
+        assert_eq!(readers.reserved_high(), 0);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
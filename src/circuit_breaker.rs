@@ -0,0 +1,288 @@
+use std::time::{Duration, Instant};
+
+use opentelemetry::KeyValue;
+use parking_lot::Mutex;
+use serde::Serialize;
+use tracing::{info, warn};
+
+use crate::config::CircuitBreakerConfig;
+use crate::telemetry::get_metrics;
+
+/// State of the circuit in front of the upstream
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CircuitState {
+    /// Requests flow normally
+    Closed,
+    /// Requests fail fast with 503
+    Open,
+    /// A limited number of probe requests are let through
+    HalfOpen,
+}
+
+impl CircuitState {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            CircuitState::Closed => "closed",
+            CircuitState::Open => "open",
+            CircuitState::HalfOpen => "half_open",
+        }
+    }
+}
+
+impl std::fmt::Display for CircuitState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Outcome of a request admitted by the breaker
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    Success,
+    /// The upstream failed (connect error, timeout, 502/503/504)
+    Failure,
+    /// The request ended without telling anything about the upstream
+    Ignored,
+}
+
+struct BreakerState {
+    state: CircuitState,
+    /// When an open circuit lets probes through again
+    open_until: Instant,
+    /// Probes currently in flight while half-open
+    probes: u32,
+    consecutive_failures: u32,
+    /// Start of the current error ratio window
+    window_start: Instant,
+    window_requests: u32,
+    window_failures: u32,
+}
+
+/// Circuit breaker driven by consecutive upstream failures and error ratio
+pub struct CircuitBreaker {
+    failure_threshold: u32,
+    error_ratio: f64,
+    min_requests: u32,
+    window: Duration,
+    open_duration: Duration,
+    half_open_probes: u32,
+    state: Mutex<BreakerState>,
+}
+
+impl CircuitBreaker {
+    pub fn new(config: &CircuitBreakerConfig) -> Self {
+        let now = Instant::now();
+        record_state(CircuitState::Closed);
+        Self {
+            failure_threshold: config.failure_threshold,
+            error_ratio: config.error_ratio,
+            min_requests: config.min_requests,
+            window: Duration::from_secs(config.window_seconds),
+            open_duration: Duration::from_secs(config.open_seconds),
+            half_open_probes: config.half_open_probes,
+            state: Mutex::new(BreakerState {
+                state: CircuitState::Closed,
+                open_until: now,
+                probes: 0,
+                consecutive_failures: 0,
+                window_start: now,
+                window_requests: 0,
+                window_failures: 0,
+            }),
+        }
+    }
+
+    /// Current state of the circuit
+    pub fn state(&self) -> CircuitState {
+        self.state.lock().state
+    }
+
+    /// Admit a request, or return how long the circuit stays open
+    ///
+    /// Every admitted request must be followed by a call to [`Self::record`].
+    pub fn try_acquire(&self) -> Result<(), Duration> {
+        self.try_acquire_at(Instant::now())
+    }
+
+    fn try_acquire_at(&self, now: Instant) -> Result<(), Duration> {
+        let mut state = self.state.lock();
+        match state.state {
+            CircuitState::Closed => Ok(()),
+            CircuitState::Open if now < state.open_until => Err(state.open_until - now),
+            CircuitState::Open => {
+                info!("Circuit half-open, probing upstream");
+                state.state = CircuitState::HalfOpen;
+                record_state(CircuitState::HalfOpen);
+                state.probes = 1;
+                Ok(())
+            }
+            CircuitState::HalfOpen if state.probes < self.half_open_probes => {
+                state.probes += 1;
+                Ok(())
+            }
+            // Probes are in flight; retry once they have had a chance
+            CircuitState::HalfOpen => Err(Duration::from_secs(1)),
+        }
+    }
+
+    /// Record the outcome of an admitted request
+    pub fn record(&self, outcome: Outcome) {
+        self.record_at(outcome, Instant::now());
+    }
+
+    fn record_at(&self, outcome: Outcome, now: Instant) {
+        let mut state = self.state.lock();
+
+        if state.state == CircuitState::HalfOpen {
+            state.probes = state.probes.saturating_sub(1);
+            match outcome {
+                Outcome::Success => {
+                    info!("Circuit closed, upstream recovered");
+                    self.close(&mut state, now);
+                }
+                Outcome::Failure => self.open(&mut state, now),
+                Outcome::Ignored => {}
+            }
+            return;
+        }
+
+        if outcome == Outcome::Ignored {
+            return;
+        }
+
+        if now.saturating_duration_since(state.window_start) >= self.window {
+            state.window_start = now;
+            state.window_requests = 0;
+            state.window_failures = 0;
+        }
+        state.window_requests += 1;
+
+        match outcome {
+            Outcome::Success => state.consecutive_failures = 0,
+            _ => {
+                state.consecutive_failures += 1;
+                state.window_failures += 1;
+            }
+        }
+
+        if state.state != CircuitState::Closed {
+            return;
+        }
+
+        let ratio = f64::from(state.window_failures) / f64::from(state.window_requests);
+        let too_many_consecutive = state.consecutive_failures >= self.failure_threshold;
+        let ratio_exceeded =
+            state.window_requests >= self.min_requests && ratio >= self.error_ratio;
+        if too_many_consecutive || ratio_exceeded {
+            self.open(&mut state, now);
+        }
+    }
+
+    fn open(&self, state: &mut BreakerState, now: Instant) {
+        warn!(
+            "Circuit opened for {}s ({} consecutive failures, {}/{} failed in window)",
+            self.open_duration.as_secs(),
+            state.consecutive_failures,
+            state.window_failures,
+            state.window_requests
+        );
+        state.state = CircuitState::Open;
+        record_state(CircuitState::Open);
+        state.open_until = now + self.open_duration;
+        state.probes = 0;
+    }
+
+    fn close(&self, state: &mut BreakerState, now: Instant) {
+        state.state = CircuitState::Closed;
+        record_state(CircuitState::Closed);
+        state.probes = 0;
+        state.consecutive_failures = 0;
+        state.window_start = now;
+        state.window_requests = 0;
+        state.window_failures = 0;
+    }
+}
+
+/// Report the current state as 1 and the others as 0
+fn record_state(current: CircuitState) {
+    let Some(metrics) = get_metrics() else {
+        return;
+    };
+
+    for state in [
+        CircuitState::Closed,
+        CircuitState::Open,
+        CircuitState::HalfOpen,
+    ] {
+        metrics.circuit_breaker_state.record(
+            u64::from(state == current),
+            &[KeyValue::new("state", state.as_str())],
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn breaker() -> CircuitBreaker {
+        CircuitBreaker::new(&CircuitBreakerConfig {
+            enabled: true,
+            failure_threshold: 3,
+            error_ratio: 0.5,
+            min_requests: 10,
+            window_seconds: 60,
+            open_seconds: 30,
+            half_open_probes: 1,
+        })
+    }
+
+    #[test]
+    fn test_opens_on_consecutive_failures_and_recovers() {
+        let breaker = breaker();
+        let now = Instant::now();
+
+        for _ in 0..3 {
+            assert!(breaker.try_acquire_at(now).is_ok());
+            breaker.record_at(Outcome::Failure, now);
+        }
+        assert_eq!(breaker.state(), CircuitState::Open);
+        assert_eq!(
+            breaker.try_acquire_at(now + Duration::from_secs(10)),
+            Err(Duration::from_secs(20))
+        );
+
+        // One probe is let through once the open period has passed
+        let later = now + Duration::from_secs(30);
+        assert!(breaker.try_acquire_at(later).is_ok());
+        assert_eq!(breaker.state(), CircuitState::HalfOpen);
+        assert!(breaker.try_acquire_at(later).is_err());
+
+        // A failed probe reopens, a successful one closes
+        breaker.record_at(Outcome::Failure, later);
+        assert_eq!(breaker.state(), CircuitState::Open);
+        let probe = later + Duration::from_secs(30);
+        assert!(breaker.try_acquire_at(probe).is_ok());
+        breaker.record_at(Outcome::Success, probe);
+        assert_eq!(breaker.state(), CircuitState::Closed);
+    }
+
+    #[test]
+    fn test_opens_on_error_ratio() {
+        let breaker = breaker();
+        let now = Instant::now();
+
+        // Alternating failures never reach 3 in a row, but hit a 50% ratio
+        for i in 0..10 {
+            let outcome = if i % 2 == 0 {
+                Outcome::Failure
+            } else {
+                Outcome::Success
+            };
+            assert_eq!(breaker.state(), CircuitState::Closed);
+            breaker.record_at(outcome, now);
+        }
+        assert_eq!(breaker.state(), CircuitState::Open);
+    }
+}
@@ -0,0 +1,294 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::net::IpAddr;
+use std::sync::Arc;
+
+use pingora::http::RequestHeader;
+
+use crate::config::{ClientIdentifyBy, ClientsConfig, Priority, PriorityConfig, Profile};
+
+/// Identity of a downstream client, used for quotas and fair sharing
+#[derive(Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct ClientId(Arc<str>);
+
+impl ClientId {
+    pub fn new(id: impl AsRef<str>) -> Self {
+        Self(Arc::from(id.as_ref()))
+    }
+
+    /// Identity used when a client cannot be identified
+    pub fn anonymous() -> Self {
+        Self::new("anonymous")
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for ClientId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// Resolves the client identity of incoming requests
+pub struct ClientIdentifier {
+    identify_by: ClientIdentifyBy,
+    /// Header carrying the client id or API key
+    header: String,
+    /// API key -> client id
+    api_keys: HashMap<String, ClientId>,
+}
+
+impl ClientIdentifier {
+    pub fn new(config: &ClientsConfig) -> Self {
+        let api_keys = config
+            .entries
+            .iter()
+            .filter_map(|entry| {
+                entry
+                    .api_key
+                    .as_ref()
+                    .map(|key| (key.clone(), ClientId::new(&entry.id)))
+            })
+            .collect();
+
+        Self {
+            identify_by: config.identify_by,
+            header: config.header_name().to_string(),
+            api_keys,
+        }
+    }
+
+    /// Identify the client of a request
+    ///
+    /// Returns `None` when API keys are required and the request carries an
+    /// unknown or missing key, or when client certificates are required and
+    /// the connection presented none.
+    pub fn identify(
+        &self,
+        req: &RequestHeader,
+        client_ip: Option<IpAddr>,
+        client_cert: Option<&str>,
+    ) -> Option<ClientId> {
+        match self.identify_by {
+            ClientIdentifyBy::Ip => Some(
+                client_ip
+                    .map(|ip| ClientId::new(ip.to_string()))
+                    .unwrap_or_else(ClientId::anonymous),
+            ),
+            ClientIdentifyBy::Header => Some(
+                self.header_value(req)
+                    .map(ClientId::new)
+                    .unwrap_or_else(ClientId::anonymous),
+            ),
+            ClientIdentifyBy::ApiKey => self
+                .header_value(req)
+                .and_then(|key| self.api_keys.get(key).cloned()),
+            ClientIdentifyBy::ClientCert => client_cert.map(ClientId::new),
+        }
+    }
+
+    fn header_value<'a>(&self, req: &'a RequestHeader) -> Option<&'a str> {
+        req.headers
+            .get(self.header.as_str())
+            .and_then(|v| v.to_str().ok())
+            .map(str::trim)
+            .filter(|v| !v.is_empty())
+    }
+}
+
+/// Resolves the priority class of incoming requests
+pub struct PriorityResolver {
+    /// Header selecting the priority class
+    header: String,
+    /// Priority when neither client nor header set one
+    default: Priority,
+    /// Clients pinned to a priority class
+    per_client: HashMap<ClientId, Priority>,
+    /// Clients allowed to choose their priority with the header
+    trusted: HashSet<ClientId>,
+}
+
+impl PriorityResolver {
+    pub fn new(config: &PriorityConfig, clients: &ClientsConfig) -> Self {
+        Self {
+            header: config.header.clone(),
+            default: config.default,
+            per_client: clients
+                .entries
+                .iter()
+                .filter_map(|e| e.priority.map(|p| (ClientId::new(&e.id), p)))
+                .collect(),
+            trusted: config.trusted_clients.iter().map(ClientId::new).collect(),
+        }
+    }
+
+    /// Resolve the priority of a request
+    ///
+    /// A priority configured for the client wins over the request header, so
+    /// pinned clients cannot promote themselves, and only trusted clients
+    /// may use the header at all.
+    pub fn resolve(&self, req: &RequestHeader, client: &ClientId) -> Priority {
+        if let Some(priority) = self.per_client.get(client) {
+            return *priority;
+        }
+        if !self.trusted.contains(client) {
+            return self.default;
+        }
+
+        req.headers
+            .get(self.header.as_str())
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(self.default)
+    }
+}
+
+impl Default for PriorityResolver {
+    fn default() -> Self {
+        Self::new(&PriorityConfig::default(), &ClientsConfig::default())
+    }
+}
+
+/// Resolves the permission profile, and so the token pool, of each client
+pub struct ProfileResolver {
+    /// Profile of clients without their own
+    default: Profile,
+    /// Clients with their own profile
+    per_client: HashMap<ClientId, Profile>,
+}
+
+impl ProfileResolver {
+    pub fn new(clients: &ClientsConfig) -> Self {
+        Self {
+            default: clients.default_profile,
+            per_client: clients
+                .entries
+                .iter()
+                .filter_map(|e| e.profile.map(|p| (ClientId::new(&e.id), p)))
+                .collect(),
+        }
+    }
+
+    /// Resolve the profile of a client
+    ///
+    /// Only configuration decides; requests cannot choose their profile.
+    pub fn resolve(&self, client: &ClientId) -> Profile {
+        self.per_client.get(client).copied().unwrap_or(self.default)
+    }
+}
+
+impl Default for ProfileResolver {
+    fn default() -> Self {
+        Self::new(&ClientsConfig::default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ClientEntry;
+
+    fn request(header: Option<(&str, &str)>) -> RequestHeader {
+        let mut req = RequestHeader::build("POST", b"/api/executeCode", None).unwrap();
+        if let Some((name, value)) = header {
+            req.insert_header(name.to_string(), value).unwrap();
+        }
+        req
+    }
+
+    #[test]
+    fn test_identify_by_ip() {
+        let identifier = ClientIdentifier::new(&ClientsConfig::default());
+        let ip: IpAddr = "10.0.0.7".parse().unwrap();
+
+        assert_eq!(
+            identifier.identify(&request(None), Some(ip), None),
+            Some(ClientId::new("10.0.0.7"))
+        );
+        assert_eq!(
+            identifier.identify(&request(None), None, None),
+            Some(ClientId::anonymous())
+        );
+    }
+
+    #[test]
+    fn test_identify_by_api_key() {
+        let config = ClientsConfig {
+            identify_by: ClientIdentifyBy::ApiKey,
+            entries: vec![ClientEntry {
+                id: "dashboards".to_string(),
+                api_key: Some("secret".to_string()),
+                max_tokens: None,
+                priority: None,
+                profile: None,
+            }],
+            ..Default::default()
+        };
+        let identifier = ClientIdentifier::new(&config);
+
+        assert_eq!(
+            identifier.identify(&request(Some(("X-API-Key", "secret"))), None, None),
+            Some(ClientId::new("dashboards"))
+        );
+        assert_eq!(
+            identifier.identify(&request(Some(("X-API-Key", "wrong"))), None, None),
+            None
+        );
+        assert_eq!(identifier.identify(&request(None), None, None), None);
+    }
+
+    #[test]
+    fn test_identify_by_client_cert() {
+        let config = ClientsConfig {
+            identify_by: ClientIdentifyBy::ClientCert,
+            ..Default::default()
+        };
+        let identifier = ClientIdentifier::new(&config);
+
+        assert_eq!(
+            identifier.identify(&request(None), None, Some("analytics")),
+            Some(ClientId::new("analytics"))
+        );
+        assert_eq!(identifier.identify(&request(None), None, None), None);
+    }
+
+    #[test]
+    fn test_resolve_priority() {
+        let clients = ClientsConfig {
+            entries: vec![ClientEntry {
+                id: "batch-jobs".to_string(),
+                api_key: None,
+                max_tokens: None,
+                priority: Some(Priority::Batch),
+                profile: None,
+            }],
+            ..Default::default()
+        };
+        let config = PriorityConfig {
+            trusted_clients: vec!["dashboards".to_string(), "batch-jobs".to_string()],
+            ..Default::default()
+        };
+        let resolver = PriorityResolver::new(&config, &clients);
+        let high = request(Some(("X-TPP-Priority", "high")));
+        let dashboards = ClientId::new("dashboards");
+
+        assert_eq!(resolver.resolve(&high, &dashboards), Priority::High);
+        assert_eq!(
+            resolver.resolve(&high, &ClientId::new("batch-jobs")),
+            Priority::Batch
+        );
+        assert_eq!(
+            resolver.resolve(&request(Some(("X-TPP-Priority", "urgent"))), &dashboards),
+            Priority::Normal
+        );
+
+        // Untrusted clients get the default whatever they ask for
+        assert_eq!(
+            resolver.resolve(&high, &ClientId::anonymous()),
+            Priority::Normal
+        );
+    }
+}
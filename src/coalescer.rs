@@ -0,0 +1,225 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use dashmap::mapref::entry::Entry;
+use dashmap::DashMap;
+use pingora::http::RequestHeader;
+use tokio::sync::watch;
+
+use crate::config::{CoalescingConfig, Profile};
+use crate::response_cache::CachedResponse;
+use crate::telemetry::get_metrics;
+
+/// Unset while the request is in flight, then the shared response or `None`
+/// if it could not be shared
+type Outcome = Option<Option<Arc<CachedResponse>>>;
+
+/// Requests in flight by key, each with an ID telling flights of the same
+/// key apart
+type Flights = DashMap<String, (u64, watch::Receiver<Outcome>)>;
+
+/// How a request takes part in coalescing
+pub enum Role {
+    /// First of its kind: goes upstream and shares the response
+    Leader(Flight),
+    /// Identical to a request in flight: waits for its response
+    Follower(watch::Receiver<Outcome>),
+}
+
+/// A request in flight that others wait on
+///
+/// Dropping it before [`Flight::complete`] releases the waiting requests
+/// without a response.
+pub struct Flight {
+    key: String,
+    id: u64,
+    tx: watch::Sender<Outcome>,
+    flights: Arc<Flights>,
+}
+
+impl Flight {
+    /// Share the response with the requests waiting for it
+    pub fn complete(self, response: Arc<CachedResponse>) {
+        self.tx.send_replace(Some(Some(response)));
+    }
+}
+
+impl Drop for Flight {
+    fn drop(&mut self) {
+        // Later requests start a new flight rather than join a finished one
+        self.flights
+            .remove_if(&self.key, |_, (id, _)| *id == self.id);
+        record_in_flight(&self.flights);
+        self.tx.send_if_modified(|outcome| {
+            if outcome.is_some() {
+                return false;
+            }
+            *outcome = Some(None);
+            true
+        });
+    }
+}
+
+/// Lets identical concurrent requests share one upstream request
+pub struct Coalescer {
+    /// Request paths eligible for coalescing
+    routes: Vec<String>,
+    /// Permission profiles whose requests are coalesced
+    profiles: Vec<Profile>,
+    max_wait: Duration,
+    max_response_bytes: usize,
+    flights: Arc<Flights>,
+    next_id: AtomicU64,
+    /// Number of requests answered with another request's response
+    coalesced: AtomicU64,
+}
+
+impl Coalescer {
+    pub fn new(config: &CoalescingConfig) -> Self {
+        Self {
+            routes: config.routes.clone(),
+            profiles: config.profiles.clone(),
+            max_wait: Duration::from_secs(config.max_wait_seconds),
+            max_response_bytes: config.max_response_bytes,
+            flights: Arc::new(DashMap::new()),
+            next_id: AtomicU64::new(0),
+            coalesced: AtomicU64::new(0),
+        }
+    }
+
+    /// Whether a request of this profile may share another's response
+    pub fn applies_to(&self, profile: Profile, req: &RequestHeader) -> bool {
+        self.profiles.contains(&profile)
+            && matches!(req.method.as_str(), "GET" | "POST")
+            && self.routes.iter().any(|route| route == req.uri.path())
+    }
+
+    /// Larger responses are not shared
+    pub fn max_response_bytes(&self) -> usize {
+        self.max_response_bytes
+    }
+
+    /// Lead a new flight for this key, or follow the one in progress
+    pub fn join(&self, key: String) -> Role {
+        let role = match self.flights.entry(key.clone()) {
+            Entry::Occupied(flight) => return Role::Follower(flight.get().1.clone()),
+            Entry::Vacant(slot) => {
+                let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+                let (tx, rx) = watch::channel(None);
+                slot.insert((id, rx));
+                Role::Leader(Flight {
+                    key,
+                    id,
+                    tx,
+                    flights: self.flights.clone(),
+                })
+            }
+        };
+
+        // Counted once the entry no longer locks its shard
+        record_in_flight(&self.flights);
+        role
+    }
+
+    /// Wait for the leader's response, giving up after the maximum wait
+    pub async fn wait(&self, mut rx: watch::Receiver<Outcome>) -> Option<Arc<CachedResponse>> {
+        let response = match tokio::time::timeout(self.max_wait, rx.wait_for(Option::is_some)).await
+        {
+            Ok(Ok(outcome)) => outcome.clone().flatten(),
+            _ => None,
+        };
+
+        if response.is_some() {
+            self.coalesced.fetch_add(1, Ordering::Relaxed);
+        }
+        response
+    }
+
+    /// Get number of requests currently leading a flight
+    pub fn in_flight(&self) -> usize {
+        self.flights.len()
+    }
+
+    /// Get number of requests answered with another request's response
+    pub fn coalesced(&self) -> u64 {
+        self.coalesced.load(Ordering::Relaxed)
+    }
+}
+
+fn record_in_flight(flights: &Flights) {
+    if let Some(metrics) = get_metrics() {
+        metrics
+            .coalescing_in_flight
+            .record(flights.len() as u64, &[]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+    use pingora::http::ResponseHeader;
+
+    fn coalescer() -> Coalescer {
+        Coalescer::new(&CoalescingConfig {
+            enabled: true,
+            routes: vec!["/api/executeCode".to_string()],
+            max_wait_seconds: 1,
+            ..Default::default()
+        })
+    }
+
+    #[tokio::test]
+    async fn test_followers_share_leader_response() {
+        let coalescer = coalescer();
+        let Role::Leader(flight) = coalescer.join("q".to_string()) else {
+            panic!("first request should lead");
+        };
+        let Role::Follower(rx) = coalescer.join("q".to_string()) else {
+            panic!("identical request should follow");
+        };
+        assert!(matches!(
+            coalescer.join("other".to_string()),
+            Role::Leader(_)
+        ));
+        assert_eq!(coalescer.in_flight(), 1);
+
+        let follower =
+            tokio::spawn(async move { coalescer.wait(rx).await.map(|r| r.body.clone()) });
+        flight.complete(Arc::new(CachedResponse::new(
+            ResponseHeader::build(200, None).unwrap(),
+            Bytes::from("result"),
+        )));
+        assert_eq!(follower.await.unwrap().unwrap(), "result");
+    }
+
+    #[tokio::test]
+    async fn test_dropped_leader_releases_followers() {
+        let coalescer = coalescer();
+        let Role::Leader(flight) = coalescer.join("q".to_string()) else {
+            panic!("first request should lead");
+        };
+        let Role::Follower(rx) = coalescer.join("q".to_string()) else {
+            panic!("identical request should follow");
+        };
+
+        drop(flight);
+        assert!(coalescer.wait(rx).await.is_none());
+        assert_eq!(coalescer.in_flight(), 0);
+        assert_eq!(coalescer.coalesced(), 0);
+
+        // The next identical request starts a new flight
+        assert!(matches!(coalescer.join("q".to_string()), Role::Leader(_)));
+    }
+
+    #[test]
+    fn test_applies_to_listed_routes() {
+        let coalescer = coalescer();
+        let req = |path: &[u8]| RequestHeader::build("POST", path, None).unwrap();
+
+        assert!(coalescer.applies_to(Profile::ReadOnly, &req(b"/api/executeCode")));
+        assert!(!coalescer.applies_to(Profile::ReadOnly, &req(b"/api/login")));
+        assert!(!coalescer.applies_to(Profile::Admin, &req(b"/api/executeCode")));
+    }
+}
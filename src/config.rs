@@ -1,7 +1,9 @@
+use std::collections::BTreeMap;
 use std::fs;
 use std::path::Path;
+use std::time::Duration;
 
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 
 use crate::error::{Result, TppError};
 
@@ -19,6 +21,89 @@ pub struct UpstreamConfig {
     pub port: u16,
     #[serde(default)]
     pub tls: bool,
+
+    /// PEM CA bundle used to verify DolphinDB (system roots if unset)
+    pub ca_path: Option<String>,
+
+    /// PEM client certificate presented to DolphinDB (mutual TLS)
+    pub client_cert_path: Option<String>,
+
+    /// PEM private key of the client certificate
+    pub client_key_path: Option<String>,
+
+    /// Server name sent in SNI and verified for every node (default: each
+    /// node's own host)
+    pub sni: Option<String>,
+
+    /// Skip certificate and hostname verification (testing only)
+    #[serde(default)]
+    pub insecure_skip_verify: bool,
+
+    /// Timeouts for proxied requests
+    #[serde(default)]
+    pub timeouts: UpstreamTimeouts,
+
+    /// Additional DolphinDB nodes ("host:port") tried on connection retries
+    #[serde(default)]
+    pub nodes: Vec<String>,
+}
+
+/// Timeouts for proxied requests; a request that times out gets 504
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+pub struct UpstreamTimeouts {
+    /// TCP (and TLS) connect timeout in seconds (default: 10)
+    #[serde(default = "default_connect_timeout")]
+    pub connect_seconds: Option<u64>,
+
+    /// Maximum wait for each read from DolphinDB in seconds
+    pub read_seconds: Option<u64>,
+
+    /// Maximum wait for each write to DolphinDB in seconds
+    pub write_seconds: Option<u64>,
+
+    /// How long an idle upstream connection is kept for reuse in seconds
+    pub idle_seconds: Option<u64>,
+
+    /// Maximum time from sending the request to the end of the response in seconds
+    pub total_seconds: Option<u64>,
+}
+
+fn default_connect_timeout() -> Option<u64> {
+    Some(10)
+}
+
+impl UpstreamTimeouts {
+    pub fn connect(&self) -> Option<Duration> {
+        self.connect_seconds.map(Duration::from_secs)
+    }
+
+    pub fn read(&self) -> Option<Duration> {
+        self.read_seconds.map(Duration::from_secs)
+    }
+
+    pub fn write(&self) -> Option<Duration> {
+        self.write_seconds.map(Duration::from_secs)
+    }
+
+    pub fn idle(&self) -> Option<Duration> {
+        self.idle_seconds.map(Duration::from_secs)
+    }
+
+    pub fn total(&self) -> Option<Duration> {
+        self.total_seconds.map(Duration::from_secs)
+    }
+}
+
+impl Default for UpstreamTimeouts {
+    fn default() -> Self {
+        Self {
+            connect_seconds: default_connect_timeout(),
+            read_seconds: None,
+            write_seconds: None,
+            idle_seconds: None,
+            total_seconds: None,
+        }
+    }
 }
 
 impl UpstreamConfig {
@@ -27,6 +112,13 @@ impl UpstreamConfig {
         format!("{}:{}", self.host, self.port)
     }
 
+    /// Addresses of all nodes, the primary `host:port` first
+    pub fn node_addresses(&self) -> Vec<String> {
+        std::iter::once(self.address())
+            .chain(self.nodes.iter().cloned())
+            .collect()
+    }
+
     /// Get the base URL for API calls
     pub fn base_url(&self) -> String {
         let scheme = if self.tls { "https" } else { "http" };
@@ -34,13 +126,79 @@ impl UpstreamConfig {
     }
 }
 
+/// Format of the log lines written to stdout
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum LogFormat {
+    /// Human-readable lines
+    #[default]
+    Text,
+    /// One JSON object per event, with the fields of its spans
+    Json,
+}
+
+impl std::str::FromStr for LogFormat {
+    type Err = TppError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "text" => Ok(Self::Text),
+            "json" => Ok(Self::Json),
+            other => Err(TppError::Config(format!("Unknown log format '{}'", other))),
+        }
+    }
+}
+
+/// Transport of OTLP exports
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OtlpProtocol {
+    /// gRPC, usually on port 4317
+    #[default]
+    #[serde(rename = "grpc")]
+    Grpc,
+    /// Protobuf over HTTP, usually on port 4318
+    #[serde(rename = "http/protobuf")]
+    HttpProtobuf,
+}
+
+impl std::str::FromStr for OtlpProtocol {
+    type Err = TppError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "grpc" => Ok(Self::Grpc),
+            "http/protobuf" => Ok(Self::HttpProtobuf),
+            other => Err(TppError::Config(format!(
+                "Unknown OTLP protocol '{}'",
+                other
+            ))),
+        }
+    }
+}
+
 /// Telemetry configuration
 #[derive(Debug, Deserialize, Clone, Default)]
 pub struct TelemetryConfig {
     /// OTLP endpoint for exporting traces and metrics
     pub otlp_endpoint: Option<String>,
+    /// OTLP transport (default: grpc)
+    #[serde(default)]
+    pub otlp_protocol: OtlpProtocol,
+    /// Also export logs over OTLP, correlated with the current trace
+    #[serde(default)]
+    pub otlp_logs: bool,
+    /// Share of new traces that are sampled, from 0.0 to 1.0 (default: 1.0);
+    /// requests continuing a client's trace follow the client's decision
+    pub sampling_ratio: Option<f64>,
     /// Log filter (e.g., "info", "debug", "tpp=debug")
     pub log_filter: Option<String>,
+    /// Format of the logs written to stdout (default: text)
+    #[serde(default)]
+    pub log_format: LogFormat,
+    /// Log filter applied on SIGUSR1 (default: "info,tpp=debug")
+    pub debug_log_filter: Option<String>,
+    /// How long the SIGUSR1 log filter stays in effect in seconds (default: 300)
+    pub debug_log_seconds: Option<u64>,
 }
 
 /// Token refresh configuration
@@ -81,12 +239,917 @@ impl Default for TokenConfig {
     }
 }
 
+/// How downstream clients are identified
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ClientIdentifyBy {
+    /// Client IP address
+    #[default]
+    Ip,
+    /// Value of a request header (e.g., "X-TPP-Client")
+    Header,
+    /// API key looked up in `clients.entries`; unknown keys are rejected
+    ApiKey,
+    /// Organization (or serial number) of the verified TLS client certificate
+    ClientCert,
+}
+
+impl std::str::FromStr for ClientIdentifyBy {
+    type Err = TppError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "ip" => Ok(Self::Ip),
+            "header" => Ok(Self::Header),
+            "api_key" => Ok(Self::ApiKey),
+            "client_cert" => Ok(Self::ClientCert),
+            other => Err(TppError::Config(format!(
+                "Unknown client identification '{}'",
+                other
+            ))),
+        }
+    }
+}
+
+/// Priority class for token waiters
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum Priority {
+    /// Served first; may use reserved tokens
+    High,
+    #[default]
+    Normal,
+    /// Served only when no high or normal waiters can be served
+    Batch,
+}
+
+impl Priority {
+    pub const COUNT: usize = 3;
+
+    /// All classes, highest first
+    pub const ALL: [Priority; Priority::COUNT] =
+        [Priority::High, Priority::Normal, Priority::Batch];
+
+    pub fn index(self) -> usize {
+        self as usize
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Priority::High => "high",
+            Priority::Normal => "normal",
+            Priority::Batch => "batch",
+        }
+    }
+}
+
+impl std::fmt::Display for Priority {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl std::str::FromStr for Priority {
+    type Err = TppError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "high" => Ok(Self::High),
+            "normal" => Ok(Self::Normal),
+            "batch" => Ok(Self::Batch),
+            other => Err(TppError::Config(format!("Unknown priority '{}'", other))),
+        }
+    }
+}
+
+/// Permission profile, each served by its own token pool
+#[derive(
+    Debug, Deserialize, Serialize, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum Profile {
+    /// Tokens of a read-only DolphinDB user
+    ReadOnly,
+    /// Tokens of the main `credential`
+    #[default]
+    ReadWrite,
+    /// Tokens of an administrative DolphinDB user
+    Admin,
+}
+
+impl Profile {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Profile::ReadOnly => "read_only",
+            Profile::ReadWrite => "read_write",
+            Profile::Admin => "admin",
+        }
+    }
+}
+
+impl std::fmt::Display for Profile {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl std::str::FromStr for Profile {
+    type Err = TppError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "read_only" => Ok(Self::ReadOnly),
+            "read_write" => Ok(Self::ReadWrite),
+            "admin" => Ok(Self::Admin),
+            other => Err(TppError::Config(format!("Unknown profile '{}'", other))),
+        }
+    }
+}
+
+/// Token pool of a permission profile other than `read_write`
+#[derive(Debug, Deserialize, Clone)]
+pub struct ProfilePoolConfig {
+    /// DolphinDB user whose permissions the profile grants
+    pub credential: Credential,
+
+    /// Number of tokens to acquire (default: `token.pool_size`)
+    pub pool_size: Option<usize>,
+}
+
+/// Priority class configuration
+#[derive(Debug, Deserialize, Clone)]
+pub struct PriorityConfig {
+    /// Request header selecting the priority class
+    #[serde(default = "default_priority_header")]
+    pub header: String,
+
+    /// Priority for requests without a header or client setting
+    #[serde(default)]
+    pub default: Priority,
+
+    /// Number of tokens only high-priority requests may use
+    #[serde(default)]
+    pub reserved_high: usize,
+
+    /// Clients whose priority header is honoured; the header of any other
+    /// client is ignored
+    #[serde(default)]
+    pub trusted_clients: Vec<String>,
+}
+
+fn default_priority_header() -> String {
+    "X-TPP-Priority".to_string()
+}
+
+impl Default for PriorityConfig {
+    fn default() -> Self {
+        Self {
+            header: default_priority_header(),
+            default: Priority::default(),
+            reserved_high: 0,
+            trusted_clients: Vec::new(),
+        }
+    }
+}
+
+/// A known client with its own settings
+#[derive(Debug, Deserialize, Clone)]
+pub struct ClientEntry {
+    /// Client identity (IP, header value, or name for API key clients)
+    pub id: String,
+    /// API key identifying this client when `identify_by: api_key`
+    pub api_key: Option<String>,
+    /// Maximum concurrent tokens for this client (overrides the default)
+    pub max_tokens: Option<usize>,
+    /// Fixed priority class for this client (ignores the priority header)
+    pub priority: Option<Priority>,
+    /// Permission profile for this client (overrides the default)
+    pub profile: Option<Profile>,
+}
+
+/// Client identification and per-client token quotas
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct ClientsConfig {
+    /// How clients are identified (default: ip)
+    #[serde(default)]
+    pub identify_by: ClientIdentifyBy,
+
+    /// Header carrying the client id or API key
+    /// (default: "X-TPP-Client" for header, "X-API-Key" for api_key)
+    pub header: Option<String>,
+
+    /// Default maximum concurrent tokens per client (unlimited if unset)
+    pub max_tokens_per_client: Option<usize>,
+
+    /// Permission profile of clients without their own (default: read_write,
+    /// `read_only` makes the proxy read-only)
+    #[serde(default)]
+    pub default_profile: Profile,
+
+    /// Known clients
+    #[serde(default)]
+    pub entries: Vec<ClientEntry>,
+}
+
+impl ClientsConfig {
+    /// Header name used to identify clients
+    pub fn header_name(&self) -> &str {
+        match (&self.header, self.identify_by) {
+            (Some(header), _) => header,
+            (None, ClientIdentifyBy::ApiKey) => "X-API-Key",
+            (None, _) => "X-TPP-Client",
+        }
+    }
+}
+
+/// What requests are rate limited by
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum RateLimitKey {
+    /// Client identity (see `clients.identify_by`)
+    #[default]
+    Client,
+    /// Client IP address
+    Ip,
+    /// Request path
+    Route,
+}
+
+impl std::str::FromStr for RateLimitKey {
+    type Err = TppError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "client" => Ok(Self::Client),
+            "ip" => Ok(Self::Ip),
+            "route" => Ok(Self::Route),
+            other => Err(TppError::Config(format!(
+                "Unknown rate limit key '{}'",
+                other
+            ))),
+        }
+    }
+}
+
+/// Token bucket parameters
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+pub struct RateLimit {
+    /// Sustained requests per second
+    pub requests_per_second: f64,
+    /// Maximum burst size (default: one second worth of requests)
+    pub burst: Option<u32>,
+}
+
+impl RateLimit {
+    /// Bucket capacity
+    pub fn burst_size(&self) -> f64 {
+        match self.burst {
+            Some(burst) => f64::from(burst.max(1)),
+            None => self.requests_per_second.ceil().max(1.0),
+        }
+    }
+}
+
+/// Rate limit for a specific key (client id, IP or path)
+#[derive(Debug, Deserialize, Clone)]
+pub struct RateLimitOverride {
+    pub key: String,
+    #[serde(flatten)]
+    pub limit: RateLimit,
+}
+
+/// Request rate limiting, applied before a token is acquired
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct RateLimitConfig {
+    /// What requests are keyed by (default: client)
+    #[serde(default)]
+    pub key: RateLimitKey,
+
+    /// Limit for each key
+    pub per_key: Option<RateLimit>,
+
+    /// Limit across all requests
+    pub global: Option<RateLimit>,
+
+    /// Limits for specific keys
+    #[serde(default)]
+    pub overrides: Vec<RateLimitOverride>,
+}
+
+impl RateLimitConfig {
+    /// Whether any limit is configured
+    pub fn is_enabled(&self) -> bool {
+        self.per_key.is_some() || self.global.is_some() || !self.overrides.is_empty()
+    }
+}
+
+/// Circuit breaker in front of the upstream
+#[derive(Debug, Deserialize, Clone)]
+pub struct CircuitBreakerConfig {
+    /// Enable the circuit breaker
+    #[serde(default)]
+    pub enabled: bool,
+
+    /// Consecutive upstream failures that open the circuit (default: 5)
+    #[serde(default = "default_failure_threshold")]
+    pub failure_threshold: u32,
+
+    /// Failure ratio within a window that opens the circuit (default: 0.5)
+    #[serde(default = "default_error_ratio")]
+    pub error_ratio: f64,
+
+    /// Requests needed in a window before the ratio applies (default: 20)
+    #[serde(default = "default_min_requests")]
+    pub min_requests: u32,
+
+    /// Length of the error ratio window in seconds (default: 30)
+    #[serde(default = "default_breaker_window")]
+    pub window_seconds: u64,
+
+    /// How long the circuit stays open before probing in seconds (default: 30)
+    #[serde(default = "default_open_seconds")]
+    pub open_seconds: u64,
+
+    /// Concurrent probe requests allowed while half-open (default: 1)
+    #[serde(default = "default_half_open_probes")]
+    pub half_open_probes: u32,
+}
+
+fn default_failure_threshold() -> u32 {
+    5
+}
+
+fn default_error_ratio() -> f64 {
+    0.5
+}
+
+fn default_min_requests() -> u32 {
+    20
+}
+
+fn default_breaker_window() -> u64 {
+    30
+}
+
+fn default_open_seconds() -> u64 {
+    30
+}
+
+fn default_half_open_probes() -> u32 {
+    1
+}
+
+impl Default for CircuitBreakerConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            failure_threshold: default_failure_threshold(),
+            error_ratio: default_error_ratio(),
+            min_requests: default_min_requests(),
+            window_seconds: default_breaker_window(),
+            open_seconds: default_open_seconds(),
+            half_open_probes: default_half_open_probes(),
+        }
+    }
+}
+
+/// Retry of requests whose upstream connection could not be established
+#[derive(Debug, Deserialize, Clone)]
+pub struct RetryConfig {
+    /// Retries per request (default: 0, disabled)
+    #[serde(default)]
+    pub max_retries: u32,
+
+    /// Retry on the next node in `upstream.nodes` (default: true)
+    #[serde(default = "default_true")]
+    pub switch_node: bool,
+
+    /// Retry with a different token from the pool
+    #[serde(default)]
+    pub switch_token: bool,
+
+    /// Retries allowed per request within a budget window (default: 0.2)
+    #[serde(default = "default_retry_budget_ratio")]
+    pub budget_ratio: f64,
+
+    /// Retries always allowed within a budget window (default: 10)
+    #[serde(default = "default_retry_budget_min")]
+    pub budget_min_retries: u64,
+
+    /// Length of the retry budget window in seconds (default: 10)
+    #[serde(default = "default_retry_budget_window")]
+    pub budget_window_seconds: u64,
+}
+
+fn default_retry_budget_ratio() -> f64 {
+    0.2
+}
+
+fn default_retry_budget_min() -> u64 {
+    10
+}
+
+fn default_retry_budget_window() -> u64 {
+    10
+}
+
+impl RetryConfig {
+    /// Whether retries are enabled
+    pub fn is_enabled(&self) -> bool {
+        self.max_retries > 0
+    }
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        Self {
+            max_retries: 0,
+            switch_node: true,
+            switch_token: false,
+            budget_ratio: default_retry_budget_ratio(),
+            budget_min_retries: default_retry_budget_min(),
+            budget_window_seconds: default_retry_budget_window(),
+        }
+    }
+}
+
+/// HTTP/2 on the listener and towards the upstream
+///
+/// Every HTTP/2 stream acquires its own token, so one multiplexed client
+/// connection can use as many tokens as it has concurrent streams, bounded
+/// by the client's quota.
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct Http2Config {
+    /// Accept HTTP/2: negotiated via ALPN with listener TLS, h2c otherwise
+    #[serde(default)]
+    pub listener: bool,
+
+    /// Negotiate HTTP/2 with the upstream via ALPN (requires `upstream.tls`)
+    #[serde(default)]
+    pub upstream: bool,
+}
+
+/// Inspection of DolphinDB response bodies for application-level errors
+#[derive(Debug, Deserialize, Clone)]
+pub struct ResponseInspectionConfig {
+    /// Parse successful JSON responses for a non-zero `code`
+    #[serde(default)]
+    pub enabled: bool,
+
+    /// Only this many bytes at the start of a body are parsed (default: 64 KiB)
+    #[serde(default = "default_inspection_max_body_bytes")]
+    pub max_body_bytes: usize,
+
+    /// Error messages containing any of these (case-insensitive) mark the
+    /// token for refresh
+    #[serde(default = "default_session_error_patterns")]
+    pub session_error_patterns: Vec<String>,
+}
+
+fn default_inspection_max_body_bytes() -> usize {
+    64 * 1024
+}
+
+/// DolphinDB's messages for an expired session or a missing login; words
+/// such as "token" alone also appear in syntax errors
+fn default_session_error_patterns() -> Vec<String> {
+    [
+        "session is expired",
+        "session has expired",
+        "session expired",
+        "session does not exist",
+        "not logged in",
+        "login is required",
+        "token is invalid",
+        "token has expired",
+    ]
+    .into_iter()
+    .map(String::from)
+    .collect()
+}
+
+impl Default for ResponseInspectionConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            max_body_bytes: default_inspection_max_body_bytes(),
+            session_error_patterns: default_session_error_patterns(),
+        }
+    }
+}
+
+/// In-memory cache of responses to repeated queries
+#[derive(Debug, Deserialize, Clone)]
+pub struct ResponseCacheConfig {
+    /// Serve identical queries from memory without taking a token
+    #[serde(default)]
+    pub enabled: bool,
+
+    /// How long a response is served from the cache in seconds (default: 5)
+    #[serde(default = "default_cache_ttl")]
+    pub ttl_seconds: u64,
+
+    /// Maximum number of cached responses (default: 1000)
+    #[serde(default = "default_cache_max_entries")]
+    pub max_entries: usize,
+
+    /// Larger responses are not cached (default: 256 KiB)
+    #[serde(default = "default_cache_max_entry_bytes")]
+    pub max_entry_bytes: usize,
+
+    /// Permission profiles whose requests are cached (default: read_only)
+    #[serde(default = "default_cache_profiles")]
+    pub profiles: Vec<Profile>,
+
+    /// Requests carrying this header skip the cache
+    #[serde(default = "default_cache_bypass_header")]
+    pub bypass_header: String,
+}
+
+fn default_cache_ttl() -> u64 {
+    5
+}
+
+fn default_cache_max_entries() -> usize {
+    1000
+}
+
+fn default_cache_max_entry_bytes() -> usize {
+    256 * 1024
+}
+
+fn default_cache_profiles() -> Vec<Profile> {
+    vec![Profile::ReadOnly]
+}
+
+fn default_cache_bypass_header() -> String {
+    "X-TPP-Cache-Bypass".to_string()
+}
+
+impl Default for ResponseCacheConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            ttl_seconds: default_cache_ttl(),
+            max_entries: default_cache_max_entries(),
+            max_entry_bytes: default_cache_max_entry_bytes(),
+            profiles: default_cache_profiles(),
+            bypass_header: default_cache_bypass_header(),
+        }
+    }
+}
+
+/// Coalescing of identical requests in flight at the same time
+#[derive(Debug, Deserialize, Clone)]
+pub struct CoalescingConfig {
+    /// Let identical concurrent requests share one upstream request
+    #[serde(default)]
+    pub enabled: bool,
+
+    /// Request paths eligible for coalescing, e.g. "/api/executeCode"
+    #[serde(default)]
+    pub routes: Vec<String>,
+
+    /// Permission profiles whose requests are coalesced (default: read_only)
+    #[serde(default = "default_cache_profiles")]
+    pub profiles: Vec<Profile>,
+
+    /// How long a request waits for the shared response before going
+    /// upstream itself in seconds (default: 30)
+    #[serde(default = "default_coalescing_wait")]
+    pub max_wait_seconds: u64,
+
+    /// Larger responses are not shared (default: 1 MiB)
+    #[serde(default = "default_coalescing_max_response_bytes")]
+    pub max_response_bytes: usize,
+}
+
+fn default_coalescing_wait() -> u64 {
+    30
+}
+
+fn default_coalescing_max_response_bytes() -> usize {
+    1024 * 1024
+}
+
+impl Default for CoalescingConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            routes: Vec::new(),
+            profiles: default_cache_profiles(),
+            max_wait_seconds: default_coalescing_wait(),
+            max_response_bytes: default_coalescing_max_response_bytes(),
+        }
+    }
+}
+
+/// Authenticated admin API on the health server
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct AdminConfig {
+    /// Serve the admin API under `/admin` on the health server
+    #[serde(default)]
+    pub enabled: bool,
+
+    /// Key admin requests must present as `Authorization: Bearer <key>`
+    pub api_key: Option<String>,
+}
+
+/// What `/readyz` checks before reporting the instance ready
+#[derive(Debug, Deserialize, Clone)]
+pub struct ReadinessConfig {
+    /// Tokens each pool must be able to serve: not quarantined and not left
+    /// stale by a failed refresh (default: 1)
+    #[serde(default = "default_min_usable_tokens")]
+    pub min_usable_tokens: usize,
+
+    /// Consecutive failed refreshes of a pool after which the instance is
+    /// not ready (default: 3)
+    #[serde(default = "default_max_refresh_failures")]
+    pub max_refresh_failures: u64,
+
+    /// Probe the upstream nodes with TCP connects (default: true)
+    #[serde(default = "default_true")]
+    pub upstream_check: bool,
+
+    /// How often the upstream is probed in seconds (default: 10)
+    #[serde(default = "default_upstream_check_interval")]
+    pub upstream_check_interval_seconds: u64,
+
+    /// How long a probe may take in seconds (default: 2)
+    #[serde(default = "default_upstream_check_timeout")]
+    pub upstream_check_timeout_seconds: u64,
+}
+
+fn default_min_usable_tokens() -> usize {
+    1
+}
+
+fn default_max_refresh_failures() -> u64 {
+    3
+}
+
+fn default_upstream_check_interval() -> u64 {
+    10
+}
+
+fn default_upstream_check_timeout() -> u64 {
+    2
+}
+
+impl Default for ReadinessConfig {
+    fn default() -> Self {
+        Self {
+            min_usable_tokens: default_min_usable_tokens(),
+            max_refresh_failures: default_max_refresh_failures(),
+            upstream_check: true,
+            upstream_check_interval_seconds: default_upstream_check_interval(),
+            upstream_check_timeout_seconds: default_upstream_check_timeout(),
+        }
+    }
+}
+
+/// Format of access log lines
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum AccessLogFormat {
+    /// One JSON object per request
+    #[default]
+    Json,
+    /// Apache combined log format, followed by tpp's own fields
+    Combined,
+}
+
+impl std::str::FromStr for AccessLogFormat {
+    type Err = TppError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "json" => Ok(Self::Json),
+            "combined" => Ok(Self::Combined),
+            other => Err(TppError::Config(format!(
+                "Unknown access log format '{}'",
+                other
+            ))),
+        }
+    }
+}
+
+/// Per-request access log
+#[derive(Debug, Deserialize, Clone)]
+pub struct AccessLogConfig {
+    /// Write a line per proxied request
+    #[serde(default)]
+    pub enabled: bool,
+
+    /// Line format (default: json)
+    #[serde(default)]
+    pub format: AccessLogFormat,
+
+    /// File to write to, rotated by size (stdout if unset)
+    pub path: Option<String>,
+
+    /// Size in bytes at which the file is rotated (default: 100 MiB)
+    #[serde(default = "default_access_log_max_file_bytes")]
+    pub max_file_bytes: u64,
+
+    /// Rotated files kept as `<path>.1` (newest) to `<path>.N` (default: 5)
+    #[serde(default = "default_access_log_max_files")]
+    pub max_files: usize,
+}
+
+fn default_access_log_max_file_bytes() -> u64 {
+    100 * 1024 * 1024
+}
+
+fn default_access_log_max_files() -> usize {
+    5
+}
+
+impl Default for AccessLogConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            format: AccessLogFormat::default(),
+            path: None,
+            max_file_bytes: default_access_log_max_file_bytes(),
+            max_files: default_access_log_max_files(),
+        }
+    }
+}
+
+/// Script rules for a specific client
+#[derive(Debug, Deserialize, Clone)]
+pub struct ScriptPolicyOverride {
+    pub client: String,
+
+    /// Only these functions may be called (in addition to the deny list)
+    pub allow_functions: Option<Vec<String>>,
+
+    /// Maximum script length for this client
+    pub max_script_length: Option<usize>,
+}
+
+/// Policy on the DolphinDB scripts and functions clients may run
+#[derive(Debug, Deserialize, Clone)]
+pub struct ScriptPolicyConfig {
+    /// Check request bodies before they reach DolphinDB
+    #[serde(default)]
+    pub enabled: bool,
+
+    /// Function names that may not appear anywhere in a request
+    /// (case-insensitive)
+    ///
+    /// Any identifier matches, not only calls, so a column named `login` is
+    /// denied as well.
+    #[serde(default = "default_deny_functions")]
+    pub deny_functions: Vec<String>,
+
+    /// Maximum length of a script in bytes (default: unlimited)
+    pub max_script_length: Option<usize>,
+
+    /// Larger request bodies are denied (default: 1 MiB)
+    #[serde(default = "default_script_policy_max_body_bytes")]
+    pub max_body_bytes: usize,
+
+    /// Rules for specific clients
+    #[serde(default)]
+    pub overrides: Vec<ScriptPolicyOverride>,
+}
+
+fn default_deny_functions() -> Vec<String> {
+    [
+        "dropDatabase",
+        "dropTable",
+        "dropPartition",
+        "shell",
+        "login",
+        "logout",
+        "createUser",
+        "deleteUser",
+        "resetPwd",
+        "changePwd",
+        "grant",
+        "deny",
+        "revoke",
+        // Would run code hidden in strings or call functions by name, which
+        // is not checked
+        "runScript",
+        "parseExpr",
+        "funcByName",
+        "call",
+        "dynamicCall",
+        "makeCall",
+        "makeUnifiedCall",
+    ]
+    .into_iter()
+    .map(String::from)
+    .collect()
+}
+
+fn default_script_policy_max_body_bytes() -> usize {
+    1024 * 1024
+}
+
+impl Default for ScriptPolicyConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            deny_functions: default_deny_functions(),
+            max_script_length: None,
+            max_body_bytes: default_script_policy_max_body_bytes(),
+            overrides: Vec::new(),
+        }
+    }
+}
+
+/// Policy for client-supplied request headers
+#[derive(Debug, Deserialize, Clone)]
+pub struct HeaderPolicyConfig {
+    /// Client headers removed before proxying (default: Authorization, Cookie)
+    #[serde(default = "default_strip_headers")]
+    pub strip: Vec<String>,
+
+    /// Headers added or overridden on every upstream request
+    #[serde(default)]
+    pub set: BTreeMap<String, String>,
+
+    /// Reject (403) requests that carry their own Authorization header
+    #[serde(default)]
+    pub reject_client_authorization: bool,
+
+    /// Also strip the headers tpp reads itself: the client identification,
+    /// priority and cache bypass headers (default: true)
+    #[serde(default = "default_true")]
+    pub strip_control_headers: bool,
+}
+
+fn default_strip_headers() -> Vec<String> {
+    vec!["Authorization".to_string(), "Cookie".to_string()]
+}
+
+fn default_true() -> bool {
+    true
+}
+
+impl Default for HeaderPolicyConfig {
+    fn default() -> Self {
+        Self {
+            strip: default_strip_headers(),
+            set: BTreeMap::new(),
+            reject_client_authorization: false,
+            strip_control_headers: true,
+        }
+    }
+}
+
+/// TLS termination on the proxy listener
+#[derive(Debug, Deserialize, Clone)]
+pub struct ListenerTlsConfig {
+    /// PEM certificate chain (leaf first)
+    pub cert_path: String,
+
+    /// PEM private key
+    pub key_path: String,
+
+    /// How often to check the certificate files for changes in seconds
+    /// (default: 30, 0 disables reloading)
+    #[serde(default = "default_tls_reload_interval")]
+    pub reload_check_seconds: u64,
+
+    /// PEM CA bundle used to verify client certificates (enables mutual TLS)
+    pub client_ca_path: Option<String>,
+
+    /// Reject clients without a valid certificate (requires `client_ca_path`)
+    #[serde(default)]
+    pub require_client_cert: bool,
+}
+
+fn default_tls_reload_interval() -> u64 {
+    30
+}
+
+impl Default for ListenerTlsConfig {
+    fn default() -> Self {
+        Self {
+            cert_path: String::new(),
+            key_path: String::new(),
+            reload_check_seconds: default_tls_reload_interval(),
+            client_ca_path: None,
+            require_client_cert: false,
+        }
+    }
+}
+
 /// Main configuration structure
 #[derive(Debug, Deserialize, Clone)]
 pub struct Config {
     /// Listen address (e.g., "0.0.0.0:8080")
     pub listen: String,
 
+    /// TLS on the proxy listener (plaintext if unset)
+    pub tls: Option<ListenerTlsConfig>,
+
     /// Health check server listen address (e.g., "0.0.0.0:9090")
     pub health_listen: Option<String>,
 
@@ -99,7 +1162,67 @@ pub struct Config {
 
     /// Token configuration (pool size, TTL, refresh interval)
     #[serde(default)]
-    pub token: TokenConfig,
+    pub token: TokenConfig,
+
+    /// Client identification and quotas
+    #[serde(default)]
+    pub clients: ClientsConfig,
+
+    /// Token pools of the `read_only` and `admin` permission profiles
+    #[serde(default)]
+    pub profiles: BTreeMap<Profile, ProfilePoolConfig>,
+
+    /// Priority classes for token waiters
+    #[serde(default)]
+    pub priority: PriorityConfig,
+
+    /// Request rate limiting
+    #[serde(default)]
+    pub rate_limit: RateLimitConfig,
+
+    /// Client header policy
+    #[serde(default)]
+    pub headers: HeaderPolicyConfig,
+
+    /// Circuit breaker in front of the upstream
+    #[serde(default)]
+    pub circuit_breaker: CircuitBreakerConfig,
+
+    /// Retry on upstream connection failures
+    #[serde(default)]
+    pub retry: RetryConfig,
+
+    /// HTTP/2 on the listener and towards the upstream
+    #[serde(default)]
+    pub http2: Http2Config,
+
+    /// Inspection of response bodies for application-level errors
+    #[serde(default)]
+    pub response_inspection: ResponseInspectionConfig,
+
+    /// Policy on the scripts clients may run
+    #[serde(default)]
+    pub script_policy: ScriptPolicyConfig,
+
+    /// Cache of responses to repeated queries
+    #[serde(default)]
+    pub cache: ResponseCacheConfig,
+
+    /// Coalescing of identical in-flight requests
+    #[serde(default)]
+    pub coalescing: CoalescingConfig,
+
+    /// Admin API for operational control
+    #[serde(default)]
+    pub admin: AdminConfig,
+
+    /// Readiness checks
+    #[serde(default)]
+    pub readiness: ReadinessConfig,
+
+    /// Per-request access log
+    #[serde(default)]
+    pub access_log: AccessLogConfig,
 
     /// Telemetry configuration
     #[serde(default)]
@@ -120,8 +1243,9 @@ impl Config {
 
     /// Create configuration purely from environment variables
     pub fn from_env() -> Result<Self> {
-        let config = Self {
+        let mut config = Self {
             listen: std::env::var("TPP_LISTEN").unwrap_or_else(|_| "0.0.0.0:8080".to_string()),
+            tls: None,
             health_listen: std::env::var("TPP_HEALTH_LISTEN").ok(),
             upstream: UpstreamConfig {
                 host: std::env::var("TPP_UPSTREAM_HOST").unwrap_or_default(),
@@ -132,6 +1256,13 @@ impl Config {
                 tls: std::env::var("TPP_UPSTREAM_TLS")
                     .map(|v| v.eq_ignore_ascii_case("true") || v == "1")
                     .unwrap_or(false),
+                ca_path: None,
+                client_cert_path: None,
+                client_key_path: None,
+                sni: None,
+                insecure_skip_verify: false,
+                timeouts: UpstreamTimeouts::default(),
+                nodes: Vec::new(),
             },
             credential: Credential {
                 username: std::env::var("TPP_CREDENTIAL_USERNAME").unwrap_or_default(),
@@ -151,15 +1282,41 @@ impl Config {
                     .and_then(|v| v.parse().ok())
                     .unwrap_or_else(default_refresh_interval),
             },
+            clients: ClientsConfig::default(),
+            profiles: BTreeMap::new(),
+            priority: PriorityConfig::default(),
+            rate_limit: RateLimitConfig::default(),
+            headers: HeaderPolicyConfig::default(),
+            circuit_breaker: CircuitBreakerConfig::default(),
+            retry: RetryConfig::default(),
+            http2: Http2Config::default(),
+            response_inspection: ResponseInspectionConfig::default(),
+            script_policy: ScriptPolicyConfig::default(),
+            cache: ResponseCacheConfig::default(),
+            coalescing: CoalescingConfig::default(),
+            admin: AdminConfig::default(),
+            readiness: ReadinessConfig::default(),
+            access_log: AccessLogConfig::default(),
             telemetry: TelemetryConfig {
                 otlp_endpoint: std::env::var("TPP_TELEMETRY_OTLP_ENDPOINT").ok(),
                 log_filter: std::env::var("TPP_TELEMETRY_LOG_FILTER").ok(),
+                ..Default::default()
             },
         };
+        config.apply_env_overrides();
         config.validate()?;
         Ok(config)
     }
 
+    /// Request headers tpp reads itself, which DolphinDB has no use for
+    pub fn control_headers(&self) -> Vec<String> {
+        vec![
+            self.clients.header_name().to_string(),
+            self.priority.header.clone(),
+            self.cache.bypass_header.clone(),
+        ]
+    }
+
     /// Apply environment variable overrides
     /// Environment variables take precedence over config file values
     fn apply_env_overrides(&mut self) {
@@ -168,6 +1325,22 @@ impl Config {
             self.listen = val;
         }
 
+        // Listener TLS settings
+        if let Ok(val) = std::env::var("TPP_TLS_CERT_PATH") {
+            self.tls.get_or_insert_with(Default::default).cert_path = val;
+        }
+        if let Ok(val) = std::env::var("TPP_TLS_KEY_PATH") {
+            self.tls.get_or_insert_with(Default::default).key_path = val;
+        }
+        if let Some(ref mut tls) = self.tls {
+            if let Ok(val) = std::env::var("TPP_TLS_CLIENT_CA_PATH") {
+                tls.client_ca_path = Some(val);
+            }
+            if let Ok(val) = std::env::var("TPP_TLS_REQUIRE_CLIENT_CERT") {
+                tls.require_client_cert = val.eq_ignore_ascii_case("true") || val == "1";
+            }
+        }
+
         // Health listen address
         if let Ok(val) = std::env::var("TPP_HEALTH_LISTEN") {
             self.health_listen = Some(val);
@@ -185,6 +1358,55 @@ impl Config {
         if let Ok(val) = std::env::var("TPP_UPSTREAM_TLS") {
             self.upstream.tls = val.eq_ignore_ascii_case("true") || val == "1";
         }
+        if let Ok(val) = std::env::var("TPP_UPSTREAM_CA_PATH") {
+            self.upstream.ca_path = Some(val);
+        }
+        if let Ok(val) = std::env::var("TPP_UPSTREAM_CLIENT_CERT_PATH") {
+            self.upstream.client_cert_path = Some(val);
+        }
+        if let Ok(val) = std::env::var("TPP_UPSTREAM_CLIENT_KEY_PATH") {
+            self.upstream.client_key_path = Some(val);
+        }
+        if let Ok(val) = std::env::var("TPP_UPSTREAM_SNI") {
+            self.upstream.sni = Some(val);
+        }
+        if let Ok(val) = std::env::var("TPP_UPSTREAM_INSECURE_SKIP_VERIFY") {
+            self.upstream.insecure_skip_verify = val.eq_ignore_ascii_case("true") || val == "1";
+        }
+        if let Ok(val) = std::env::var("TPP_UPSTREAM_CONNECT_TIMEOUT_SECONDS") {
+            if let Ok(secs) = val.parse() {
+                self.upstream.timeouts.connect_seconds = Some(secs);
+            }
+        }
+        if let Ok(val) = std::env::var("TPP_UPSTREAM_READ_TIMEOUT_SECONDS") {
+            if let Ok(secs) = val.parse() {
+                self.upstream.timeouts.read_seconds = Some(secs);
+            }
+        }
+        if let Ok(val) = std::env::var("TPP_UPSTREAM_WRITE_TIMEOUT_SECONDS") {
+            if let Ok(secs) = val.parse() {
+                self.upstream.timeouts.write_seconds = Some(secs);
+            }
+        }
+        if let Ok(val) = std::env::var("TPP_UPSTREAM_IDLE_TIMEOUT_SECONDS") {
+            if let Ok(secs) = val.parse() {
+                self.upstream.timeouts.idle_seconds = Some(secs);
+            }
+        }
+        if let Ok(val) = std::env::var("TPP_UPSTREAM_TOTAL_TIMEOUT_SECONDS") {
+            if let Ok(secs) = val.parse() {
+                self.upstream.timeouts.total_seconds = Some(secs);
+            }
+        }
+
+        if let Ok(val) = std::env::var("TPP_UPSTREAM_NODES") {
+            self.upstream.nodes = val
+                .split(',')
+                .map(str::trim)
+                .filter(|n| !n.is_empty())
+                .map(String::from)
+                .collect();
+        }
 
         // Credential settings
         if let Ok(val) = std::env::var("TPP_CREDENTIAL_USERNAME") {
@@ -211,6 +1433,236 @@ impl Config {
             }
         }
 
+        // Client settings
+        if let Ok(val) = std::env::var("TPP_CLIENTS_IDENTIFY_BY") {
+            if let Ok(identify_by) = val.parse() {
+                self.clients.identify_by = identify_by;
+            }
+        }
+        if let Ok(val) = std::env::var("TPP_CLIENTS_HEADER") {
+            self.clients.header = Some(val);
+        }
+        if let Ok(val) = std::env::var("TPP_CLIENTS_MAX_TOKENS_PER_CLIENT") {
+            if let Ok(max) = val.parse() {
+                self.clients.max_tokens_per_client = Some(max);
+            }
+        }
+
+        if let Ok(val) = std::env::var("TPP_CLIENTS_DEFAULT_PROFILE") {
+            if let Ok(profile) = val.parse() {
+                self.clients.default_profile = profile;
+            }
+        }
+
+        // Read-only profile pool
+        if let (Ok(username), Ok(password)) = (
+            std::env::var("TPP_PROFILES_READ_ONLY_USERNAME"),
+            std::env::var("TPP_PROFILES_READ_ONLY_PASSWORD"),
+        ) {
+            self.profiles.insert(
+                Profile::ReadOnly,
+                ProfilePoolConfig {
+                    credential: Credential { username, password },
+                    pool_size: std::env::var("TPP_PROFILES_READ_ONLY_POOL_SIZE")
+                        .ok()
+                        .and_then(|v| v.parse().ok()),
+                },
+            );
+        }
+
+        // Priority settings
+        if let Ok(val) = std::env::var("TPP_PRIORITY_HEADER") {
+            self.priority.header = val;
+        }
+        if let Ok(val) = std::env::var("TPP_PRIORITY_DEFAULT") {
+            if let Ok(priority) = val.parse() {
+                self.priority.default = priority;
+            }
+        }
+        if let Ok(val) = std::env::var("TPP_PRIORITY_RESERVED_HIGH") {
+            if let Ok(reserved) = val.parse() {
+                self.priority.reserved_high = reserved;
+            }
+        }
+        if let Ok(val) = std::env::var("TPP_PRIORITY_TRUSTED_CLIENTS") {
+            self.priority.trusted_clients = val
+                .split(',')
+                .map(str::trim)
+                .filter(|c| !c.is_empty())
+                .map(String::from)
+                .collect();
+        }
+
+        // Rate limit settings
+        if let Ok(val) = std::env::var("TPP_RATE_LIMIT_KEY") {
+            if let Ok(key) = val.parse() {
+                self.rate_limit.key = key;
+            }
+        }
+        if let Ok(val) = std::env::var("TPP_RATE_LIMIT_PER_KEY_RPS") {
+            if let Ok(rps) = val.parse() {
+                self.rate_limit.per_key = Some(RateLimit {
+                    requests_per_second: rps,
+                    burst: self.rate_limit.per_key.as_ref().and_then(|l| l.burst),
+                });
+            }
+        }
+        if let Ok(val) = std::env::var("TPP_RATE_LIMIT_GLOBAL_RPS") {
+            if let Ok(rps) = val.parse() {
+                self.rate_limit.global = Some(RateLimit {
+                    requests_per_second: rps,
+                    burst: self.rate_limit.global.as_ref().and_then(|l| l.burst),
+                });
+            }
+        }
+
+        // Header policy settings
+        if let Ok(val) = std::env::var("TPP_HEADERS_STRIP") {
+            self.headers.strip = val
+                .split(',')
+                .map(str::trim)
+                .filter(|h| !h.is_empty())
+                .map(String::from)
+                .collect();
+        }
+        if let Ok(val) = std::env::var("TPP_HEADERS_REJECT_CLIENT_AUTHORIZATION") {
+            self.headers.reject_client_authorization =
+                val.eq_ignore_ascii_case("true") || val == "1";
+        }
+        if let Ok(val) = std::env::var("TPP_HEADERS_STRIP_CONTROL_HEADERS") {
+            self.headers.strip_control_headers = val.eq_ignore_ascii_case("true") || val == "1";
+        }
+
+        // Circuit breaker settings
+        if let Ok(val) = std::env::var("TPP_CIRCUIT_BREAKER_ENABLED") {
+            self.circuit_breaker.enabled = val.eq_ignore_ascii_case("true") || val == "1";
+        }
+        if let Ok(val) = std::env::var("TPP_CIRCUIT_BREAKER_FAILURE_THRESHOLD") {
+            if let Ok(threshold) = val.parse() {
+                self.circuit_breaker.failure_threshold = threshold;
+            }
+        }
+        if let Ok(val) = std::env::var("TPP_CIRCUIT_BREAKER_OPEN_SECONDS") {
+            if let Ok(secs) = val.parse() {
+                self.circuit_breaker.open_seconds = secs;
+            }
+        }
+
+        // Retry settings
+        if let Ok(val) = std::env::var("TPP_RETRY_MAX_RETRIES") {
+            if let Ok(max) = val.parse() {
+                self.retry.max_retries = max;
+            }
+        }
+        if let Ok(val) = std::env::var("TPP_RETRY_SWITCH_TOKEN") {
+            self.retry.switch_token = val.eq_ignore_ascii_case("true") || val == "1";
+        }
+
+        // HTTP/2 settings
+        if let Ok(val) = std::env::var("TPP_HTTP2_LISTENER") {
+            self.http2.listener = val.eq_ignore_ascii_case("true") || val == "1";
+        }
+        if let Ok(val) = std::env::var("TPP_HTTP2_UPSTREAM") {
+            self.http2.upstream = val.eq_ignore_ascii_case("true") || val == "1";
+        }
+
+        // Response cache settings
+        if let Ok(val) = std::env::var("TPP_CACHE_ENABLED") {
+            self.cache.enabled = val.eq_ignore_ascii_case("true") || val == "1";
+        }
+        if let Ok(val) = std::env::var("TPP_CACHE_TTL_SECONDS") {
+            if let Ok(ttl) = val.parse() {
+                self.cache.ttl_seconds = ttl;
+            }
+        }
+        if let Ok(val) = std::env::var("TPP_CACHE_MAX_ENTRIES") {
+            if let Ok(max) = val.parse() {
+                self.cache.max_entries = max;
+            }
+        }
+
+        // Request coalescing settings
+        if let Ok(val) = std::env::var("TPP_COALESCING_ENABLED") {
+            self.coalescing.enabled = val.eq_ignore_ascii_case("true") || val == "1";
+        }
+        if let Ok(val) = std::env::var("TPP_COALESCING_ROUTES") {
+            self.coalescing.routes = val
+                .split(',')
+                .map(str::trim)
+                .filter(|s| !s.is_empty())
+                .map(String::from)
+                .collect();
+        }
+        if let Ok(val) = std::env::var("TPP_COALESCING_MAX_WAIT_SECONDS") {
+            if let Ok(wait) = val.parse() {
+                self.coalescing.max_wait_seconds = wait;
+            }
+        }
+
+        // Admin API settings
+        if let Ok(val) = std::env::var("TPP_ADMIN_ENABLED") {
+            self.admin.enabled = val.eq_ignore_ascii_case("true") || val == "1";
+        }
+        if let Ok(val) = std::env::var("TPP_ADMIN_API_KEY") {
+            self.admin.api_key = Some(val);
+        }
+
+        // Readiness settings
+        if let Ok(val) = std::env::var("TPP_READINESS_MIN_USABLE_TOKENS") {
+            if let Ok(min) = val.parse() {
+                self.readiness.min_usable_tokens = min;
+            }
+        }
+        if let Ok(val) = std::env::var("TPP_READINESS_MAX_REFRESH_FAILURES") {
+            if let Ok(max) = val.parse() {
+                self.readiness.max_refresh_failures = max;
+            }
+        }
+        if let Ok(val) = std::env::var("TPP_READINESS_UPSTREAM_CHECK") {
+            self.readiness.upstream_check = val.eq_ignore_ascii_case("true") || val == "1";
+        }
+
+        // Access log settings
+        if let Ok(val) = std::env::var("TPP_ACCESS_LOG_ENABLED") {
+            self.access_log.enabled = val.eq_ignore_ascii_case("true") || val == "1";
+        }
+        if let Ok(val) = std::env::var("TPP_ACCESS_LOG_FORMAT") {
+            if let Ok(format) = val.parse() {
+                self.access_log.format = format;
+            }
+        }
+        if let Ok(val) = std::env::var("TPP_ACCESS_LOG_PATH") {
+            self.access_log.path = Some(val);
+        }
+
+        // Response inspection settings
+        if let Ok(val) = std::env::var("TPP_RESPONSE_INSPECTION_ENABLED") {
+            self.response_inspection.enabled = val.eq_ignore_ascii_case("true") || val == "1";
+        }
+        if let Ok(val) = std::env::var("TPP_RESPONSE_INSPECTION_MAX_BODY_BYTES") {
+            if let Ok(max) = val.parse() {
+                self.response_inspection.max_body_bytes = max;
+            }
+        }
+
+        // Script policy settings
+        if let Ok(val) = std::env::var("TPP_SCRIPT_POLICY_ENABLED") {
+            self.script_policy.enabled = val.eq_ignore_ascii_case("true") || val == "1";
+        }
+        if let Ok(val) = std::env::var("TPP_SCRIPT_POLICY_DENY_FUNCTIONS") {
+            self.script_policy.deny_functions = val
+                .split(',')
+                .map(str::trim)
+                .filter(|f| !f.is_empty())
+                .map(String::from)
+                .collect();
+        }
+        if let Ok(val) = std::env::var("TPP_SCRIPT_POLICY_MAX_SCRIPT_LENGTH") {
+            if let Ok(max) = val.parse() {
+                self.script_policy.max_script_length = Some(max);
+            }
+        }
+
         // Telemetry settings
         if let Ok(val) = std::env::var("TPP_TELEMETRY_OTLP_ENDPOINT") {
             self.telemetry.otlp_endpoint = Some(val);
@@ -218,6 +1670,32 @@ impl Config {
         if let Ok(val) = std::env::var("TPP_TELEMETRY_LOG_FILTER") {
             self.telemetry.log_filter = Some(val);
         }
+        if let Ok(val) = std::env::var("TPP_TELEMETRY_DEBUG_LOG_FILTER") {
+            self.telemetry.debug_log_filter = Some(val);
+        }
+        if let Ok(val) = std::env::var("TPP_TELEMETRY_DEBUG_LOG_SECONDS") {
+            if let Ok(secs) = val.parse() {
+                self.telemetry.debug_log_seconds = Some(secs);
+            }
+        }
+        if let Ok(val) = std::env::var("TPP_TELEMETRY_LOG_FORMAT") {
+            if let Ok(format) = val.parse() {
+                self.telemetry.log_format = format;
+            }
+        }
+        if let Ok(val) = std::env::var("TPP_TELEMETRY_OTLP_PROTOCOL") {
+            if let Ok(protocol) = val.parse() {
+                self.telemetry.otlp_protocol = protocol;
+            }
+        }
+        if let Ok(val) = std::env::var("TPP_TELEMETRY_OTLP_LOGS") {
+            self.telemetry.otlp_logs = val.eq_ignore_ascii_case("true") || val == "1";
+        }
+        if let Ok(val) = std::env::var("TPP_TELEMETRY_SAMPLING_RATIO") {
+            if let Ok(ratio) = val.parse() {
+                self.telemetry.sampling_ratio = Some(ratio);
+            }
+        }
     }
 
     /// Validate configuration
@@ -226,14 +1704,113 @@ impl Config {
             return Err(TppError::Config("'listen' address is required".to_string()));
         }
 
+        if let Some(ref tls) = self.tls {
+            if tls.cert_path.is_empty() || tls.key_path.is_empty() {
+                return Err(TppError::Config(
+                    "'tls.cert_path' and 'tls.key_path' are required".to_string(),
+                ));
+            }
+            if tls.require_client_cert && tls.client_ca_path.is_none() {
+                return Err(TppError::Config(
+                    "'tls.require_client_cert' requires 'tls.client_ca_path'".to_string(),
+                ));
+            }
+        }
+
+        if self.clients.identify_by == ClientIdentifyBy::ClientCert
+            && self
+                .tls
+                .as_ref()
+                .is_none_or(|tls| tls.client_ca_path.is_none())
+        {
+            return Err(TppError::Config(
+                "'clients.identify_by: client_cert' requires 'tls.client_ca_path'".to_string(),
+            ));
+        }
+
         if self.upstream.host.is_empty() {
             return Err(TppError::Config("'upstream.host' is required".to_string()));
         }
 
+        if self.profiles.contains_key(&Profile::ReadWrite) {
+            return Err(TppError::Config(
+                "'profiles.read_write' is not allowed; it uses 'credential'".to_string(),
+            ));
+        }
+        // An IP or a header value is no proof of identity, so it must not
+        // pick a more privileged profile or priority
+        let proven_identity = matches!(
+            self.clients.identify_by,
+            ClientIdentifyBy::ApiKey | ClientIdentifyBy::ClientCert
+        );
+        let own_profile = self
+            .clients
+            .entries
+            .iter()
+            .find(|e| e.profile.is_some_and(|p| p != self.clients.default_profile));
+        if let Some(entry) = own_profile.filter(|_| !proven_identity) {
+            return Err(TppError::Config(format!(
+                "client '{}' has its own profile, which requires \
+                 'clients.identify_by: api_key' or 'client_cert'",
+                entry.id
+            )));
+        }
+        let own_priority = self
+            .clients
+            .entries
+            .iter()
+            .find(|e| e.priority.is_some_and(|p| p != self.priority.default));
+        if let Some(entry) = own_priority.filter(|_| !proven_identity) {
+            return Err(TppError::Config(format!(
+                "client '{}' has its own priority, which requires \
+                 'clients.identify_by: api_key' or 'client_cert'",
+                entry.id
+            )));
+        }
+        if !self.priority.trusted_clients.is_empty() && !proven_identity {
+            return Err(TppError::Config(
+                "'priority.trusted_clients' requires 'clients.identify_by: api_key' \
+                 or 'client_cert'"
+                    .to_string(),
+            ));
+        }
+        let used_profiles = std::iter::once(self.clients.default_profile)
+            .chain(self.clients.entries.iter().filter_map(|e| e.profile));
+        for profile in used_profiles {
+            if profile != Profile::ReadWrite && !self.profiles.contains_key(&profile) {
+                return Err(TppError::Config(format!(
+                    "profile '{}' is used by clients but 'profiles.{}' is not configured",
+                    profile, profile
+                )));
+            }
+        }
+
         if self.upstream.port == 0 {
             return Err(TppError::Config("'upstream.port' must be > 0".to_string()));
         }
 
+        if self.upstream.client_cert_path.is_some() != self.upstream.client_key_path.is_some() {
+            return Err(TppError::Config(
+                "'upstream.client_cert_path' and 'upstream.client_key_path' must be set together"
+                    .to_string(),
+            ));
+        }
+
+        let timeouts = &self.upstream.timeouts;
+        if [
+            timeouts.connect_seconds,
+            timeouts.read_seconds,
+            timeouts.write_seconds,
+            timeouts.idle_seconds,
+            timeouts.total_seconds,
+        ]
+        .contains(&Some(0))
+        {
+            return Err(TppError::Config(
+                "'upstream.timeouts' values must be > 0".to_string(),
+            ));
+        }
+
         if self.credential.username.is_empty() {
             return Err(TppError::Config(
                 "'credential.username' is required".to_string(),
@@ -246,6 +1823,168 @@ impl Config {
             ));
         }
 
+        if self.clients.max_tokens_per_client == Some(0)
+            || self.clients.entries.iter().any(|e| e.max_tokens == Some(0))
+        {
+            return Err(TppError::Config(
+                "client 'max_tokens' must be > 0".to_string(),
+            ));
+        }
+
+        if self.priority.reserved_high >= self.token.pool_size {
+            return Err(TppError::Config(
+                "'priority.reserved_high' must be less than 'token.pool_size'".to_string(),
+            ));
+        }
+        // Every pool holds back the same number of tokens
+        for (profile, pool) in &self.profiles {
+            if pool.pool_size == Some(0) {
+                return Err(TppError::Config(format!(
+                    "'profiles.{}.pool_size' must be > 0",
+                    profile
+                )));
+            }
+            if pool
+                .pool_size
+                .is_some_and(|size| self.priority.reserved_high >= size)
+            {
+                return Err(TppError::Config(format!(
+                    "'priority.reserved_high' must be less than 'profiles.{}.pool_size'",
+                    profile
+                )));
+            }
+        }
+
+        let rate_limits = self
+            .rate_limit
+            .per_key
+            .iter()
+            .chain(self.rate_limit.global.iter())
+            .chain(self.rate_limit.overrides.iter().map(|o| &o.limit));
+        for limit in rate_limits {
+            if !limit.requests_per_second.is_finite() || limit.requests_per_second <= 0.0 {
+                return Err(TppError::Config(
+                    "rate limit 'requests_per_second' must be > 0".to_string(),
+                ));
+            }
+        }
+
+        let breaker = &self.circuit_breaker;
+        if breaker.enabled
+            && (breaker.failure_threshold == 0
+                || breaker.half_open_probes == 0
+                || breaker.window_seconds == 0
+                || !(breaker.error_ratio > 0.0 && breaker.error_ratio <= 1.0))
+        {
+            return Err(TppError::Config(
+                "'circuit_breaker' thresholds must be > 0 and 'error_ratio' in (0, 1]".to_string(),
+            ));
+        }
+
+        if self.retry.is_enabled()
+            && (!self.retry.budget_ratio.is_finite()
+                || self.retry.budget_ratio < 0.0
+                || self.retry.budget_window_seconds == 0)
+        {
+            return Err(TppError::Config(
+                "'retry.budget_ratio' must be >= 0 and 'retry.budget_window_seconds' > 0"
+                    .to_string(),
+            ));
+        }
+
+        if self.cache.enabled && (self.cache.ttl_seconds == 0 || self.cache.max_entries == 0) {
+            return Err(TppError::Config(
+                "'cache.ttl_seconds' and 'cache.max_entries' must be > 0".to_string(),
+            ));
+        }
+
+        if self.coalescing.enabled && self.coalescing.routes.is_empty() {
+            return Err(TppError::Config(
+                "'coalescing.routes' must list at least one route".to_string(),
+            ));
+        }
+
+        if self.readiness.min_usable_tokens == 0 || self.readiness.max_refresh_failures == 0 {
+            return Err(TppError::Config(
+                "'readiness.min_usable_tokens' and 'readiness.max_refresh_failures' must be > 0"
+                    .to_string(),
+            ));
+        }
+        if self.readiness.upstream_check
+            && (self.readiness.upstream_check_interval_seconds == 0
+                || self.readiness.upstream_check_timeout_seconds == 0)
+        {
+            return Err(TppError::Config(
+                "'readiness.upstream_check_interval_seconds' and \
+                 'readiness.upstream_check_timeout_seconds' must be > 0"
+                    .to_string(),
+            ));
+        }
+
+        if self
+            .telemetry
+            .sampling_ratio
+            .is_some_and(|ratio| !(0.0..=1.0).contains(&ratio))
+        {
+            return Err(TppError::Config(
+                "'telemetry.sampling_ratio' must be between 0.0 and 1.0".to_string(),
+            ));
+        }
+
+        if self.telemetry.debug_log_seconds == Some(0) {
+            return Err(TppError::Config(
+                "'telemetry.debug_log_seconds' must be > 0".to_string(),
+            ));
+        }
+
+        if self.access_log.enabled
+            && self.access_log.path.is_some()
+            && self.access_log.max_file_bytes == 0
+        {
+            return Err(TppError::Config(
+                "'access_log.max_file_bytes' must be > 0".to_string(),
+            ));
+        }
+
+        if self.admin.enabled {
+            if self.admin.api_key.as_deref().is_none_or(str::is_empty) {
+                return Err(TppError::Config(
+                    "'admin.api_key' is required when the admin API is enabled".to_string(),
+                ));
+            }
+            if self.health_listen.is_none() {
+                return Err(TppError::Config(
+                    "'admin.enabled' requires 'health_listen'".to_string(),
+                ));
+            }
+        }
+
+        if self.http2.upstream && !self.upstream.tls {
+            return Err(TppError::Config(
+                "'http2.upstream' requires 'upstream.tls'".to_string(),
+            ));
+        }
+
+        let mut probe = pingora::http::RequestHeader::build("GET", b"/", None)
+            .map_err(|e| TppError::Config(e.to_string()))?;
+        for (name, value) in &self.headers.set {
+            if probe.insert_header(name.clone(), value.as_str()).is_err() {
+                return Err(TppError::Config(format!(
+                    "invalid header in 'headers.set': '{}'",
+                    name
+                )));
+            }
+        }
+
+        if self.clients.identify_by == ClientIdentifyBy::ApiKey
+            && !self.clients.entries.iter().any(|e| e.api_key.is_some())
+        {
+            return Err(TppError::Config(
+                "'clients.identify_by: api_key' requires at least one entry with 'api_key'"
+                    .to_string(),
+            ));
+        }
+
         Ok(())
     }
 }
@@ -289,14 +2028,169 @@ telemetry:
         );
     }
 
+    #[test]
+    fn test_parse_clients_config() {
+        let yaml = r#"
+listen: "0.0.0.0:8080"
+upstream:
+  host: "dolphindb.example.com"
+  port: 8848
+credential:
+  username: "user1"
+  password: "pass1"
+clients:
+  identify_by: api_key
+  max_tokens_per_client: 50
+  entries:
+    - id: "batch"
+      api_key: "k1"
+      max_tokens: 20
+      priority: batch
+priority:
+  reserved_high: 5
+"#;
+
+        let mut config: Config = serde_yaml::from_str(yaml).unwrap();
+        assert_eq!(config.clients.identify_by, ClientIdentifyBy::ApiKey);
+        assert_eq!(config.clients.header_name(), "X-API-Key");
+        assert_eq!(config.clients.max_tokens_per_client, Some(50));
+        assert_eq!(config.clients.entries[0].max_tokens, Some(20));
+        assert_eq!(config.clients.entries[0].priority, Some(Priority::Batch));
+        assert_eq!(config.priority.header, "X-TPP-Priority");
+        assert_eq!(config.priority.default, Priority::Normal);
+        assert_eq!(config.priority.reserved_high, 5);
+        assert!(config.validate().is_ok());
+
+        // Clients naming themselves cannot pick their priority
+        config.priority.trusted_clients = vec!["batch".to_string()];
+        assert!(config.validate().is_ok());
+        config.clients.identify_by = ClientIdentifyBy::Ip;
+        assert!(config.validate().is_err());
+        config.priority.trusted_clients.clear();
+        assert!(config.validate().is_err());
+        config.clients.entries[0].priority = Some(Priority::Normal);
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn test_parse_profiles_config() {
+        let yaml = r#"
+listen: "0.0.0.0:8080"
+upstream:
+  host: "dolphindb.example.com"
+  port: 8848
+credential:
+  username: "writer"
+  password: "pass1"
+profiles:
+  read_only:
+    credential:
+      username: "reader"
+      password: "pass2"
+    pool_size: 20
+clients:
+  identify_by: api_key
+  default_profile: read_only
+  entries:
+    - id: "etl"
+      api_key: "etl-key"
+      profile: admin
+"#;
+
+        let mut config: Config = serde_yaml::from_str(yaml).unwrap();
+        assert_eq!(config.clients.default_profile, Profile::ReadOnly);
+        assert_eq!(config.clients.entries[0].profile, Some(Profile::Admin));
+        assert_eq!(config.profiles[&Profile::ReadOnly].pool_size, Some(20));
+        // The admin profile has no pool to serve it
+        assert!(config.validate().is_err());
+
+        config.clients.entries[0].profile = Some(Profile::ReadWrite);
+        assert!(config.validate().is_ok());
+
+        // Clients naming themselves cannot be given their own profile
+        config.clients.identify_by = ClientIdentifyBy::Header;
+        assert!(config.validate().is_err());
+        config.clients.entries[0].profile = Some(Profile::ReadOnly);
+        assert!(config.validate().is_ok());
+
+        // High-priority reservation must leave tokens in every pool
+        config.token.pool_size = 50;
+        config.priority.reserved_high = 20;
+        assert!(config.validate().is_err());
+        config.priority.reserved_high = 19;
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn test_parse_listener_tls_config() {
+        let yaml = r#"
+listen: "0.0.0.0:8443"
+tls:
+  cert_path: "server.crt"
+  key_path: "server.key"
+  require_client_cert: true
+upstream:
+  host: "dolphindb.example.com"
+  port: 8848
+credential:
+  username: "user1"
+  password: "pass1"
+clients:
+  identify_by: client_cert
+"#;
+
+        let mut config: Config = serde_yaml::from_str(yaml).unwrap();
+        let tls = config.tls.as_ref().unwrap();
+        assert_eq!(tls.reload_check_seconds, 30);
+        assert_eq!(config.clients.identify_by, ClientIdentifyBy::ClientCert);
+        assert!(config.validate().is_err());
+
+        config.tls.as_mut().unwrap().client_ca_path = Some("clients-ca.crt".to_string());
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn test_parse_rate_limit_config() {
+        let yaml = r#"
+key: route
+per_key:
+  requests_per_second: 20
+  burst: 40
+global:
+  requests_per_second: 2.5
+overrides:
+  - key: "/api/executeCode"
+    requests_per_second: 5
+"#;
+
+        let rate_limit: RateLimitConfig = serde_yaml::from_str(yaml).unwrap();
+        assert!(rate_limit.is_enabled());
+        assert_eq!(rate_limit.key, RateLimitKey::Route);
+        assert_eq!(rate_limit.per_key.unwrap().burst_size(), 40.0);
+        assert_eq!(rate_limit.global.unwrap().burst_size(), 3.0);
+        assert_eq!(rate_limit.overrides[0].key, "/api/executeCode");
+        assert_eq!(rate_limit.overrides[0].limit.requests_per_second, 5.0);
+    }
+
     #[test]
     fn test_upstream_address() {
         let upstream = UpstreamConfig {
             host: "example.com".to_string(),
             port: 8080,
             tls: false,
+            ca_path: None,
+            client_cert_path: None,
+            client_key_path: None,
+            sni: None,
+            insecure_skip_verify: false,
+            timeouts: UpstreamTimeouts::default(),
+            nodes: vec!["replica.example.com:8080".to_string()],
         };
         assert_eq!(upstream.address(), "example.com:8080");
         assert_eq!(upstream.base_url(), "http://example.com:8080");
+        assert_eq!(
+            upstream.node_addresses(),
+            vec!["example.com:8080", "replica.example.com:8080"]
+        );
     }
 }
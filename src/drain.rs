@@ -0,0 +1,25 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Whether the proxy is draining: new requests are refused with 503 and
+/// connections are closed after their current request, so tokens return to
+/// the pool while the instance is taken out of rotation
+#[derive(Debug, Default)]
+pub struct Drain {
+    draining: AtomicBool,
+}
+
+impl Drain {
+    /// Start draining, returning false if already draining
+    pub fn start(&self) -> bool {
+        !self.draining.swap(true, Ordering::Relaxed)
+    }
+
+    /// Accept requests again, returning false if not draining
+    pub fn stop(&self) -> bool {
+        self.draining.swap(false, Ordering::Relaxed)
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::Relaxed)
+    }
+}
@@ -14,6 +14,9 @@ pub enum TppError {
     #[error("YAML parse error: {0}")]
     Yaml(#[from] serde_yaml::Error),
 
+    #[error("TLS error: {0}")]
+    Tls(String),
+
     #[error("Server initialization error: {0}")]
     ServerInit(String),
 }
@@ -0,0 +1,139 @@
+use pingora::http::RequestHeader;
+use pingora::prelude::*;
+
+use crate::config::HeaderPolicyConfig;
+
+/// Controls which client headers reach DolphinDB
+pub struct HeaderPolicy {
+    /// Client headers removed before proxying
+    strip: Vec<String>,
+    /// Headers added or overridden on every upstream request
+    set: Vec<(String, String)>,
+    /// Reject requests carrying their own Authorization header
+    reject_client_authorization: bool,
+    /// Whether the headers tpp reads itself are stripped too
+    strip_control_headers: bool,
+}
+
+impl HeaderPolicy {
+    pub fn new(config: &HeaderPolicyConfig) -> Self {
+        Self {
+            strip: config.strip.clone(),
+            set: config
+                .set
+                .iter()
+                .map(|(name, value)| (name.clone(), value.clone()))
+                .collect(),
+            reject_client_authorization: config.reject_client_authorization,
+            strip_control_headers: config.strip_control_headers,
+        }
+    }
+
+    /// Strip the headers tpp reads itself too, unless `strip_control_headers`
+    /// is off
+    pub fn with_control_headers(mut self, headers: Vec<String>) -> Self {
+        if self.strip_control_headers {
+            self.strip.extend(headers);
+        }
+        self
+    }
+
+    /// Whether the request must be rejected before it is proxied
+    pub fn rejects(&self, req: &RequestHeader) -> bool {
+        self.reject_client_authorization && req.headers.contains_key("Authorization")
+    }
+
+    /// Strip and set headers on the upstream request
+    ///
+    /// Runs before the pool token is injected, so `set` cannot override it.
+    pub fn apply(&self, req: &mut RequestHeader) -> Result<()> {
+        for name in &self.strip {
+            req.remove_header(name.as_str());
+        }
+
+        for (name, value) in &self.set {
+            req.insert_header(name.clone(), value.as_str())
+                .map_err(|e| {
+                    pingora::Error::because(
+                        pingora::ErrorType::InternalError,
+                        format!("Failed to set header '{}'", name),
+                        e,
+                    )
+                })?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Default for HeaderPolicy {
+    fn default() -> Self {
+        Self::new(&HeaderPolicyConfig::default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request() -> RequestHeader {
+        let mut req = RequestHeader::build("POST", b"/api/executeCode", None).unwrap();
+        req.insert_header("Authorization", "Bearer client").unwrap();
+        req.insert_header("Cookie", "session=abc").unwrap();
+        req.insert_header("X-Keep", "1").unwrap();
+        req
+    }
+
+    #[test]
+    fn test_default_policy_strips_credentials() {
+        let policy = HeaderPolicy::default();
+        let mut req = request();
+
+        assert!(!policy.rejects(&req));
+        policy.apply(&mut req).unwrap();
+        assert!(!req.headers.contains_key("Authorization"));
+        assert!(!req.headers.contains_key("Cookie"));
+        assert_eq!(req.headers.get("X-Keep").unwrap(), "1");
+    }
+
+    #[test]
+    fn test_set_and_reject() {
+        let config = HeaderPolicyConfig {
+            strip: vec![],
+            set: [("X-Keep".to_string(), "2".to_string())].into(),
+            reject_client_authorization: true,
+            ..Default::default()
+        };
+        let policy = HeaderPolicy::new(&config);
+        let mut req = request();
+
+        assert!(policy.rejects(&req));
+        policy.apply(&mut req).unwrap();
+        assert_eq!(req.headers.get("X-Keep").unwrap(), "2");
+        assert!(req.headers.contains_key("Cookie"));
+    }
+
+    #[test]
+    fn test_control_headers_stripped() {
+        let control = || vec!["X-API-Key".to_string(), "X-TPP-Priority".to_string()];
+        let mut req = request();
+        req.insert_header("X-API-Key", "secret").unwrap();
+        req.insert_header("X-TPP-Priority", "high").unwrap();
+
+        let policy = HeaderPolicy::default().with_control_headers(control());
+        policy.apply(&mut req).unwrap();
+        assert!(!req.headers.contains_key("X-API-Key"));
+        assert!(!req.headers.contains_key("X-TPP-Priority"));
+        assert_eq!(req.headers.get("X-Keep").unwrap(), "1");
+
+        let config = HeaderPolicyConfig {
+            strip_control_headers: false,
+            ..Default::default()
+        };
+        let mut req = request();
+        req.insert_header("X-TPP-Priority", "high").unwrap();
+        let policy = HeaderPolicy::new(&config).with_control_headers(control());
+        policy.apply(&mut req).unwrap();
+        assert_eq!(req.headers.get("X-TPP-Priority").unwrap(), "high");
+    }
+}
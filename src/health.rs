@@ -1,17 +1,33 @@
+use std::collections::{BTreeMap, HashMap};
+use std::net::SocketAddr;
 use std::sync::Arc;
+use std::time::Duration;
 
-use axum::{extract::State, http::StatusCode, response::IntoResponse, routing::get, Json, Router};
+use axum::extract::{Query, State};
+use axum::{http::StatusCode, response::IntoResponse, routing::get, Json, Router};
 use serde::Serialize;
 use tokio::net::TcpListener;
 use tracing::info;
 
-use crate::token_pool::TokenPool;
+use crate::admin::{admin_router, Admin};
+use crate::circuit_breaker::{CircuitBreaker, CircuitState};
+use crate::config::{Profile, ReadinessConfig};
+use crate::drain::Drain;
+use crate::telemetry::render_metrics;
+use crate::token_pool::{TokenPool, TokenStats};
+use crate::upstream_probe::UpstreamProbe;
 
 /// Health check response
 #[derive(Serialize)]
 pub struct HealthResponse {
     pub status: &'static str,
     pub pool: PoolStatus,
+    /// Pools of the permission profiles other than read_write
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub profiles: BTreeMap<Profile, PoolStatus>,
+    /// Upstream circuit state, if the circuit breaker is enabled
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub circuit: Option<CircuitState>,
 }
 
 /// Token pool status
@@ -23,29 +39,195 @@ pub struct PoolStatus {
     pub waiting: u64,
 }
 
+impl PoolStatus {
+    fn of(pool: &TokenPool) -> Self {
+        Self {
+            total: pool.total(),
+            in_use: pool.in_use(),
+            available: pool.available(),
+            waiting: pool.waiting(),
+        }
+    }
+}
+
+/// Per-token statistics response
+#[derive(Serialize)]
+pub struct TokensResponse {
+    pub pool: Vec<TokenStats>,
+    /// Tokens of the permission profiles other than read_write
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub profiles: BTreeMap<Profile, Vec<TokenStats>>,
+}
+
 /// Application state for health check server
 #[derive(Clone)]
 pub struct HealthState {
     pool: Arc<TokenPool>,
+    profile_pools: BTreeMap<Profile, Arc<TokenPool>>,
+    circuit_breaker: Option<Arc<CircuitBreaker>>,
+    /// Token TTL, for the time left until each token is refreshed
+    token_ttl: Option<Duration>,
+    admin: Option<Arc<Admin>>,
+    /// Thresholds of the readiness checks
+    readiness: ReadinessConfig,
+    upstream_probe: Option<Arc<UpstreamProbe>>,
+    drain: Option<Arc<Drain>>,
 }
 
 impl HealthState {
     pub fn new(pool: Arc<TokenPool>) -> Self {
-        Self { pool }
+        Self {
+            pool,
+            profile_pools: BTreeMap::new(),
+            circuit_breaker: None,
+            token_ttl: None,
+            admin: None,
+            readiness: ReadinessConfig::default(),
+            upstream_probe: None,
+            drain: None,
+        }
+    }
+
+    /// Report the token pool of a permission profile
+    pub fn with_profile_pool(mut self, profile: Profile, pool: Arc<TokenPool>) -> Self {
+        self.profile_pools.insert(profile, pool);
+        self
+    }
+
+    /// Report the time left until each token is refreshed on `/tokens`
+    pub fn with_token_ttl(mut self, ttl: Duration) -> Self {
+        self.token_ttl = Some(ttl);
+        self
+    }
+
+    /// Use these thresholds for `/readyz`
+    pub fn with_readiness(mut self, readiness: ReadinessConfig) -> Self {
+        self.readiness = readiness;
+        self
+    }
+
+    /// Report not ready while the upstream is unreachable
+    pub fn with_upstream_probe(mut self, probe: Arc<UpstreamProbe>) -> Self {
+        self.upstream_probe = Some(probe);
+        self
+    }
+
+    /// Report not ready while draining
+    pub fn with_drain(mut self, drain: Arc<Drain>) -> Self {
+        self.drain = Some(drain);
+        self
+    }
+
+    /// Serve the admin API under `/admin`
+    pub fn with_admin(mut self, admin: Arc<Admin>) -> Self {
+        self.admin = Some(admin);
+        self
+    }
+
+    /// Report the upstream circuit state on `/health`
+    pub fn with_circuit_breaker(mut self, circuit_breaker: Arc<CircuitBreaker>) -> Self {
+        self.circuit_breaker = Some(circuit_breaker);
+        self
+    }
+}
+
+impl HealthState {
+    /// Every token pool, read_write first
+    fn pools(&self) -> impl Iterator<Item = (Profile, &Arc<TokenPool>)> {
+        std::iter::once((Profile::ReadWrite, &self.pool)).chain(
+            self.profile_pools
+                .iter()
+                .map(|(profile, pool)| (*profile, pool)),
+        )
+    }
+
+    /// Outcome of each readiness check, by name
+    fn readiness_checks(&self) -> Vec<(&'static str, Result<(), String>)> {
+        vec![
+            ("tokens", self.check_tokens()),
+            ("refresh", self.check_refresh()),
+            ("upstream", self.check_upstream()),
+            ("drain", self.check_drain()),
+        ]
+    }
+
+    /// Every pool has enough tokens that are not quarantined or stale
+    fn check_tokens(&self) -> Result<(), String> {
+        let failures: Vec<String> = self
+            .pools()
+            .filter(|(_, pool)| pool.usable() < self.readiness.min_usable_tokens)
+            .map(|(profile, pool)| {
+                format!(
+                    "{}: {} of {} tokens usable, need {}",
+                    profile,
+                    pool.usable(),
+                    pool.total(),
+                    self.readiness.min_usable_tokens
+                )
+            })
+            .collect();
+        join_failures(failures)
+    }
+
+    /// No pool keeps failing to refresh its tokens
+    fn check_refresh(&self) -> Result<(), String> {
+        let failures: Vec<String> = self
+            .pools()
+            .filter(|(_, pool)| pool.refresh_failures() >= self.readiness.max_refresh_failures)
+            .map(|(profile, pool)| {
+                format!(
+                    "{}: {} refreshes failed in a row",
+                    profile,
+                    pool.refresh_failures()
+                )
+            })
+            .collect();
+        join_failures(failures)
+    }
+
+    /// The upstream accepts connections and the circuit is not open
+    fn check_upstream(&self) -> Result<(), String> {
+        if let Some(ref probe) = self.upstream_probe {
+            probe.status()?;
+        }
+        match self.circuit_breaker.as_ref().map(|b| b.state()) {
+            Some(CircuitState::Open) => Err("circuit open".to_string()),
+            _ => Ok(()),
+        }
+    }
+
+    fn check_drain(&self) -> Result<(), String> {
+        match self.drain {
+            Some(ref drain) if drain.is_draining() => Err("draining".to_string()),
+            _ => Ok(()),
+        }
+    }
+}
+
+fn join_failures(failures: Vec<String>) -> Result<(), String> {
+    if failures.is_empty() {
+        Ok(())
+    } else {
+        Err(failures.join("; "))
     }
 }
 
 /// Health check handler - returns 200 if healthy
 async fn health_handler(State(state): State<HealthState>) -> impl IntoResponse {
-    let pool_status = PoolStatus {
-        total: state.pool.total(),
-        in_use: state.pool.in_use(),
-        available: state.pool.available(),
-        waiting: state.pool.waiting(),
-    };
+    let pool_status = PoolStatus::of(&state.pool);
+    let profiles = state
+        .profile_pools
+        .iter()
+        .map(|(profile, pool)| (*profile, PoolStatus::of(pool)))
+        .collect();
+
+    let circuit = state.circuit_breaker.as_ref().map(|b| b.state());
 
-    // Consider unhealthy if all tokens are in use and there are waiters
-    let status = if state.pool.waiting() > 0 && state.pool.available() == 0 {
+    // Consider unhealthy if all tokens are in use and there are waiters,
+    // or the upstream circuit is not closed
+    let status = if (state.pool.waiting() > 0 && state.pool.available() == 0)
+        || circuit.is_some_and(|c| c != CircuitState::Closed)
+    {
         "degraded"
     } else {
         "healthy"
@@ -54,6 +236,22 @@ async fn health_handler(State(state): State<HealthState>) -> impl IntoResponse {
     let response = HealthResponse {
         status,
         pool: pool_status,
+        profiles,
+        circuit,
+    };
+
+    (StatusCode::OK, Json(response))
+}
+
+/// Per-token statistics handler - lists every token without its value
+async fn tokens_handler(State(state): State<HealthState>) -> impl IntoResponse {
+    let response = TokensResponse {
+        pool: state.pool.token_stats(state.token_ttl),
+        profiles: state
+            .profile_pools
+            .iter()
+            .map(|(profile, pool)| (*profile, pool.token_stats(state.token_ttl)))
+            .collect(),
     };
 
     (StatusCode::OK, Json(response))
@@ -64,76 +262,146 @@ async fn liveness_handler() -> impl IntoResponse {
     StatusCode::OK
 }
 
-/// Readiness probe - returns 200 if pool has available tokens
-async fn readiness_handler(State(state): State<HealthState>) -> impl IntoResponse {
-    if state.pool.total() > 0 {
+/// Readiness probe - returns 200 if every readiness check passes, with a
+/// line per check given `?verbose`
+async fn readiness_handler(
+    State(state): State<HealthState>,
+    Query(params): Query<HashMap<String, String>>,
+) -> impl IntoResponse {
+    let checks = state.readiness_checks();
+    let ready = checks.iter().all(|(_, result)| result.is_ok());
+    let status = if ready {
         StatusCode::OK
     } else {
         StatusCode::SERVICE_UNAVAILABLE
+    };
+
+    if !params.contains_key("verbose") {
+        return status.into_response();
     }
-}
 
-/// Metrics handler - returns pool metrics in Prometheus format
-async fn metrics_handler(State(state): State<HealthState>) -> impl IntoResponse {
-    let metrics = format!(
-        "# HELP tpp_tokens_total Total number of tokens in the pool\n\
-         # TYPE tpp_tokens_total gauge\n\
-         tpp_tokens_total {}\n\
-         # HELP tpp_tokens_in_use Number of tokens currently in use\n\
-         # TYPE tpp_tokens_in_use gauge\n\
-         tpp_tokens_in_use {}\n\
-         # HELP tpp_tokens_available Number of tokens available\n\
-         # TYPE tpp_tokens_available gauge\n\
-         tpp_tokens_available {}\n\
-         # HELP tpp_requests_waiting Number of requests waiting for a token\n\
-         # TYPE tpp_requests_waiting gauge\n\
-         tpp_requests_waiting {}\n",
-        state.pool.total(),
-        state.pool.in_use(),
-        state.pool.available(),
-        state.pool.waiting(),
-    );
+    let mut body = String::new();
+    for (name, result) in &checks {
+        match result {
+            Ok(()) => body.push_str(&format!("[+]{} ok\n", name)),
+            Err(reason) => body.push_str(&format!("[-]{} failed: {}\n", name, reason)),
+        }
+    }
+    body.push_str(if ready {
+        "readyz check passed\n"
+    } else {
+        "readyz check failed\n"
+    });
+    (status, body).into_response()
+}
 
+/// Metrics handler - returns every instrument of the meter in Prometheus format
+async fn metrics_handler() -> impl IntoResponse {
     (
         StatusCode::OK,
-        [("content-type", "text/plain; charset=utf-8")],
-        metrics,
+        [("content-type", "text/plain; version=0.0.4; charset=utf-8")],
+        render_metrics(),
     )
 }
 
 /// Create the health check router
-pub fn health_router(pool: Arc<TokenPool>) -> Router {
-    let state = HealthState::new(pool);
-
-    Router::new()
+pub fn health_router(state: HealthState) -> Router {
+    let admin = state.admin.clone();
+    let router = Router::new()
         .route("/health", get(health_handler))
         .route("/healthz", get(health_handler))
+        .route("/tokens", get(tokens_handler))
         .route("/livez", get(liveness_handler))
         .route("/readyz", get(readiness_handler))
         .route("/metrics", get(metrics_handler))
-        .with_state(state)
+        .with_state(state);
+
+    match admin {
+        Some(admin) => router.nest("/admin", admin_router(admin)),
+        None => router,
+    }
 }
 
 /// Start the health check server
 pub async fn start_health_server(
     addr: &str,
-    pool: Arc<TokenPool>,
+    state: HealthState,
 ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    let app = health_router(pool);
+    let app = health_router(state);
     let listener = TcpListener::bind(addr).await?;
 
     info!("Health check server listening on {}", addr);
 
-    axum::serve(listener, app).await?;
+    // Admin actions are audited with the caller's address
+    axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .await?;
 
     Ok(())
 }
 
 /// Spawn health check server as a background task
-pub fn spawn_health_server(addr: String, pool: Arc<TokenPool>) -> tokio::task::JoinHandle<()> {
+pub fn spawn_health_server(addr: String, state: HealthState) -> tokio::task::JoinHandle<()> {
     tokio::spawn(async move {
-        if let Err(e) = start_health_server(&addr, pool).await {
+        if let Err(e) = start_health_server(&addr, state).await {
             tracing::error!("Health check server error: {}", e);
         }
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Credential;
+
+    fn pool(size: usize) -> Arc<TokenPool> {
+        let tokens = (0..size).map(|i| format!("token-{}", i)).collect();
+        TokenPool::new(
+            tokens,
+            Credential {
+                username: "admin".to_string(),
+                password: "123456".to_string(),
+            },
+        )
+    }
+
+    fn failed(state: &HealthState) -> Vec<&'static str> {
+        state
+            .readiness_checks()
+            .into_iter()
+            .filter(|(_, result)| result.is_err())
+            .map(|(name, _)| name)
+            .collect()
+    }
+
+    #[test]
+    fn test_readiness_checks() {
+        let main = pool(2);
+        let read_only = pool(1);
+        let drain = Arc::new(Drain::default());
+        let state = HealthState::new(main.clone())
+            .with_profile_pool(Profile::ReadOnly, read_only.clone())
+            .with_readiness(ReadinessConfig {
+                max_refresh_failures: 2,
+                ..Default::default()
+            })
+            .with_drain(drain.clone());
+        assert!(failed(&state).is_empty());
+
+        // A pool whose only token is quarantined cannot serve anyone
+        read_only.quarantine(0);
+        main.fail_refresh(0);
+        assert_eq!(failed(&state), ["tokens"]);
+
+        main.fail_refresh(0);
+        drain.start();
+        assert_eq!(failed(&state), ["tokens", "refresh", "drain"]);
+        let (_, tokens) = &state.readiness_checks()[0];
+        assert_eq!(
+            tokens.as_ref().unwrap_err(),
+            "read_only: 0 of 1 tokens usable, need 1"
+        );
+    }
+}
@@ -1,11 +1,28 @@
+pub mod access_log;
+pub mod admin;
+pub mod circuit_breaker;
+pub mod client;
+pub mod coalescer;
 pub mod config;
+pub mod drain;
 pub mod error;
+pub mod header_policy;
 pub mod health;
 pub mod proxy;
+pub mod rate_limit;
+pub mod response_cache;
+pub mod response_inspector;
+pub mod retry;
+pub mod script_policy;
 pub mod telemetry;
+pub mod tls;
 pub mod token_acquirer;
 pub mod token_pool;
 pub mod token_refresher;
+pub mod upstream_probe;
+
+#[cfg(test)]
+mod test_support;
 
 pub use config::Config;
 pub use error::{Result, TppError};
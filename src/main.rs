@@ -3,18 +3,35 @@ static GLOBAL: jemallocator::Jemalloc = jemallocator::Jemalloc;
 
 use std::path::PathBuf;
 use std::process;
+use std::sync::Arc;
 use std::time::Duration;
 
 use clap::Parser;
+use pingora::apps::HttpServerOptions;
 use pingora::prelude::*;
 use pingora_proxy::http_proxy_service;
 use tracing::{error, info};
 
-use tpp::config::Config;
+use tpp::access_log::AccessLog;
+use tpp::admin::Admin;
+use tpp::circuit_breaker::CircuitBreaker;
+use tpp::client::{ClientIdentifier, PriorityResolver, ProfileResolver};
+use tpp::coalescer::Coalescer;
+use tpp::config::{Config, Profile};
+use tpp::drain::Drain;
+use tpp::header_policy::HeaderPolicy;
+use tpp::health::HealthState;
 use tpp::proxy::TokenPoolProxy;
+use tpp::rate_limit::RateLimiter;
+use tpp::response_cache::ResponseCache;
+use tpp::response_inspector::ResponseInspector;
+use tpp::retry::RetryBudget;
+use tpp::script_policy::ScriptPolicy;
 use tpp::telemetry::{init_telemetry, TelemetryConfig};
+use tpp::tls::UpstreamTls;
 use tpp::token_acquirer::TokenAcquirer;
-use tpp::token_pool::TokenPool;
+use tpp::token_pool::{ClientQuotas, TokenPool};
+use tpp::upstream_probe::UpstreamProbe;
 
 #[derive(Parser, Debug)]
 #[command(name = "tpp")]
@@ -31,7 +48,7 @@ fn main() {
 
     // Load configuration from file or environment variables
     let config = match args.config {
-        Some(path) => match Config::from_file(&path) {
+        Some(ref path) => match Config::from_file(path) {
             Ok(c) => c,
             Err(e) => {
                 eprintln!("Failed to load config: {}", e);
@@ -50,11 +67,15 @@ fn main() {
     // Initialize telemetry
     let telemetry_config = TelemetryConfig {
         otlp_endpoint: config.telemetry.otlp_endpoint.clone(),
+        otlp_protocol: config.telemetry.otlp_protocol,
+        otlp_logs: config.telemetry.otlp_logs,
+        sampling_ratio: config.telemetry.sampling_ratio.unwrap_or(1.0),
         log_filter: config
             .telemetry
             .log_filter
             .clone()
             .unwrap_or_else(|| "info".to_string()),
+        log_format: config.telemetry.log_format,
     };
     if let Err(e) = init_telemetry(telemetry_config) {
         eprintln!("Failed to initialize telemetry: {}", e);
@@ -66,16 +87,36 @@ fn main() {
         config.credential.username, config.token.pool_size
     );
 
+    // Load upstream TLS settings shared by the login client and the proxy
+    let upstream_tls = if config.upstream.tls {
+        match UpstreamTls::load(&config.upstream) {
+            Ok(tls) => Some(tls),
+            Err(e) => {
+                eprintln!("Failed to load upstream TLS settings: {}", e);
+                process::exit(1);
+            }
+        }
+    } else {
+        None
+    };
+
     // Use a dedicated runtime for async initialization (token acquisition)
     // This runtime will be dropped before Pingora creates its own
-    let (pool, acquirer) = {
+    let (pool, profile_pools, acquirer) = {
         let rt = tokio::runtime::Builder::new_current_thread()
             .enable_all()
             .build()
             .expect("Failed to create tokio runtime for initialization");
 
         rt.block_on(async {
-            let acquirer = TokenAcquirer::new(&config.upstream.base_url());
+            let acquirer =
+                match TokenAcquirer::from_upstream(&config.upstream, upstream_tls.as_ref()) {
+                    Ok(a) => a,
+                    Err(e) => {
+                        error!("Failed to create login client: {}", e);
+                        process::exit(1);
+                    }
+                };
             let tokens = match acquirer
                 .acquire_n(&config.credential, config.token.pool_size)
                 .await
@@ -90,19 +131,154 @@ fn main() {
             info!("Acquired {} tokens", tokens.len());
 
             let pool = TokenPool::new(tokens, config.credential.clone());
-            (pool, acquirer)
+            pool.set_name(Profile::ReadWrite.as_str());
+            pool.set_quotas(ClientQuotas::from_config(&config.clients));
+            pool.set_reserved_high(config.priority.reserved_high);
+
+            // Each permission profile gets tokens of its own DolphinDB user
+            let mut profile_pools = Vec::new();
+            for (profile, profile_config) in &config.profiles {
+                let size = profile_config.pool_size.unwrap_or(config.token.pool_size);
+                let tokens = match acquirer.acquire_n(&profile_config.credential, size).await {
+                    Ok(t) => t,
+                    Err(e) => {
+                        error!("Failed to acquire {} tokens: {}", profile, e);
+                        process::exit(1);
+                    }
+                };
+                info!("Acquired {} {} tokens", tokens.len(), profile);
+
+                let profile_pool = TokenPool::new(tokens, profile_config.credential.clone());
+                profile_pool.set_name(profile.as_str());
+                profile_pool.set_quotas(ClientQuotas::from_config(&config.clients));
+                profile_pool.set_reserved_high(config.priority.reserved_high);
+                profile_pools.push((*profile, profile_pool));
+            }
+
+            (pool, profile_pools, acquirer)
         })
     };
 
+    // Log filter switched on with SIGUSR1
+    let debug_log_filter = config
+        .telemetry
+        .debug_log_filter
+        .clone()
+        .unwrap_or_else(|| "info,tpp=debug".to_string());
+    let debug_log_duration = Duration::from_secs(config.telemetry.debug_log_seconds.unwrap_or(300));
+
     // Start health check server and token refresher on Pingora's runtime
     let health_addr = config.health_listen.clone();
     let ttl = Duration::from_secs(config.token.ttl_seconds);
     let check_interval = Duration::from_secs(config.token.refresh_check_seconds);
-    let pool_for_health = pool.clone();
-    let pool_for_refresher = pool.clone();
+    let pools_for_refresher: Vec<_> = std::iter::once(pool.clone())
+        .chain(profile_pools.iter().map(|(_, pool)| pool.clone()))
+        .collect();
+
+    let mut health_state = HealthState::new(pool.clone())
+        .with_token_ttl(ttl)
+        .with_readiness(config.readiness.clone());
+
+    // Probe the upstream nodes for readiness
+    let upstream_probe = if config.readiness.upstream_check {
+        let probe = Arc::new(UpstreamProbe::new(
+            config.upstream.node_addresses(),
+            &config.readiness,
+        ));
+        health_state = health_state.with_upstream_probe(probe.clone());
+        Some(probe)
+    } else {
+        None
+    };
 
     // Create proxy
-    let proxy = TokenPoolProxy::new(pool.clone(), config.upstream.address(), config.upstream.tls);
+    let mut proxy =
+        TokenPoolProxy::new(pool.clone(), config.upstream.address(), config.upstream.tls)
+            .with_client_identifier(ClientIdentifier::new(&config.clients))
+            .with_priority_resolver(PriorityResolver::new(&config.priority, &config.clients))
+            .with_profile_resolver(ProfileResolver::new(&config.clients))
+            .with_header_policy(
+                HeaderPolicy::new(&config.headers).with_control_headers(config.control_headers()),
+            )
+            .with_timeouts(config.upstream.timeouts.clone());
+
+    if let Some(tls) = upstream_tls {
+        proxy = proxy.with_upstream_tls(tls);
+    }
+
+    for (profile, profile_pool) in &profile_pools {
+        proxy = proxy.with_profile_pool(*profile, profile_pool.clone());
+        health_state = health_state.with_profile_pool(*profile, profile_pool.clone());
+    }
+
+    if config.http2.upstream {
+        proxy = proxy.with_upstream_h2();
+    }
+
+    if config.rate_limit.is_enabled() {
+        let rate_limiter = Arc::new(RateLimiter::new(&config.rate_limit));
+        proxy = proxy.with_rate_limiter(rate_limiter);
+    }
+
+    if !config.upstream.nodes.is_empty() {
+        proxy = proxy.with_fallback_nodes(config.upstream.nodes.clone());
+    }
+
+    if config.retry.is_enabled() {
+        let budget = Arc::new(RetryBudget::new(&config.retry));
+        proxy = proxy.with_retry(config.retry.clone(), budget);
+    }
+
+    if config.script_policy.enabled {
+        proxy = proxy.with_script_policy(ScriptPolicy::new(&config.script_policy));
+    }
+
+    if config.response_inspection.enabled {
+        proxy = proxy.with_response_inspector(ResponseInspector::new(&config.response_inspection));
+    }
+
+    if config.cache.enabled {
+        let cache = Arc::new(ResponseCache::new(&config.cache));
+        proxy = proxy.with_response_cache(cache);
+    }
+
+    if config.coalescing.enabled {
+        let coalescer = Arc::new(Coalescer::new(&config.coalescing));
+        proxy = proxy.with_coalescer(coalescer);
+    }
+
+    if config.circuit_breaker.enabled {
+        let breaker = Arc::new(CircuitBreaker::new(&config.circuit_breaker));
+        proxy = proxy.with_circuit_breaker(breaker.clone());
+        health_state = health_state.with_circuit_breaker(breaker);
+    }
+
+    if config.access_log.enabled {
+        match AccessLog::new(&config.access_log) {
+            Ok(access_log) => proxy = proxy.with_access_log(Arc::new(access_log)),
+            Err(e) => {
+                error!("Failed to open access log: {}", e);
+                process::exit(1);
+            }
+        }
+    }
+
+    let drain = Arc::new(Drain::default());
+    proxy = proxy.with_drain(drain.clone());
+    health_state = health_state.with_drain(drain.clone());
+
+    if config.admin.enabled {
+        let api_key = config.admin.api_key.clone().unwrap_or_default();
+        let mut admin = Admin::new(api_key, acquirer.clone(), drain)
+            .with_pool(Profile::ReadWrite, pool.clone());
+        for (profile, profile_pool) in &profile_pools {
+            admin = admin.with_pool(*profile, profile_pool.clone());
+        }
+        if let Some(ref path) = args.config {
+            admin = admin.with_config_path(path.clone());
+        }
+        health_state = health_state.with_admin(Arc::new(admin));
+    }
 
     // Create Pingora server
     let mut server = match Server::new(Some(Opt::default())) {
@@ -116,10 +292,39 @@ fn main() {
 
     // Create HTTP proxy service
     let mut proxy_service = http_proxy_service(&server.configuration, proxy);
-    proxy_service.add_tcp(&config.listen);
+    let mut listener_certs = None;
+    match config.tls {
+        Some(ref tls) => match tpp::tls::listener_settings(tls) {
+            Ok((mut settings, certs)) => {
+                if config.http2.listener {
+                    settings.enable_h2();
+                }
+                proxy_service.add_tls_with_settings(&config.listen, None, settings);
+                if tls.reload_check_seconds > 0 {
+                    listener_certs = Some((certs, Duration::from_secs(tls.reload_check_seconds)));
+                }
+            }
+            Err(e) => {
+                error!("Failed to configure listener TLS: {}", e);
+                process::exit(1);
+            }
+        },
+        None => {
+            if config.http2.listener {
+                if let Some(app) = proxy_service.app_logic_mut() {
+                    let mut options = HttpServerOptions::default();
+                    options.h2c = true;
+                    app.server_options = Some(options);
+                }
+            }
+            proxy_service.add_tcp(&config.listen);
+        }
+    }
 
     info!(
         listen = %config.listen,
+        listen_tls = config.tls.is_some(),
+        listen_h2 = config.http2.listener,
         upstream = %config.upstream.address(),
         tls = config.upstream.tls,
         pool_size = pool.total(),
@@ -143,17 +348,35 @@ fn main() {
         rt.block_on(async {
             // Start health check server if configured
             if let Some(addr) = health_addr {
-                tpp::health::spawn_health_server(addr.clone(), pool_for_health);
+                tpp::health::spawn_health_server(addr.clone(), health_state);
                 info!("Health check server started on {}", addr);
             }
 
-            // Start token refresher
-            tpp::token_refresher::spawn_refresher(
-                pool_for_refresher,
-                acquirer,
-                ttl,
-                check_interval,
-            );
+            // Switch the debug log filter on and off with SIGUSR1 and SIGUSR2
+            match tpp::telemetry::spawn_log_filter_signals(debug_log_filter, debug_log_duration) {
+                Ok(_) => info!("Log filter signal handler started"),
+                Err(e) => error!("Failed to listen for log filter signals: {}", e),
+            }
+
+            // Probe the upstream for readiness
+            if let Some(probe) = upstream_probe {
+                tpp::upstream_probe::spawn_upstream_probe(probe);
+                info!("Upstream probe started");
+            }
+
+            // Reload the listener certificate when its files change
+            if let Some((certs, interval)) = listener_certs {
+                tpp::tls::spawn_cert_reloader(certs, interval);
+                info!(
+                    "Listener certificate reloader started (check interval: {}s)",
+                    interval.as_secs()
+                );
+            }
+
+            // Start a token refresher per pool
+            for pool in pools_for_refresher {
+                tpp::token_refresher::spawn_refresher(pool, acquirer.clone(), ttl, check_interval);
+            }
             info!(
                 "Token refresher started (TTL: {}s, check interval: {}s)",
                 ttl.as_secs(),
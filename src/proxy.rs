@@ -1,41 +1,679 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
 use std::sync::Arc;
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
 use async_trait::async_trait;
+use bytes::Bytes;
+use chrono::{Local, TimeDelta};
+use opentelemetry::KeyValue;
 use pingora::prelude::*;
-use pingora_proxy::{ProxyHttp, Session};
-use tracing::{debug, info};
+use pingora::protocols::http::ServerSession;
+use pingora_proxy::{FailToProxy, ProxyHttp, Session};
+use tracing::field::{display, Empty};
+use tracing::{debug, error, info, info_span, warn, Instrument, Span};
 
+use crate::access_log::{AccessLog, AccessLogEntry};
+use crate::circuit_breaker::{CircuitBreaker, Outcome};
+use crate::client::{ClientId, ClientIdentifier, PriorityResolver, ProfileResolver};
+use crate::coalescer::{Coalescer, Flight, Role};
+use crate::config::{Priority, Profile, RateLimitKey, RetryConfig, UpstreamTimeouts};
+use crate::drain::Drain;
+use crate::header_policy::HeaderPolicy;
+use crate::rate_limit::{RateDecision, RateLimiter};
+use crate::response_cache::{cache_key, CachedResponse, ResponseCache, MAX_REQUEST_BODY_BYTES};
+use crate::response_inspector::ResponseInspector;
+use crate::retry::RetryBudget;
+use crate::script_policy::{PolicyDecision, ScriptPolicy};
+use crate::telemetry::{get_metrics, set_parent_from, status_class, trace_context};
+use crate::tls::UpstreamTls;
 use crate::token_pool::{Token, TokenPool};
 
 /// HTTP proxy that injects Bearer tokens from a pool
 pub struct TokenPoolProxy {
-    /// The token pool
+    /// The token pool of the read_write profile
     pool: Arc<TokenPool>,
-    /// Upstream server address (host:port)
-    upstream: String,
+    /// Token pools of the other permission profiles
+    profile_pools: HashMap<Profile, Arc<TokenPool>>,
+    /// Resolves the permission profile of each client
+    profiles: ProfileResolver,
+    /// Upstream server addresses (host:port), the primary first
+    nodes: Vec<String>,
     /// Whether to use TLS for upstream connection
     use_tls: bool,
+    /// CA, client certificate, SNI and verification for upstream TLS
+    upstream_tls: Option<UpstreamTls>,
+    /// Whether to offer HTTP/2 to the upstream (over TLS)
+    upstream_h2: bool,
+    /// Connect, read, write, idle and total timeouts for upstream requests
+    timeouts: UpstreamTimeouts,
+    /// Resolves client identity for quotas (anonymous if unset)
+    identifier: Option<ClientIdentifier>,
+    /// Resolves the priority class of each request
+    priorities: PriorityResolver,
+    /// Request rate limiter (disabled if unset)
+    rate_limiter: Option<Arc<RateLimiter>>,
+    /// Policy for client-supplied headers
+    headers: HeaderPolicy,
+    /// Fails fast while the upstream is down (disabled if unset)
+    circuit_breaker: Option<Arc<CircuitBreaker>>,
+    /// How connection failures are retried
+    retry: RetryConfig,
+    /// Shared budget capping retries (retries disabled if unset)
+    retry_budget: Option<Arc<RetryBudget>>,
+    /// Finds application errors in response bodies (disabled if unset)
+    inspector: Option<ResponseInspector>,
+    /// Allows or denies the scripts clients run (disabled if unset)
+    script_policy: Option<ScriptPolicy>,
+    /// Serves repeated queries from memory (disabled if unset)
+    cache: Option<Arc<ResponseCache>>,
+    /// Lets identical concurrent requests share a response (disabled if unset)
+    coalescer: Option<Arc<Coalescer>>,
+    /// Refuses new requests while set, shared with the admin API (never
+    /// draining if unset)
+    drain: Option<Arc<Drain>>,
+    /// Writes a line per request (disabled if unset)
+    access_log: Option<Arc<AccessLog>>,
 }
 
 /// Per-connection context
 pub struct ProxyCtx {
     /// The token acquired for this connection
     token: Option<Token>,
+    /// Identity of the client, resolved on the first request
+    client: Option<ClientId>,
+    /// Priority class of the current request
+    priority: Priority,
+    /// Permission profile, selecting the pool the token comes from
+    profile: Profile,
     /// When this connection started
     conn_start: Instant,
+    /// When the current request started
+    request_start: Instant,
+    /// When the current request was sent upstream
+    upstream_start: Option<Instant>,
+    /// Time the current request waited for a token
+    token_wait: Option<Duration>,
+    /// Response body bytes sent for the current request
+    body_bytes: usize,
+    /// When the current upstream request must be finished by
+    deadline: Option<Instant>,
+    /// Whether the current request upgrades to a WebSocket stream
+    upgrade: bool,
+    /// Whether the circuit breaker admitted this request
+    breaker_admitted: bool,
+    /// Connection retries made for the current request
+    retries: u32,
+    /// Index into the upstream nodes for the current attempt
+    node: usize,
+    /// Whether the next attempt should use a different token
+    switch_token: bool,
+    /// Response body buffered for inspection, dropped once it grows too large
+    inspect_body: Option<Vec<u8>>,
+    /// Whether DolphinDB reported an error in a successful response
+    app_error: bool,
+    /// Request body held back until the script policy has checked it
+    request_body: Vec<u8>,
+    /// Why the script policy denied the current request
+    script_denial: Option<String>,
+    /// Whether the script policy already checked the current request body
+    body_checked: bool,
+    /// Cache key of the current request if its response may be cached
+    cache_key: Option<String>,
+    /// Identical requests waiting for the response of the current request
+    flight: Option<Flight>,
+    /// Response header and body buffered for the cache or coalesced
+    /// requests, dropped once too large
+    shared_response: Option<(pingora::http::ResponseHeader, Vec<u8>)>,
     /// Number of requests on this connection
     request_count: u64,
+    /// Span of the current request, continuing the client's trace
+    span: Span,
+    /// Span of the current upstream attempt, a child of `span`
+    upstream_span: Option<Span>,
 }
 
 impl TokenPoolProxy {
     pub fn new(pool: Arc<TokenPool>, upstream: String, use_tls: bool) -> Self {
         Self {
             pool,
-            upstream,
+            profile_pools: HashMap::new(),
+            profiles: ProfileResolver::default(),
+            nodes: vec![upstream],
             use_tls,
+            upstream_tls: None,
+            upstream_h2: false,
+            timeouts: UpstreamTimeouts::default(),
+            identifier: None,
+            priorities: PriorityResolver::default(),
+            rate_limiter: None,
+            headers: HeaderPolicy::default(),
+            circuit_breaker: None,
+            retry: RetryConfig::default(),
+            retry_budget: None,
+            inspector: None,
+            script_policy: None,
+            cache: None,
+            coalescer: None,
+            drain: None,
+            access_log: None,
         }
     }
+
+    /// Use custom TLS settings for upstream connections
+    pub fn with_upstream_tls(mut self, upstream_tls: UpstreamTls) -> Self {
+        self.upstream_tls = Some(upstream_tls);
+        self
+    }
+
+    /// Negotiate HTTP/2 with the upstream, falling back to HTTP/1.1
+    pub fn with_upstream_h2(mut self) -> Self {
+        self.upstream_h2 = true;
+        self
+    }
+
+    /// Bound upstream requests so hung queries fail with 504
+    pub fn with_timeouts(mut self, timeouts: UpstreamTimeouts) -> Self {
+        self.timeouts = timeouts;
+        self
+    }
+
+    /// Identify clients so tokens are shared fairly between them
+    pub fn with_client_identifier(mut self, identifier: ClientIdentifier) -> Self {
+        self.identifier = Some(identifier);
+        self
+    }
+
+    /// Serve token waiters by priority class
+    pub fn with_priority_resolver(mut self, priorities: PriorityResolver) -> Self {
+        self.priorities = priorities;
+        self
+    }
+
+    /// Serve clients of a permission profile from their own token pool
+    pub fn with_profile_pool(mut self, profile: Profile, pool: Arc<TokenPool>) -> Self {
+        self.profile_pools.insert(profile, pool);
+        self
+    }
+
+    /// Map clients to permission profiles
+    pub fn with_profile_resolver(mut self, profiles: ProfileResolver) -> Self {
+        self.profiles = profiles;
+        self
+    }
+
+    /// Token pool of a permission profile, if one is configured
+    ///
+    /// There is no fallback: a client never borrows a token of another profile.
+    fn pool_for(&self, profile: Profile) -> Option<&Arc<TokenPool>> {
+        match profile {
+            Profile::ReadWrite => Some(&self.pool),
+            other => self.profile_pools.get(&other),
+        }
+    }
+
+    /// Reject requests over the configured rate with 429
+    pub fn with_rate_limiter(mut self, rate_limiter: Arc<RateLimiter>) -> Self {
+        self.rate_limiter = Some(rate_limiter);
+        self
+    }
+
+    /// Strip, set or reject client-supplied headers
+    pub fn with_header_policy(mut self, headers: HeaderPolicy) -> Self {
+        self.headers = headers;
+        self
+    }
+
+    /// Fail fast with 503 while the upstream keeps failing
+    pub fn with_circuit_breaker(mut self, circuit_breaker: Arc<CircuitBreaker>) -> Self {
+        self.circuit_breaker = Some(circuit_breaker);
+        self
+    }
+
+    /// Add nodes tried after the primary when retrying connection failures
+    pub fn with_fallback_nodes(mut self, nodes: Vec<String>) -> Self {
+        self.nodes.extend(nodes);
+        self
+    }
+
+    /// Retry failed upstream connections within the shared budget
+    pub fn with_retry(mut self, retry: RetryConfig, budget: Arc<RetryBudget>) -> Self {
+        self.retry = retry;
+        self.retry_budget = Some(budget);
+        self
+    }
+
+    /// Record DolphinDB errors reported in 200 responses against the token
+    pub fn with_response_inspector(mut self, inspector: ResponseInspector) -> Self {
+        self.inspector = Some(inspector);
+        self
+    }
+
+    /// Check request bodies against the script policy before they are sent
+    pub fn with_script_policy(mut self, script_policy: ScriptPolicy) -> Self {
+        self.script_policy = Some(script_policy);
+        self
+    }
+
+    /// Serve repeated read-only queries from the response cache
+    pub fn with_response_cache(mut self, cache: Arc<ResponseCache>) -> Self {
+        self.cache = Some(cache);
+        self
+    }
+
+    /// Let identical read-only requests in flight share one upstream request
+    pub fn with_coalescer(mut self, coalescer: Arc<Coalescer>) -> Self {
+        self.coalescer = Some(coalescer);
+        self
+    }
+
+    /// Refuse new requests with 503 while draining
+    pub fn with_drain(mut self, drain: Arc<Drain>) -> Self {
+        self.drain = Some(drain);
+        self
+    }
+
+    /// Write an access log line per request
+    pub fn with_access_log(mut self, access_log: Arc<AccessLog>) -> Self {
+        self.access_log = Some(access_log);
+        self
+    }
+
+    /// Check a complete request body against the script policy
+    ///
+    /// Returns false, recording the reason, if the request is denied.
+    fn check_script(&self, session: &Session, ctx: &mut ProxyCtx, body: &[u8]) -> bool {
+        let Some(ref policy) = self.script_policy else {
+            return true;
+        };
+
+        let client = ctx.client.clone().unwrap_or_else(ClientId::anonymous);
+        let path = session.req_header().uri.path();
+        match policy.check(&client, path, body) {
+            PolicyDecision::Allow => {
+                debug!("Script policy allowed {} for client '{}'", path, client);
+                true
+            }
+            PolicyDecision::Deny { reason } => {
+                warn!(
+                    "Script policy denied {} for client '{}': {}",
+                    path, client, reason
+                );
+                ctx.script_denial = Some(reason);
+                false
+            }
+        }
+    }
+
+    /// Answer a repeated query from the cache, or with the response of an
+    /// identical request in flight, before any token is taken
+    ///
+    /// The body is read ahead to build the key and replayed to the upstream
+    /// otherwise, so only bodies of known, small size take part.
+    /// Returns true if a response was sent.
+    async fn serve_shared(&self, session: &mut Session, ctx: &mut ProxyCtx) -> Result<bool> {
+        let req = session.req_header();
+        let cache = self
+            .cache
+            .as_ref()
+            .filter(|cache| cache.applies_to(ctx.profile, req));
+        let coalescer = self
+            .coalescer
+            .as_ref()
+            .filter(|coalescer| coalescer.applies_to(ctx.profile, req));
+        if cache.is_none() && coalescer.is_none() {
+            return Ok(false);
+        }
+
+        let length = req
+            .headers
+            .get("Content-Length")
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse::<usize>().ok());
+        let length = match length {
+            Some(length) => length,
+            None if session.is_body_empty() => 0,
+            None => return Ok(false),
+        };
+        if length > MAX_REQUEST_BODY_BYTES {
+            return Ok(false);
+        }
+
+        session.enable_retry_buffering();
+        let mut body = Vec::with_capacity(length);
+        while let Some(chunk) = session.read_request_body().await? {
+            body.extend_from_slice(&chunk);
+        }
+
+        // The body is sent upstream from the retry buffer, so a body it does
+        // not fully hold must not be proxied
+        if body.len() > MAX_REQUEST_BODY_BYTES {
+            session.respond_error(413).await?;
+            return Ok(true);
+        }
+        let buffered = session.get_retry_buffer().map_or(0, |buffer| buffer.len());
+        if buffered != body.len() {
+            error!(
+                "Retry buffer holds {} of {} request body bytes",
+                buffered,
+                body.len()
+            );
+            session.respond_error(500).await?;
+            return Ok(true);
+        }
+
+        // A shared response must not let a client past its script rules
+        ctx.body_checked = true;
+        if !body.is_empty() && !self.check_script(session, ctx, &body) {
+            let body = script_denial_body(ctx.script_denial.as_deref().unwrap_or_default());
+            ctx.body_bytes = body.len();
+            session.respond_error_with_body(403, body).await?;
+            return Ok(true);
+        }
+
+        let req = session.req_header();
+        let path = req
+            .uri
+            .path_and_query()
+            .map_or("/", |pq| pq.as_str())
+            .to_string();
+        let key = cache_key(ctx.profile, req.method.as_str(), &path, &body);
+
+        if let Some(cache) = cache {
+            if let Some(cached) = cache.get(&key) {
+                debug!("Serving {} for {} profile from cache", path, ctx.profile);
+                ctx.body_bytes = cached.body.len();
+                respond_shared(session, &cached, "X-TPP-Cache", "HIT").await?;
+                return Ok(true);
+            }
+            ctx.cache_key = Some(key.clone());
+        }
+
+        if let Some(coalescer) = coalescer {
+            match coalescer.join(key) {
+                Role::Leader(flight) => ctx.flight = Some(flight),
+                Role::Follower(rx) => {
+                    if let Some(shared) = coalescer.wait(rx).await {
+                        if let Some(metrics) = get_metrics() {
+                            metrics.requests_coalesced.add(1, &[]);
+                        }
+                        debug!("Serving {} with the response of an identical request", path);
+                        ctx.body_bytes = shared.body.len();
+                        respond_shared(session, &shared, "X-TPP-Coalesced", "true").await?;
+                        return Ok(true);
+                    }
+                    debug!("No shared response for {}, sending the request", path);
+                }
+            }
+        }
+
+        Ok(false)
+    }
+
+    /// Largest response body buffered for the cache or coalesced requests,
+    /// if the current response is wanted by either
+    fn share_limit(&self, ctx: &ProxyCtx) -> Option<usize> {
+        let cache = self
+            .cache
+            .as_ref()
+            .filter(|_| ctx.cache_key.is_some())
+            .map(|cache| cache.max_entry_bytes());
+        let flight = self
+            .coalescer
+            .as_ref()
+            .filter(|_| ctx.flight.is_some())
+            .map(|coalescer| coalescer.max_response_bytes());
+        cache.max(flight)
+    }
+
+    /// Buffer a response to share and, once complete, cache it and hand it
+    /// to the requests waiting for it
+    fn share_response(&self, body: &Option<Bytes>, end_of_stream: bool, ctx: &mut ProxyCtx) {
+        let Some(limit) = self.share_limit(ctx) else {
+            return;
+        };
+        let Some((_, buf)) = ctx.shared_response.as_mut() else {
+            return;
+        };
+
+        if let Some(chunk) = body {
+            if buf.len() + chunk.len() > limit {
+                ctx.shared_response = None;
+                ctx.flight = None;
+                return;
+            }
+            buf.extend_from_slice(chunk);
+        }
+        if !end_of_stream {
+            return;
+        }
+
+        let Some((header, buf)) = ctx.shared_response.take() else {
+            return;
+        };
+        // Errors DolphinDB reports in the body are not worth repeating
+        if ctx.app_error {
+            ctx.flight = None;
+            return;
+        }
+
+        let response = Arc::new(CachedResponse::new(header, Bytes::from(buf)));
+        if let (Some(cache), Some(key)) = (&self.cache, ctx.cache_key.take()) {
+            if response.body.len() <= cache.max_entry_bytes() {
+                cache.insert(key, response.clone());
+            }
+        }
+        if let Some(flight) = ctx.flight.take() {
+            flight.complete(response);
+        }
+    }
+
+    /// Buffer the response body and inspect it once complete
+    fn inspect_body(&self, body: &Option<Bytes>, end_of_stream: bool, ctx: &mut ProxyCtx) {
+        let (Some(inspector), Some(buf)) = (&self.inspector, ctx.inspect_body.as_mut()) else {
+            return;
+        };
+
+        // Only the start of the body is kept, where DolphinDB puts the status
+        if let Some(chunk) = body {
+            let room = inspector.max_body_bytes().saturating_sub(buf.len());
+            buf.extend_from_slice(&chunk[..chunk.len().min(room)]);
+        }
+        if !end_of_stream {
+            return;
+        }
+
+        let Some(err) = ctx
+            .inspect_body
+            .take()
+            .and_then(|buf| inspector.inspect(&buf))
+        else {
+            return;
+        };
+        ctx.app_error = true;
+        if let Some(metrics) = get_metrics() {
+            metrics.upstream_app_errors.add(1, &[]);
+        }
+
+        if let (Some(token), Some(pool)) = (&ctx.token, self.pool_for(ctx.profile)) {
+            warn!(
+                "DolphinDB error on {} token #{}: {} (code: {})",
+                ctx.profile, token.id, err.message, err.code
+            );
+            pool.mark_app_error(token);
+            if err.session_error {
+                pool.mark_needs_refresh(token.id);
+            }
+        }
+    }
+
+    /// Check the request against the rate limiter, responding 429 if limited
+    ///
+    /// Returns true if a response was sent.
+    async fn enforce_rate_limit(&self, session: &mut Session, client: &ClientId) -> Result<bool> {
+        let Some(ref limiter) = self.rate_limiter else {
+            return Ok(false);
+        };
+
+        let key = match limiter.key_by() {
+            RateLimitKey::Client => client.to_string(),
+            RateLimitKey::Ip => client_ip(session)
+                .map(|ip| ip.to_string())
+                .unwrap_or_else(|| "unknown".to_string()),
+            RateLimitKey::Route => session.req_header().uri.path().to_string(),
+        };
+
+        let RateDecision::Limited { retry_after } = limiter.check(&key) else {
+            return Ok(false);
+        };
+
+        if let Some(metrics) = get_metrics() {
+            metrics.requests_rate_limited.add(1, &[]);
+        }
+        debug!("Rate limited '{}', retry after {:?}", key, retry_after);
+
+        respond_retry_after(session, 429, retry_after).await?;
+        Ok(true)
+    }
+
+    /// Fail fast with 503 while the circuit is open
+    ///
+    /// Returns true if a response was sent.
+    async fn enforce_circuit_breaker(
+        &self,
+        session: &mut Session,
+        ctx: &mut ProxyCtx,
+    ) -> Result<bool> {
+        let Some(ref breaker) = self.circuit_breaker else {
+            return Ok(false);
+        };
+
+        match breaker.try_acquire() {
+            Ok(()) => {
+                ctx.breaker_admitted = true;
+                Ok(false)
+            }
+            Err(retry_after) => {
+                if let Some(metrics) = get_metrics() {
+                    metrics.requests_circuit_rejected.add(1, &[]);
+                }
+                debug!("Circuit {}, rejecting request", breaker.state());
+                respond_retry_after(session, 503, retry_after).await?;
+                Ok(true)
+            }
+        }
+    }
+}
+
+/// Body of the 403 response to a request denied by the script policy
+fn script_denial_body(reason: &str) -> Bytes {
+    Bytes::from(format!("Denied by script policy: {}\n", reason))
+}
+
+/// Send a response shared from the cache or another request, marked by a
+/// header telling where it came from
+async fn respond_shared(
+    session: &mut Session,
+    shared: &CachedResponse,
+    name: &'static str,
+    value: &'static str,
+) -> Result<()> {
+    let mut header = shared.header.clone();
+    header.insert_header(name, value)?;
+    session
+        .write_response_header(Box::new(header), false)
+        .await?;
+    session
+        .write_response_body(Some(shared.body.clone()), true)
+        .await
+}
+
+/// Respond with an error status and a Retry-After header
+async fn respond_retry_after(
+    session: &mut Session,
+    status: u16,
+    retry_after: Duration,
+) -> Result<()> {
+    // Retry-After is whole seconds; round up so clients don't retry early
+    let retry_secs = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+    let mut resp = ServerSession::generate_error(status);
+    resp.insert_header("Retry-After", retry_secs.max(1).to_string())?;
+    session.write_error_response(resp, Default::default()).await
+}
+
+/// What a finished request says about the health of the upstream
+fn upstream_outcome(e: Option<&pingora::Error>, status: Option<u16>) -> Outcome {
+    if let Some(e) = e {
+        return match e.etype() {
+            ConnectTimedout | ReadTimedout | WriteTimedout => Outcome::Failure,
+            _ if e.esource() == &ErrorSource::Upstream => Outcome::Failure,
+            _ => Outcome::Ignored,
+        };
+    }
+    match status {
+        Some(502..=504) => Outcome::Failure,
+        Some(_) => Outcome::Success,
+        None => Outcome::Ignored,
+    }
+}
+
+/// Access log entry for the request that just finished
+fn access_log_entry(session: &Session, ctx: &ProxyCtx, status: Option<u16>) -> AccessLogEntry {
+    let req = session.req_header();
+    let header = |name: &str| {
+        req.headers
+            .get(name)
+            .and_then(|v| v.to_str().ok())
+            .map(String::from)
+    };
+    let elapsed = TimeDelta::from_std(ctx.request_start.elapsed()).unwrap_or_default();
+
+    AccessLogEntry {
+        time: Local::now() - elapsed,
+        client_addr: session.client_addr().map(|addr| addr.to_string()),
+        client: ctx
+            .client
+            .as_ref()
+            .map_or_else(|| "-".to_string(), |client| client.to_string()),
+        method: req.method.to_string(),
+        path: req.uri.path().to_string(),
+        protocol: format!("{:?}", req.version),
+        status,
+        bytes: ctx.body_bytes,
+        upstream_latency: ctx.upstream_start.map(|start| start.elapsed()),
+        token_wait: ctx.token_wait,
+        token_id: ctx.token.as_ref().map(|token| token.id),
+        pool: ctx.profile.as_str(),
+        referer: header("Referer"),
+        user_agent: header("User-Agent"),
+    }
+}
+
+/// IP address of the downstream client, if connected over TCP
+fn client_ip(session: &Session) -> Option<IpAddr> {
+    session
+        .client_addr()
+        .and_then(|addr| addr.as_inet())
+        .map(|addr| addr.ip())
+}
+
+/// Identity from the verified TLS client certificate: its organization, or
+/// its serial number when the subject has no organization
+fn client_cert_identity(session: &Session) -> Option<String> {
+    let ssl = session.digest()?.ssl_digest.as_ref()?;
+    ssl.organization
+        .clone()
+        .or_else(|| ssl.serial_number.clone())
+}
+
+/// Fail once the total upstream timeout of the request has passed
+fn check_deadline(ctx: &ProxyCtx) -> Result<()> {
+    match ctx.deadline {
+        Some(deadline) if Instant::now() > deadline => {
+            Error::e_explain(ReadTimedout, "total upstream request timeout exceeded")
+        }
+        _ => Ok(()),
+    }
 }
 
 #[async_trait]
@@ -45,32 +683,213 @@ impl ProxyHttp for TokenPoolProxy {
     fn new_ctx(&self) -> Self::CTX {
         ProxyCtx {
             token: None,
+            client: None,
+            priority: Priority::default(),
+            profile: Profile::default(),
             conn_start: Instant::now(),
+            request_start: Instant::now(),
+            upstream_start: None,
+            body_bytes: 0,
+            token_wait: None,
+            deadline: None,
+            upgrade: false,
+            breaker_admitted: false,
+            retries: 0,
+            node: 0,
+            switch_token: false,
+            inspect_body: None,
+            app_error: false,
+            request_body: Vec::new(),
+            script_denial: None,
+            body_checked: false,
+            cache_key: None,
+            flight: None,
+            shared_response: None,
             request_count: 0,
+            span: Span::none(),
+            upstream_span: None,
+        }
+    }
+
+    /// Identify the client and its priority before any token is acquired
+    async fn request_filter(&self, session: &mut Session, ctx: &mut Self::CTX) -> Result<bool> {
+        ctx.request_start = Instant::now();
+        ctx.upstream_start = None;
+        ctx.token_wait = None;
+        ctx.body_bytes = 0;
+        ctx.deadline = None;
+        let req = session.req_header();
+        ctx.span = info_span!(
+            "proxy_request",
+            otel.kind = "server",
+            http.request.method = %req.method,
+            url.path = req.uri.path(),
+            tpp.client = Empty,
+            tpp.pool = Empty,
+            tpp.token_id = Empty,
+            server.address = Empty,
+            http.response.status_code = Empty,
+        );
+        set_parent_from(&ctx.span, &req.headers);
+
+        if self.drain.as_ref().is_some_and(|drain| drain.is_draining()) {
+            // Close the connection so its token goes back to the pool
+            session.set_keepalive(None);
+            session.respond_error(503).await?;
+            return Ok(true);
+        }
+
+        if ctx.client.is_none() {
+            let client = match self.identifier {
+                Some(ref identifier) => identifier.identify(
+                    session.req_header(),
+                    client_ip(session),
+                    client_cert_identity(session).as_deref(),
+                ),
+                None => Some(ClientId::anonymous()),
+            };
+
+            match client {
+                Some(client) => ctx.client = Some(client),
+                None => {
+                    warn!("Rejected request with unknown or missing client credentials");
+                    session.respond_error(401).await?;
+                    return Ok(true);
+                }
+            }
         }
+
+        if self.headers.rejects(session.req_header()) {
+            warn!("Rejected request carrying its own Authorization header");
+            session.respond_error(403).await?;
+            return Ok(true);
+        }
+
+        let client = ctx.client.clone().unwrap_or_else(ClientId::anonymous);
+        ctx.priority = self.priorities.resolve(session.req_header(), &client);
+        ctx.profile = self.profiles.resolve(&client);
+        ctx.span.record("tpp.client", display(&client));
+        ctx.span.record("tpp.pool", ctx.profile.as_str());
+        if self.pool_for(ctx.profile).is_none() {
+            error!(
+                "No token pool for profile '{}' of client '{}'",
+                ctx.profile, client
+            );
+            session.respond_error(503).await?;
+            return Ok(true);
+        }
+
+        if self.enforce_rate_limit(session, &client).await? {
+            return Ok(true);
+        }
+
+        ctx.script_denial = None;
+        ctx.body_checked = false;
+        ctx.cache_key = None;
+        if self.serve_shared(session, ctx).await? {
+            return Ok(true);
+        }
+
+        self.enforce_circuit_breaker(session, ctx).await
     }
 
     /// Select upstream peer and acquire token on first request
     async fn upstream_peer(
         &self,
-        _session: &mut Session,
+        session: &mut Session,
         ctx: &mut Self::CTX,
     ) -> Result<Box<HttpPeer>> {
+        let pool = self
+            .pool_for(ctx.profile)
+            .ok_or_else(|| Error::explain(InternalError, "no token pool for profile"))?;
+
+        // Retrying with a different token: return the one that was used
+        if ctx.switch_token {
+            ctx.switch_token = false;
+            if let Some(token) = ctx.token.take() {
+                debug!("Switching away from token #{} for retry", token.id);
+                pool.release(token);
+            }
+        }
+
         // Acquire token on first request of this connection
         if ctx.token.is_none() {
-            let token = self.pool.acquire().await;
+            let client = ctx.client.get_or_insert_with(ClientId::anonymous);
+            let wait_start = Instant::now();
+            let token = pool
+                .acquire_for(client, ctx.priority)
+                .instrument(info_span!(parent: &ctx.span, "token_wait"))
+                .await;
             info!(
-                "Connection acquired token #{} (pool: {}/{} in use)",
+                "Client '{}' acquired {} token #{} at {} priority (pool: {}/{} in use)",
+                client,
+                ctx.profile,
                 token.id,
-                self.pool.in_use(),
-                self.pool.total()
+                ctx.priority,
+                pool.in_use(),
+                pool.total()
             );
             ctx.token = Some(token);
+            ctx.token_wait = Some(ctx.token_wait.unwrap_or_default() + wait_start.elapsed());
+        } else {
+            ctx.token_wait.get_or_insert(Duration::ZERO);
         }
 
-        ctx.request_count += 1;
+        // Retries are attempts of the same request
+        if ctx.retries == 0 {
+            ctx.request_count += 1;
+            if let Some(ref budget) = self.retry_budget {
+                budget.record_request();
+            }
+        }
 
-        let peer = HttpPeer::new(&self.upstream, self.use_tls, self.upstream.clone());
+        // SNI is the bare host unless overridden by the upstream TLS settings
+        let address = &self.nodes[ctx.node % self.nodes.len()];
+        if let Some(ref token) = ctx.token {
+            ctx.span.record("tpp.token_id", token.id);
+        }
+        ctx.span.record("server.address", address.as_str());
+        // A retry replaces, and so ends, the span of the failed attempt
+        ctx.upstream_span = Some(info_span!(
+            parent: &ctx.span,
+            "upstream",
+            otel.kind = "client",
+            server.address = address.as_str(),
+            tpp.retry = ctx.retries,
+            http.response.status_code = Empty,
+        ));
+        let host = address
+            .rsplit_once(':')
+            .map_or(address.as_str(), |(host, _)| host);
+        let mut peer = HttpPeer::new(address, self.use_tls, host.to_string());
+        if let Some(ref tls) = self.upstream_tls {
+            tls.apply_to_peer(&mut peer);
+        }
+        if self.upstream_h2 {
+            peer.options.set_http_version(2, 1);
+        }
+
+        // The total timeout starts with the first attempt, so retries share
+        // it, and per-operation timeouts never outlast what is left of it.
+        // A WebSocket stream lives as long as the client keeps it open and
+        // may stay silent between messages, so neither applies to it.
+        ctx.upgrade = session.is_upgrade_req();
+        if ctx.deadline.is_none() {
+            let total = self.timeouts.total().filter(|_| !ctx.upgrade);
+            ctx.deadline = total.map(|total| Instant::now() + total);
+        }
+        check_deadline(ctx)?;
+        let remaining = ctx
+            .deadline
+            .map(|deadline| deadline.saturating_duration_since(Instant::now()));
+        let cap = |timeout: Option<Duration>| match (timeout, remaining) {
+            (Some(timeout), Some(remaining)) => Some(timeout.min(remaining)),
+            (timeout, remaining) => timeout.or(remaining),
+        };
+        peer.options.connection_timeout = cap(self.timeouts.connect());
+        peer.options.read_timeout = cap(self.timeouts.read().filter(|_| !ctx.upgrade));
+        peer.options.write_timeout = cap(self.timeouts.write());
+        peer.options.idle_timeout = self.timeouts.idle();
 
         Ok(Box::new(peer))
     }
@@ -82,6 +901,8 @@ impl ProxyHttp for TokenPoolProxy {
         upstream_request: &mut pingora::http::RequestHeader,
         ctx: &mut Self::CTX,
     ) -> Result<()> {
+        self.headers.apply(upstream_request)?;
+
         if let Some(ref token) = ctx.token {
             upstream_request
                 .insert_header("Authorization", format!("Bearer {}", token.value))
@@ -96,9 +917,235 @@ impl ProxyHttp for TokenPoolProxy {
             debug!("Injected Authorization header for token #{}", token.id);
         }
 
+        ctx.upstream_start = Some(Instant::now());
+
+        // Continue the trace upstream, under the span of this attempt
+        let span = ctx.upstream_span.as_ref().unwrap_or(&ctx.span);
+        for (name, value) in trace_context(span) {
+            upstream_request.insert_header(name, value)?;
+        }
+
         Ok(())
     }
 
+    /// Hold the request body back until it is complete, then forward it only
+    /// if the script policy allows it
+    async fn request_body_filter(
+        &self,
+        session: &mut Session,
+        body: &mut Option<Bytes>,
+        end_of_stream: bool,
+        ctx: &mut Self::CTX,
+    ) -> Result<()> {
+        let Some(ref policy) = self.script_policy else {
+            return Ok(());
+        };
+        // Read ahead and checked for the cache, or WebSocket frames
+        if ctx.body_checked || session.is_upgrade_req() {
+            return Ok(());
+        }
+
+        if let Some(chunk) = body.take() {
+            // Stop buffering early; the policy denies oversized bodies
+            if ctx.request_body.len() <= policy.max_body_bytes() {
+                ctx.request_body.extend_from_slice(&chunk);
+            }
+        }
+        if !end_of_stream {
+            // An empty chunk sends nothing yet, where None would end the body
+            *body = Some(Bytes::new());
+            return Ok(());
+        }
+        if ctx.request_body.is_empty() {
+            return Ok(());
+        }
+
+        let request_body = std::mem::take(&mut ctx.request_body);
+        if !self.check_script(session, ctx, &request_body) {
+            return Error::e_explain(HTTPStatus(403), "denied by script policy");
+        }
+        *body = Some(Bytes::from(request_body));
+        Ok(())
+    }
+
+    /// Retry connection failures (nothing was sent yet) within the budget,
+    /// on the next node and/or with a different token if configured
+    fn fail_to_connect(
+        &self,
+        _session: &mut Session,
+        peer: &HttpPeer,
+        ctx: &mut Self::CTX,
+        mut e: Box<pingora::Error>,
+    ) -> Box<pingora::Error> {
+        let Some(ref budget) = self.retry_budget else {
+            return e;
+        };
+
+        if ctx.retries >= self.retry.max_retries || !budget.try_retry() {
+            debug!(
+                "Not retrying connection to {} after {} retries",
+                peer, ctx.retries
+            );
+            e.set_retry(false);
+            return e;
+        }
+
+        ctx.retries += 1;
+        if self.retry.switch_node {
+            ctx.node += 1;
+        }
+        ctx.switch_token = self.retry.switch_token;
+
+        if let Some(metrics) = get_metrics() {
+            metrics.upstream_retries.add(1, &[]);
+        }
+        warn!(
+            "Connection to {} failed ({}), retry {}/{} on {}",
+            peer,
+            e.etype().as_str(),
+            ctx.retries,
+            self.retry.max_retries,
+            self.nodes[ctx.node % self.nodes.len()]
+        );
+
+        e.set_retry(true);
+        e
+    }
+
+    /// Decide whether to inspect the response body for application errors
+    /// and whether to share the response
+    async fn response_filter(
+        &self,
+        _session: &mut Session,
+        upstream_response: &mut pingora::http::ResponseHeader,
+        ctx: &mut Self::CTX,
+    ) -> Result<()> {
+        check_deadline(ctx)?;
+
+        let status = upstream_response.status.as_u16();
+        if let Some(ref span) = ctx.upstream_span {
+            span.record("http.response.status_code", status);
+        }
+        if let Some(metrics) = get_metrics() {
+            metrics.upstream_responses.add(
+                1,
+                &[
+                    KeyValue::new("upstream", self.nodes[ctx.node % self.nodes.len()].clone()),
+                    KeyValue::new("status_class", status_class(Some(status))),
+                ],
+            );
+        }
+
+        ctx.inspect_body = self
+            .inspector
+            .as_ref()
+            .filter(|inspector| inspector.wants(upstream_response))
+            .map(|_| Vec::new());
+
+        ctx.shared_response = None;
+        if let Some(limit) = self.share_limit(ctx) {
+            let too_large = matches!(
+                upstream_response
+                    .headers
+                    .get("Content-Length")
+                    .and_then(|v| v.to_str().ok())
+                    .and_then(|v| v.parse::<usize>().ok()),
+                Some(len) if len > limit
+            );
+            if upstream_response.status.as_u16() == 200 && !too_large {
+                ctx.shared_response = Some((upstream_response.clone(), Vec::new()));
+            } else {
+                // Waiting requests send their own
+                ctx.flight = None;
+            }
+        }
+        if ctx.cache_key.is_some() {
+            upstream_response.insert_header("X-TPP-Cache", "MISS")?;
+        }
+        Ok(())
+    }
+
+    /// Fail the request once the total timeout has passed, even while data
+    /// is still trickling in, and inspect the body if requested
+    fn upstream_response_body_filter(
+        &self,
+        _session: &mut Session,
+        body: &mut Option<Bytes>,
+        end_of_stream: bool,
+        ctx: &mut Self::CTX,
+    ) -> Result<()> {
+        check_deadline(ctx)?;
+
+        self.inspect_body(body, end_of_stream, ctx);
+        self.share_response(body, end_of_stream, ctx);
+        Ok(())
+    }
+
+    /// Count the response body bytes sent to the client, as Pingora's own
+    /// count includes the HTTP/1 response header
+    fn response_body_filter(
+        &self,
+        _session: &mut Session,
+        body: &mut Option<Bytes>,
+        _end_of_stream: bool,
+        ctx: &mut Self::CTX,
+    ) -> Result<Option<Duration>> {
+        ctx.body_bytes += body.as_ref().map_or(0, Bytes::len);
+        Ok(None)
+    }
+
+    /// Answer upstream timeouts with 504, other failures as Pingora does
+    async fn fail_to_proxy(
+        &self,
+        session: &mut Session,
+        e: &Error,
+        ctx: &mut Self::CTX,
+    ) -> FailToProxy {
+        if let Some(ref reason) = ctx.script_denial {
+            let body = script_denial_body(reason);
+            ctx.body_bytes = body.len();
+            if let Err(e) = session.respond_error_with_body(403, body).await {
+                warn!("Failed to send error response to downstream: {}", e);
+            }
+            return FailToProxy {
+                error_code: 403,
+                can_reuse_downstream: false,
+            };
+        }
+
+        let code = match e.etype() {
+            ConnectTimedout | ReadTimedout | WriteTimedout => {
+                warn!(
+                    "Upstream request timed out ({}) on token #{}",
+                    e.etype().as_str(),
+                    ctx.token
+                        .as_ref()
+                        .map_or("-".to_string(), |t| t.id.to_string())
+                );
+                504
+            }
+            HTTPStatus(code) => *code,
+            _ => match e.esource() {
+                ErrorSource::Upstream => 502,
+                ErrorSource::Downstream => match e.etype() {
+                    WriteError | ReadError | ConnectionClosed => 0,
+                    _ => 400,
+                },
+                ErrorSource::Internal | ErrorSource::Unset => 500,
+            },
+        };
+        if code > 0 {
+            if let Err(e) = session.respond_error(code).await {
+                warn!("Failed to send error response to downstream: {}", e);
+            }
+        }
+
+        FailToProxy {
+            error_code: code,
+            can_reuse_downstream: false,
+        }
+    }
+
     /// Called when request completes (success or error)
     async fn logging(
         &self,
@@ -107,35 +1154,718 @@ impl ProxyHttp for TokenPoolProxy {
         ctx: &mut Self::CTX,
     ) {
         let duration = ctx.conn_start.elapsed();
+        // Logged in the request's span, which ends once this returns
+        let span = ctx.span.clone();
+        let _entered = span.enter();
 
-        // Check if this was an error response
-        let is_error = e.is_some()
-            || session
-                .response_written()
-                .is_some_and(|resp| resp.status.as_u16() >= 400);
+        // Requests still waiting on a failed request send their own
+        ctx.flight = None;
+
+        if ctx.breaker_admitted {
+            ctx.breaker_admitted = false;
+            if let Some(ref breaker) = self.circuit_breaker {
+                let status = session.response_written().map(|resp| resp.status.as_u16());
+                breaker.record(upstream_outcome(e, status));
+            }
+        }
+
+        // Check if this was an error response (a denied script is the
+        // client's fault, not the token's)
+        let status = session.response_written().map(|resp| resp.status.as_u16());
+        if let Some(status) = status {
+            ctx.span.record("http.response.status_code", status);
+        }
+        if let Some(ref access_log) = self.access_log {
+            access_log.log(&access_log_entry(session, ctx, status));
+        }
+        // End the spans of this request with the logging below
+        ctx.upstream_span = None;
+        ctx.span = Span::none();
+
+        let is_error = ctx.script_denial.is_none()
+            && (e.is_some() || ctx.app_error || status.is_some_and(|status| status >= 400));
+
+        let Some(pool) = self.pool_for(ctx.profile) else {
+            return;
+        };
+
+        if let Some(metrics) = get_metrics() {
+            let attrs = [
+                KeyValue::new("pool", pool.name()),
+                KeyValue::new("status_class", status_class(status)),
+            ];
+            metrics.requests_total.add(1, &attrs);
+            if is_error {
+                metrics.requests_errors.add(1, &attrs);
+            }
+            metrics
+                .request_duration_seconds
+                .record(ctx.request_start.elapsed().as_secs_f64(), &attrs);
+        }
 
         if let Some(ref token) = ctx.token {
             if is_error {
-                self.pool.mark_error(token);
+                pool.mark_error(token);
             }
         }
 
         // Only release token when connection is closing
-        // For HTTP/1.1 keep-alive, this happens when the connection ends
-        if session.is_body_done() {
+        // For HTTP/1.1 keep-alive, this happens when the connection ends;
+        // a failed request (e.g. timeout) also closes the connection, and an
+        // upgraded WebSocket stream is only logged once it has closed.
+        // Each HTTP/2 stream has its own context, so its token is released
+        // when the stream ends.
+        if e.is_some() || ctx.upgrade || session.is_body_done() {
             if let Some(token) = ctx.token.take() {
                 let token_id = token.id;
-                self.pool.release(token);
+                pool.release(token);
+                if let Some(metrics) = get_metrics() {
+                    metrics.connection_duration_seconds.record(
+                        duration.as_secs_f64(),
+                        &[KeyValue::new("pool", pool.name())],
+                    );
+                }
 
                 info!(
-                    "Connection released token #{} after {} requests, duration: {:.2}s (pool: {}/{} in use)",
+                    "Connection released {} token #{} after {} requests, duration: {:.2}s (pool: {}/{} in use)",
+                    ctx.profile,
                     token_id,
                     ctx.request_count,
                     duration.as_secs_f64(),
-                    self.pool.in_use(),
-                    self.pool.total()
+                    pool.in_use(),
+                    pool.total()
                 );
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::circuit_breaker::CircuitState;
+    use crate::config::{
+        AccessLogConfig, CircuitBreakerConfig, ClientEntry, ClientIdentifyBy, ClientsConfig,
+        CoalescingConfig, Credential, HeaderPolicyConfig, ResponseCacheConfig,
+        ResponseInspectionConfig, ScriptPolicyConfig,
+    };
+    use crate::test_support::{
+        spawn_h2c_proxy, spawn_proxy, MockResponse, MockUpstream, MockWebSocket,
+    };
+    use futures_util::{SinkExt, StreamExt};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio_tungstenite::tungstenite::Message;
+
+    fn pool() -> Arc<TokenPool> {
+        TokenPool::new(
+            vec!["pool-token".to_string()],
+            Credential {
+                username: "testuser".to_string(),
+                password: "testpass".to_string(),
+            },
+        )
+    }
+
+    #[tokio::test]
+    async fn test_client_credentials_are_replaced() {
+        let upstream = MockUpstream::echo().await;
+        let config = HeaderPolicyConfig {
+            set: [("X-Proxied-By".to_string(), "tpp".to_string())].into(),
+            ..Default::default()
+        };
+        let proxy = TokenPoolProxy::new(pool(), upstream.address(), false)
+            .with_header_policy(HeaderPolicy::new(&config));
+        let addr = spawn_proxy(proxy);
+
+        let resp = reqwest::Client::new()
+            .post(format!("http://{}/api/executeCode", addr))
+            .header("Authorization", "Bearer client-token")
+            .header("Cookie", "session=abc")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), 200);
+
+        let head = resp.text().await.unwrap().to_ascii_lowercase();
+        assert!(head.contains("authorization: bearer pool-token"));
+        assert!(!head.contains("client-token"));
+        assert!(!head.contains("cookie:"));
+        assert!(head.contains("x-proxied-by: tpp"));
+    }
+
+    #[tokio::test]
+    async fn test_trace_context_forwarded() {
+        let upstream = MockUpstream::echo().await;
+        let proxy = TokenPoolProxy::new(pool(), upstream.address(), false);
+        let addr = spawn_proxy(proxy);
+
+        let traceparent = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+        let resp = reqwest::Client::new()
+            .post(format!("http://{}/api/executeCode", addr))
+            .header("traceparent", traceparent)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), 200);
+
+        // Without span export the client's trace context is passed on as is
+        let head = resp.text().await.unwrap().to_ascii_lowercase();
+        assert!(head.contains(&format!("traceparent: {}", traceparent)));
+    }
+
+    #[tokio::test]
+    async fn test_access_log_records_request() {
+        let upstream = MockUpstream::echo().await;
+        let dir = std::env::temp_dir().join(format!("tpp-proxy-access-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("access.log");
+        let config = AccessLogConfig {
+            enabled: true,
+            path: Some(path.to_string_lossy().to_string()),
+            ..Default::default()
+        };
+        let proxy = TokenPoolProxy::new(pool(), upstream.address(), false)
+            .with_access_log(Arc::new(AccessLog::new(&config).unwrap()));
+        let addr = spawn_proxy(proxy);
+
+        let resp = reqwest::Client::new()
+            .post(format!("http://{}/api/executeCode", addr))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), 200);
+        let body_len = resp.bytes().await.unwrap().len();
+
+        // The line is written once the request is logged, after the response
+        let mut line = String::new();
+        for _ in 0..50 {
+            line = std::fs::read_to_string(&path).unwrap();
+            if !line.is_empty() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        let entry: serde_json::Value = serde_json::from_str(line.trim()).unwrap();
+        assert_eq!(entry["client"], "anonymous");
+        assert_eq!(entry["method"], "POST");
+        assert_eq!(entry["path"], "/api/executeCode");
+        assert_eq!(entry["status"], 200);
+        assert_eq!(entry["bytes"], body_len);
+        assert_eq!(entry["token_id"], 0);
+        assert_eq!(entry["pool"], "read_write");
+        assert!(entry["upstream_latency_ms"].is_f64());
+        assert!(entry["token_wait_ms"].is_f64());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_client_authorization_rejected() {
+        let upstream = MockUpstream::echo().await;
+        let config = HeaderPolicyConfig {
+            reject_client_authorization: true,
+            ..Default::default()
+        };
+        let proxy = TokenPoolProxy::new(pool(), upstream.address(), false)
+            .with_header_policy(HeaderPolicy::new(&config));
+        let addr = spawn_proxy(proxy);
+        let client = reqwest::Client::new();
+
+        let resp = client
+            .get(format!("http://{}/", addr))
+            .header("Authorization", "Bearer client-token")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), 403);
+        assert_eq!(upstream.request_count(), 0);
+
+        let resp = client
+            .get(format!("http://{}/", addr))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), 200);
+        assert_eq!(upstream.request_count(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_upstream_timeout_returns_504() {
+        let upstream = MockUpstream::start(|_| MockResponse {
+            delay: Duration::from_secs(3),
+            ..MockResponse::ok("{}")
+        })
+        .await;
+        let pool = pool();
+        let timeouts = UpstreamTimeouts {
+            read_seconds: Some(1),
+            ..Default::default()
+        };
+        let proxy =
+            TokenPoolProxy::new(pool.clone(), upstream.address(), false).with_timeouts(timeouts);
+        let addr = spawn_proxy(proxy);
+
+        let resp = reqwest::Client::new()
+            .post(format!("http://{}/api/executeCode", addr))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), 504);
+
+        // The hung session is recorded against its token, which is returned
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let (_, errors, _) = pool.get_token_stats(0).unwrap();
+        assert_eq!(errors, 1);
+        assert_eq!(pool.in_use(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_total_timeout_caps_read_timeout() {
+        let upstream = MockUpstream::start(|_| MockResponse {
+            delay: Duration::from_secs(3),
+            ..MockResponse::ok("{}")
+        })
+        .await;
+        let timeouts = UpstreamTimeouts {
+            read_seconds: Some(5),
+            total_seconds: Some(1),
+            ..Default::default()
+        };
+        let proxy = TokenPoolProxy::new(pool(), upstream.address(), false).with_timeouts(timeouts);
+        let addr = spawn_proxy(proxy);
+
+        let start = Instant::now();
+        let resp = reqwest::Client::new()
+            .post(format!("http://{}/api/executeCode", addr))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), 504);
+        assert!(start.elapsed() < Duration::from_secs(2));
+    }
+
+    #[tokio::test]
+    async fn test_open_circuit_fails_fast() {
+        // Nothing listens on this port, so every connect fails
+        let dead = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let breaker = Arc::new(CircuitBreaker::new(&CircuitBreakerConfig {
+            enabled: true,
+            failure_threshold: 1,
+            ..Default::default()
+        }));
+        let pool = pool();
+        let proxy = TokenPoolProxy::new(pool.clone(), dead.to_string(), false)
+            .with_circuit_breaker(breaker.clone());
+        let addr = spawn_proxy(proxy);
+        let client = reqwest::Client::new();
+
+        let resp = client
+            .get(format!("http://{}/", addr))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), 502);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(breaker.state(), CircuitState::Open);
+
+        let resp = client
+            .get(format!("http://{}/", addr))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), 503);
+        assert!(resp.headers().contains_key("retry-after"));
+        assert_eq!(pool.get_token_stats(0).unwrap().0, 1);
+    }
+
+    #[tokio::test]
+    async fn test_draining_refuses_requests() {
+        let upstream = MockUpstream::echo().await;
+        let drain = Arc::new(Drain::default());
+        let proxy =
+            TokenPoolProxy::new(pool(), upstream.address(), false).with_drain(drain.clone());
+        let addr = spawn_proxy(proxy);
+        let client = reqwest::Client::new();
+
+        assert!(drain.start());
+        let resp = client
+            .get(format!("http://{}/", addr))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), 503);
+        assert_eq!(upstream.request_count(), 0);
+
+        assert!(drain.stop());
+        let resp = client
+            .get(format!("http://{}/", addr))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), 200);
+    }
+
+    #[tokio::test]
+    async fn test_connect_failure_retried_on_next_node() {
+        let upstream = MockUpstream::echo().await;
+        let dead = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let retry = RetryConfig {
+            max_retries: 1,
+            ..Default::default()
+        };
+        let budget = Arc::new(RetryBudget::new(&retry));
+        let proxy = TokenPoolProxy::new(pool(), dead.to_string(), false)
+            .with_fallback_nodes(vec![upstream.address()])
+            .with_retry(retry, budget.clone());
+        let addr = spawn_proxy(proxy);
+
+        let resp = reqwest::Client::new()
+            .get(format!("http://{}/", addr))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), 200);
+        assert_eq!(upstream.request_count(), 1);
+        assert_eq!(budget.retries(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_websocket_holds_token_until_closed() {
+        let upstream = MockWebSocket::echo().await;
+        let pool = pool();
+        let timeouts = UpstreamTimeouts {
+            read_seconds: Some(1),
+            total_seconds: Some(1),
+            ..Default::default()
+        };
+        // Frames are not scripts, so the script policy leaves them alone
+        let policy = ScriptPolicy::new(&ScriptPolicyConfig {
+            enabled: true,
+            ..Default::default()
+        });
+        let proxy = TokenPoolProxy::new(pool.clone(), upstream.address(), false)
+            .with_timeouts(timeouts)
+            .with_script_policy(policy);
+        let addr = spawn_proxy(proxy);
+
+        let (mut ws, resp) = tokio_tungstenite::connect_async(format!("ws://{}/stream", addr))
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), 101);
+        assert_eq!(
+            upstream.authorizations.lock().as_slice(),
+            [Some("Bearer pool-token".to_string())]
+        );
+
+        // The stream outlives the read and total timeouts while holding the token
+        ws.send(Message::text("ping")).await.unwrap();
+        assert_eq!(ws.next().await.unwrap().unwrap(), Message::text("ping"));
+        tokio::time::sleep(Duration::from_millis(1500)).await;
+        assert_eq!(pool.in_use(), 1);
+        ws.send(Message::text("still here")).await.unwrap();
+        assert_eq!(
+            ws.next().await.unwrap().unwrap(),
+            Message::text("still here")
+        );
+
+        ws.close(None).await.unwrap();
+        for _ in 0..50 {
+            if pool.in_use() == 0 {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        panic!("token was not released after the WebSocket closed");
+    }
+
+    #[tokio::test]
+    async fn test_h2c_streams_take_a_token_each() {
+        let upstream = MockUpstream::start(|_| MockResponse {
+            delay: Duration::from_millis(200),
+            ..MockResponse::ok("{}")
+        })
+        .await;
+        let pool = pool();
+        let proxy = TokenPoolProxy::new(pool.clone(), upstream.address(), false);
+        let addr = spawn_h2c_proxy(proxy);
+
+        // Both streams share one connection; the single token serves them in turn
+        let client = reqwest::Client::builder()
+            .http2_prior_knowledge()
+            .build()
+            .unwrap();
+        let url = format!("http://{}/", addr);
+        let (a, b) = tokio::join!(client.get(&url).send(), client.get(&url).send());
+        for resp in [a.unwrap(), b.unwrap()] {
+            assert_eq!(resp.version(), reqwest::Version::HTTP_2);
+            assert_eq!(resp.status(), 200);
+            resp.text().await.unwrap();
+        }
+        assert_eq!(upstream.request_count(), 2);
+
+        // Tokens go back to the pool as each stream ends
+        for _ in 0..50 {
+            if pool.in_use() == 0 {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        panic!("tokens were not released after the streams ended");
+    }
+
+    #[tokio::test]
+    async fn test_app_error_in_200_marks_token_for_refresh() {
+        let upstream = MockUpstream::start(|head| {
+            if head.starts_with("POST") {
+                MockResponse::ok(r#"{"code":"1","message":"The session is expired"}"#)
+            } else {
+                MockResponse::ok(r#"{"code":"0","message":"","result":[]}"#)
+            }
+        })
+        .await;
+        let pool = pool();
+        let proxy = TokenPoolProxy::new(pool.clone(), upstream.address(), false)
+            .with_response_inspector(ResponseInspector::new(&ResponseInspectionConfig::default()));
+        let addr = spawn_proxy(proxy);
+        let client = reqwest::Client::new();
+
+        let resp = client
+            .get(format!("http://{}/", addr))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), 200);
+        resp.text().await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(pool.app_errors(), 0);
+
+        // The client still gets DolphinDB's response unchanged
+        let resp = client
+            .post(format!("http://{}/api/executeCode", addr))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), 200);
+        assert!(resp.text().await.unwrap().contains("session is expired"));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(pool.app_errors(), 1);
+        assert_eq!(pool.get_token_stats(0).unwrap().1, 1);
+        assert_eq!(pool.get_tokens_needing_refresh(), vec![0]);
+    }
+
+    #[tokio::test]
+    async fn test_script_policy_denies_before_upstream() {
+        let upstream = MockUpstream::echo().await;
+        let pool = pool();
+        let policy = ScriptPolicy::new(&ScriptPolicyConfig {
+            enabled: true,
+            ..Default::default()
+        });
+        let proxy =
+            TokenPoolProxy::new(pool.clone(), upstream.address(), false).with_script_policy(policy);
+        let addr = spawn_proxy(proxy);
+        let client = reqwest::Client::new();
+        let execute = |script: &str| {
+            client
+                .post(format!("http://{}/api/executeCode", addr))
+                .json(&serde_json::json!({
+                    "functionName": "executeCode",
+                    "params": [{"name": "script", "value": script}],
+                }))
+                .send()
+        };
+
+        let resp = execute(r#"dropDatabase("dfs://db")"#).await.unwrap();
+        assert_eq!(resp.status(), 403);
+        assert!(resp.text().await.unwrap().contains("'dropDatabase'"));
+        assert_eq!(upstream.request_count(), 0);
+
+        let resp = execute("select count(*) from t").await.unwrap();
+        assert_eq!(resp.status(), 200);
+        assert_eq!(upstream.request_count(), 1);
+
+        // Denials are not held against the token
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(pool.get_token_stats(0).unwrap().1, 0);
+    }
+
+    #[tokio::test]
+    async fn test_script_policy_forwards_body_sent_in_chunks() {
+        let upstream = MockUpstream::echo().await;
+        let policy = ScriptPolicy::new(&ScriptPolicyConfig {
+            enabled: true,
+            ..Default::default()
+        });
+        let proxy =
+            TokenPoolProxy::new(pool(), upstream.address(), false).with_script_policy(policy);
+        let addr = spawn_proxy(proxy);
+
+        let body = r#"{"functionName":"executeCode","params":[{"name":"script","value":"select count(*) from t"}]}"#;
+        let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let head = format!(
+            "POST /api/executeCode HTTP/1.1\r\nHost: tpp\r\nContent-Type: application/json\r\n\
+             Content-Length: {}\r\nConnection: close\r\n\r\n",
+            body.len()
+        );
+        stream.write_all(head.as_bytes()).await.unwrap();
+        for chunk in body.as_bytes().chunks(30) {
+            stream.write_all(chunk).await.unwrap();
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        let mut resp = String::new();
+        tokio::time::timeout(Duration::from_secs(5), stream.read_to_string(&mut resp))
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert!(resp.starts_with("HTTP/1.1 200"));
+        assert_eq!(
+            upstream.bodies.lock().as_slice(),
+            [body.as_bytes().to_vec()]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_repeated_query_served_from_cache() {
+        let upstream = MockUpstream::echo().await;
+        let pool = pool();
+        let cache = Arc::new(ResponseCache::new(&ResponseCacheConfig {
+            enabled: true,
+            profiles: vec![Profile::ReadWrite],
+            ..Default::default()
+        }));
+        let proxy = TokenPoolProxy::new(pool.clone(), upstream.address(), false)
+            .with_response_cache(cache.clone());
+        let addr = spawn_proxy(proxy);
+        let client = reqwest::Client::new();
+        let execute = |script: &str, bypass: bool| {
+            let mut req = client
+                .post(format!("http://{}/api/executeCode", addr))
+                .json(&serde_json::json!({
+                    "functionName": "executeCode",
+                    "params": [{"name": "script", "value": script}],
+                }));
+            if bypass {
+                req = req.header("X-TPP-Cache-Bypass", "1");
+            }
+            req.send()
+        };
+
+        let resp = execute("select count(*) from t", false).await.unwrap();
+        assert_eq!(resp.headers()["X-TPP-Cache"], "MISS");
+        let body = resp.text().await.unwrap();
+        assert!(body.contains("Bearer pool-token"));
+
+        // Same query with different whitespace: no token, no upstream request
+        let resp = execute("select  count(*)\nfrom t", false).await.unwrap();
+        assert_eq!(resp.headers()["X-TPP-Cache"], "HIT");
+        assert_eq!(resp.text().await.unwrap(), body);
+        assert_eq!(upstream.request_count(), 1);
+        assert_eq!(pool.get_token_stats(0).unwrap().0, 1);
+
+        let resp = execute("select count(*) from t", true).await.unwrap();
+        assert_eq!(resp.status(), 200);
+        assert!(resp.headers().get("X-TPP-Cache").is_none());
+        assert_eq!(upstream.request_count(), 2);
+        assert_eq!((cache.hits(), cache.misses()), (1, 1));
+    }
+
+    #[tokio::test]
+    async fn test_identical_requests_in_flight_share_one_token() {
+        let upstream = MockUpstream::start(|_| MockResponse {
+            delay: Duration::from_millis(300),
+            ..MockResponse::ok(r#"{"code":"0","result":[42]}"#)
+        })
+        .await;
+        let pool = pool();
+        let coalescer = Arc::new(Coalescer::new(&CoalescingConfig {
+            enabled: true,
+            routes: vec!["/api/executeCode".to_string()],
+            profiles: vec![Profile::ReadWrite],
+            ..Default::default()
+        }));
+        let proxy = TokenPoolProxy::new(pool.clone(), upstream.address(), false)
+            .with_coalescer(coalescer.clone());
+        let addr = spawn_proxy(proxy);
+        let client = reqwest::Client::new();
+        let execute = || {
+            client
+                .post(format!("http://{}/api/executeCode", addr))
+                .json(&serde_json::json!({
+                    "functionName": "executeCode",
+                    "params": [{"name": "script", "value": "select sum(x) from t"}],
+                }))
+                .send()
+        };
+
+        let responses = futures_util::future::join_all((0..3).map(|_| execute())).await;
+        let mut coalesced = 0;
+        for resp in responses {
+            let resp = resp.unwrap();
+            assert_eq!(resp.status(), 200);
+            coalesced += resp.headers().contains_key("X-TPP-Coalesced") as usize;
+            assert_eq!(resp.text().await.unwrap(), r#"{"code":"0","result":[42]}"#);
+        }
+
+        assert_eq!(coalesced, 2);
+        assert_eq!(upstream.request_count(), 1);
+        assert_eq!(pool.get_token_stats(0).unwrap().0, 1);
+        assert_eq!(coalescer.in_flight(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_profiles_use_their_own_pool() {
+        let upstream = MockUpstream::echo().await;
+        let entry = |id: &str, profile| ClientEntry {
+            id: id.to_string(),
+            api_key: Some(format!("{}-key", id)),
+            max_tokens: None,
+            priority: None,
+            profile,
+        };
+        let clients = ClientsConfig {
+            identify_by: ClientIdentifyBy::ApiKey,
+            entries: vec![
+                entry("reports", Some(Profile::ReadOnly)),
+                entry("ops", Some(Profile::Admin)),
+                entry("someone", None),
+            ],
+            ..Default::default()
+        };
+        let read_only = TokenPool::new(
+            vec!["reader-token".to_string()],
+            Credential {
+                username: "reader".to_string(),
+                password: "readerpass".to_string(),
+            },
+        );
+        let proxy = TokenPoolProxy::new(pool(), upstream.address(), false)
+            .with_client_identifier(ClientIdentifier::new(&clients))
+            .with_profile_resolver(ProfileResolver::new(&clients))
+            .with_profile_pool(Profile::ReadOnly, read_only.clone());
+        let addr = spawn_proxy(proxy);
+        let client = reqwest::Client::new();
+        let send = |id: &str| {
+            client
+                .get(format!("http://{}/", addr))
+                .header("X-API-Key", format!("{}-key", id))
+                .send()
+        };
+
+        let head = send("reports").await.unwrap().text().await.unwrap();
+        assert!(head.contains("Bearer reader-token"));
+        let head = send("someone").await.unwrap().text().await.unwrap();
+        assert!(head.contains("Bearer pool-token"));
+
+        // No admin pool: admin clients are refused rather than served another profile's token
+        assert_eq!(send("ops").await.unwrap().status(), 503);
+        assert_eq!(upstream.request_count(), 2);
+        assert_eq!(read_only.get_token_stats(0).unwrap().0, 1);
+    }
+}
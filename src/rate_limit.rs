@@ -0,0 +1,282 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use dashmap::DashMap;
+use parking_lot::Mutex;
+
+use crate::config::{RateLimit, RateLimitConfig, RateLimitKey};
+use crate::telemetry::get_metrics;
+
+/// Idle buckets are pruned once the number of tracked keys exceeds this
+const MAX_TRACKED_KEYS: usize = 10_000;
+
+/// Buckets are pruned at most this often, so a client cycling through keys
+/// doesn't make every request scan them all
+const PRUNE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Classic token bucket: `rate` tokens per second, up to `burst` tokens
+#[derive(Debug)]
+struct TokenBucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    fn full(limit: &RateLimit, now: Instant) -> Self {
+        Self {
+            tokens: limit.burst_size(),
+            updated: now,
+        }
+    }
+
+    fn refill(&mut self, limit: &RateLimit, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * limit.requests_per_second).min(limit.burst_size());
+        self.updated = now;
+    }
+
+    /// Take one token, or return how long until one is available
+    fn try_take(&mut self, limit: &RateLimit, now: Instant) -> Result<(), Duration> {
+        self.refill(limit, now);
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
+        } else {
+            let missing = 1.0 - self.tokens;
+            Err(Duration::from_secs_f64(missing / limit.requests_per_second))
+        }
+    }
+
+    fn refund(&mut self, limit: &RateLimit) {
+        self.tokens = (self.tokens + 1.0).min(limit.burst_size());
+    }
+}
+
+/// Outcome of a rate limit check
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RateDecision {
+    Allowed,
+    /// Rejected; the client may retry after this long
+    Limited {
+        retry_after: Duration,
+    },
+}
+
+/// Requests-per-second limiter, per key (client, IP or route) and globally
+pub struct RateLimiter {
+    /// What requests are keyed by
+    key_by: RateLimitKey,
+    /// Limit for keys without an override
+    default: Option<RateLimit>,
+    /// Per-key limits
+    overrides: HashMap<String, RateLimit>,
+    /// Limit across all requests
+    global: Option<(RateLimit, Mutex<TokenBucket>)>,
+    /// Buckets per key
+    buckets: DashMap<String, TokenBucket>,
+    /// When the buckets were last pruned
+    last_prune: Mutex<Instant>,
+    /// Number of requests allowed
+    allowed: AtomicU64,
+    /// Number of requests rejected
+    limited: AtomicU64,
+}
+
+impl RateLimiter {
+    pub fn new(config: &RateLimitConfig) -> Self {
+        let now = Instant::now();
+        Self {
+            key_by: config.key,
+            default: config.per_key.clone(),
+            overrides: config
+                .overrides
+                .iter()
+                .map(|o| (o.key.clone(), o.limit.clone()))
+                .collect(),
+            global: config.global.clone().map(|limit| {
+                let bucket = TokenBucket::full(&limit, now);
+                (limit, Mutex::new(bucket))
+            }),
+            buckets: DashMap::new(),
+            last_prune: Mutex::new(now),
+            allowed: AtomicU64::new(0),
+            limited: AtomicU64::new(0),
+        }
+    }
+
+    /// What requests are keyed by
+    pub fn key_by(&self) -> RateLimitKey {
+        self.key_by
+    }
+
+    /// Check whether a request for `key` may proceed
+    pub fn check(&self, key: &str) -> RateDecision {
+        self.check_at(key, Instant::now())
+    }
+
+    fn check_at(&self, key: &str, now: Instant) -> RateDecision {
+        let limit = self.overrides.get(key).or(self.default.as_ref());
+
+        if let Some(limit) = limit {
+            if self.buckets.len() > MAX_TRACKED_KEYS {
+                self.prune(now);
+            }
+
+            let taken = self
+                .buckets
+                .entry(key.to_string())
+                .or_insert_with(|| TokenBucket::full(limit, now))
+                .try_take(limit, now);
+            if let Err(retry_after) = taken {
+                return self.reject(retry_after);
+            }
+        }
+
+        if let Some((ref global_limit, ref bucket)) = self.global {
+            if let Err(retry_after) = bucket.lock().try_take(global_limit, now) {
+                // Don't charge the key for a request that was not served
+                if let (Some(limit), Some(mut bucket)) = (limit, self.buckets.get_mut(key)) {
+                    bucket.refund(limit);
+                }
+                return self.reject(retry_after);
+            }
+        }
+
+        self.allowed.fetch_add(1, Ordering::Relaxed);
+        if let Some(metrics) = get_metrics() {
+            metrics.rate_limit_allowed.add(1, &[]);
+        }
+        RateDecision::Allowed
+    }
+
+    fn reject(&self, retry_after: Duration) -> RateDecision {
+        self.limited.fetch_add(1, Ordering::Relaxed);
+        RateDecision::Limited { retry_after }
+    }
+
+    /// Drop buckets that have refilled completely, they carry no state
+    fn prune(&self, now: Instant) {
+        // Another request pruning right now is as good
+        let Some(mut last_prune) = self.last_prune.try_lock() else {
+            return;
+        };
+        if now.saturating_duration_since(*last_prune) < PRUNE_INTERVAL {
+            return;
+        }
+        *last_prune = now;
+        drop(last_prune);
+
+        self.buckets.retain(|key, bucket| {
+            let limit = self.overrides.get(key).or(self.default.as_ref());
+            match limit {
+                Some(limit) => {
+                    bucket.refill(limit, now);
+                    bucket.tokens < limit.burst_size()
+                }
+                None => false,
+            }
+        });
+    }
+
+    /// Get number of requests allowed
+    pub fn allowed(&self) -> u64 {
+        self.allowed.load(Ordering::Relaxed)
+    }
+
+    /// Get number of requests rejected
+    pub fn limited(&self) -> u64 {
+        self.limited.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::RateLimitOverride;
+
+    fn limit(requests_per_second: f64, burst: u32) -> RateLimit {
+        RateLimit {
+            requests_per_second,
+            burst: Some(burst),
+        }
+    }
+
+    #[test]
+    fn test_per_key_bucket() {
+        let limiter = RateLimiter::new(&RateLimitConfig {
+            per_key: Some(limit(10.0, 2)),
+            ..Default::default()
+        });
+        let now = Instant::now();
+
+        assert_eq!(limiter.check_at("a", now), RateDecision::Allowed);
+        assert_eq!(limiter.check_at("a", now), RateDecision::Allowed);
+        let RateDecision::Limited { retry_after } = limiter.check_at("a", now) else {
+            panic!("third request should be limited");
+        };
+        assert!(retry_after <= Duration::from_millis(100));
+
+        // Other keys have their own bucket
+        assert_eq!(limiter.check_at("b", now), RateDecision::Allowed);
+
+        // One token refills after 100ms
+        let later = now + Duration::from_millis(100);
+        assert_eq!(limiter.check_at("a", later), RateDecision::Allowed);
+        assert_eq!(limiter.allowed(), 4);
+        assert_eq!(limiter.limited(), 1);
+    }
+
+    #[test]
+    fn test_global_limit_and_overrides() {
+        let limiter = RateLimiter::new(&RateLimitConfig {
+            per_key: Some(limit(1.0, 1)),
+            global: Some(limit(1.0, 2)),
+            overrides: vec![RateLimitOverride {
+                key: "batch".to_string(),
+                limit: limit(1.0, 5),
+            }],
+            ..Default::default()
+        });
+        let now = Instant::now();
+
+        assert_eq!(limiter.check_at("batch", now), RateDecision::Allowed);
+        assert_eq!(limiter.check_at("batch", now), RateDecision::Allowed);
+        // Global bucket is empty even though "batch" has tokens left
+        assert!(matches!(
+            limiter.check_at("batch", now),
+            RateDecision::Limited { .. }
+        ));
+        assert!(matches!(
+            limiter.check_at("other", now),
+            RateDecision::Limited { .. }
+        ));
+
+        // "other" was refunded, so it is served once the global bucket refills
+        let later = now + Duration::from_secs(1);
+        assert_eq!(limiter.check_at("other", later), RateDecision::Allowed);
+    }
+
+    #[test]
+    fn test_prune_at_most_once_per_interval() {
+        let limiter = RateLimiter::new(&RateLimitConfig {
+            per_key: Some(limit(10.0, 1)),
+            ..Default::default()
+        });
+        let now = Instant::now() + PRUNE_INTERVAL;
+        for i in 0..=MAX_TRACKED_KEYS {
+            limiter.check_at(&i.to_string(), now);
+        }
+
+        // Every bucket is in use when pruned; they have refilled by `soon`,
+        // but were pruned too recently
+        let soon = now + PRUNE_INTERVAL / 2;
+        limiter.check_at("a", now);
+        limiter.check_at("b", soon);
+        assert_eq!(limiter.buckets.len(), MAX_TRACKED_KEYS + 3);
+
+        let later = now + PRUNE_INTERVAL;
+        limiter.check_at("c", later);
+        assert_eq!(limiter.buckets.len(), 1);
+    }
+}
@@ -0,0 +1,369 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use dashmap::DashMap;
+use pingora::http::{RequestHeader, ResponseHeader};
+use serde_json::Value;
+
+use crate::config::{Profile, ResponseCacheConfig};
+use crate::telemetry::get_metrics;
+
+/// Request bodies are read ahead for the cache key only up to this size,
+/// kept below the 64 KiB Pingora's retry buffer replays to the upstream
+/// afterwards
+pub const MAX_REQUEST_BODY_BYTES: usize = 60 * 1024;
+
+/// Headers of one connection or one client, never replayed to another
+const UNSHARED_HEADERS: &[&str] = &[
+    "Connection",
+    "Keep-Alive",
+    "Proxy-Authenticate",
+    "Proxy-Connection",
+    "TE",
+    "Trailer",
+    "Transfer-Encoding",
+    "Upgrade",
+    "Set-Cookie",
+];
+
+/// A complete upstream response that can be replayed to clients
+pub struct CachedResponse {
+    pub header: ResponseHeader,
+    pub body: Bytes,
+}
+
+impl CachedResponse {
+    /// Build from an upstream response header and its complete body
+    ///
+    /// Hop-by-hop headers, including those named by `Connection`, and
+    /// cookies are dropped; the body is replayed in one piece.
+    pub fn new(mut header: ResponseHeader, body: Bytes) -> Self {
+        let listed: Vec<String> = header
+            .headers
+            .get_all("Connection")
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+            .map(|name| name.trim().to_string())
+            .filter(|name| !name.is_empty())
+            .collect();
+        for name in UNSHARED_HEADERS
+            .iter()
+            .copied()
+            .chain(listed.iter().map(String::as_str))
+        {
+            header.remove_header(name);
+        }
+        let _ = header.insert_header("Content-Length", body.len().to_string());
+        Self { header, body }
+    }
+}
+
+struct Entry {
+    response: Arc<CachedResponse>,
+    expires: Instant,
+}
+
+/// In-memory cache of responses, keyed by [`cache_key`]
+pub struct ResponseCache {
+    ttl: Duration,
+    max_entries: usize,
+    max_entry_bytes: usize,
+    /// Permission profiles whose requests are cached
+    profiles: Vec<Profile>,
+    bypass_header: String,
+    entries: DashMap<String, Entry>,
+    /// Number of requests served from the cache
+    hits: AtomicU64,
+    /// Number of cacheable requests sent upstream
+    misses: AtomicU64,
+}
+
+impl ResponseCache {
+    pub fn new(config: &ResponseCacheConfig) -> Self {
+        Self {
+            ttl: Duration::from_secs(config.ttl_seconds),
+            max_entries: config.max_entries,
+            max_entry_bytes: config.max_entry_bytes,
+            profiles: config.profiles.clone(),
+            bypass_header: config.bypass_header.clone(),
+            entries: DashMap::new(),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /// Whether a request of this profile may be served from the cache
+    pub fn applies_to(&self, profile: Profile, req: &RequestHeader) -> bool {
+        self.profiles.contains(&profile)
+            && matches!(req.method.as_str(), "GET" | "POST")
+            && !req.headers.contains_key(self.bypass_header.as_str())
+    }
+
+    /// Larger responses are not cached
+    pub fn max_entry_bytes(&self) -> usize {
+        self.max_entry_bytes
+    }
+
+    /// Look up a fresh response, counting a hit or a miss
+    pub fn get(&self, key: &str) -> Option<Arc<CachedResponse>> {
+        self.get_at(key, Instant::now())
+    }
+
+    fn get_at(&self, key: &str, now: Instant) -> Option<Arc<CachedResponse>> {
+        let found = self
+            .entries
+            .get(key)
+            .filter(|entry| entry.expires > now)
+            .map(|entry| entry.response.clone());
+
+        let metrics = get_metrics();
+        if found.is_some() {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            if let Some(metrics) = metrics {
+                metrics.cache_hits.add(1, &[]);
+            }
+        } else {
+            self.misses.fetch_add(1, Ordering::Relaxed);
+            if let Some(metrics) = metrics {
+                metrics.cache_misses.add(1, &[]);
+            }
+        }
+        found
+    }
+
+    /// Cache a response for the TTL
+    pub fn insert(&self, key: String, response: Arc<CachedResponse>) {
+        self.insert_at(key, response, Instant::now());
+    }
+
+    fn insert_at(&self, key: String, response: Arc<CachedResponse>, now: Instant) {
+        if self.entries.len() >= self.max_entries && !self.entries.contains_key(&key) {
+            self.entries.retain(|_, entry| entry.expires > now);
+
+            // Still full: make room by dropping the entry closest to expiry
+            if self.entries.len() >= self.max_entries {
+                let oldest = self
+                    .entries
+                    .iter()
+                    .min_by_key(|entry| entry.expires)
+                    .map(|entry| entry.key().clone());
+                if let Some(oldest) = oldest {
+                    self.entries.remove(&oldest);
+                }
+            }
+        }
+
+        self.entries.insert(
+            key,
+            Entry {
+                response,
+                expires: now + self.ttl,
+            },
+        );
+
+        if let Some(metrics) = get_metrics() {
+            metrics.cache_entries.record(self.entries.len() as u64, &[]);
+        }
+    }
+
+    /// Get number of cached responses, including expired ones not yet evicted
+    pub fn entries(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Get number of requests served from the cache
+    pub fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    /// Get number of cacheable requests that had to go upstream
+    pub fn misses(&self) -> u64 {
+        self.misses.load(Ordering::Relaxed)
+    }
+}
+
+/// Key identifying requests that get the same response: the permission
+/// profile, the route and the request body with the client name removed and
+/// whitespace in scripts normalized
+///
+/// The session stays in the key, as a script may read variables of its
+/// session that other clients must not see.
+pub fn cache_key(profile: Profile, method: &str, path: &str, body: &[u8]) -> String {
+    let body = match serde_json::from_slice::<Value>(body) {
+        Ok(Value::Object(mut payload)) => {
+            payload.remove("client");
+            if let Some(Value::String(script)) = payload.get_mut("script") {
+                *script = normalize_script(script);
+            }
+            if let Some(Value::Array(params)) = payload.get_mut("params") {
+                for param in params {
+                    if param.get("name").and_then(Value::as_str) != Some("script") {
+                        continue;
+                    }
+                    if let Some(Value::String(script)) = param.get_mut("value") {
+                        *script = normalize_script(script);
+                    }
+                }
+            }
+            Value::Object(payload).to_string()
+        }
+        _ => normalize_script(&String::from_utf8_lossy(body)),
+    };
+
+    format!("{}|{} {}|{}", profile, method, path, body)
+}
+
+/// Collapse whitespace outside string literals into single spaces
+fn normalize_script(script: &str) -> String {
+    let mut normalized = String::with_capacity(script.len());
+    let mut quote = None;
+    let mut escaped = false;
+    let mut space = false;
+
+    for c in script.trim().chars() {
+        match quote {
+            Some(q) => {
+                normalized.push(c);
+                if escaped {
+                    escaped = false;
+                } else if c == '\\' {
+                    escaped = true;
+                } else if c == q {
+                    quote = None;
+                }
+            }
+            None if c.is_whitespace() => space = true,
+            None => {
+                if space {
+                    normalized.push(' ');
+                    space = false;
+                }
+                if c == '"' || c == '\'' {
+                    quote = Some(c);
+                }
+                normalized.push(c);
+            }
+        }
+    }
+
+    normalized
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn response(body: &'static str) -> Arc<CachedResponse> {
+        Arc::new(CachedResponse::new(
+            ResponseHeader::build(200, None).unwrap(),
+            Bytes::from(body),
+        ))
+    }
+
+    #[test]
+    fn test_cache_key_normalizes_scripts() {
+        let key = |body: &str| {
+            cache_key(
+                Profile::ReadOnly,
+                "POST",
+                "/api/executeCode",
+                body.as_bytes(),
+            )
+        };
+
+        assert_eq!(
+            key(r#"{"sessionID":"1","params":[{"name":"script","value":"select *\n  from t"}]}"#),
+            key(r#"{"sessionID":"1","params":[{"name":"script","value":" select * from t "}]}"#)
+        );
+        assert_ne!(
+            key(r#"{"script":"x = \"a  b\""}"#),
+            key(r#"{"script":"x = \"a b\""}"#)
+        );
+        assert_ne!(
+            key("select 1"),
+            cache_key(Profile::Admin, "POST", "/api/executeCode", b"select 1")
+        );
+    }
+
+    #[test]
+    fn test_cache_key_keeps_session() {
+        let key = |body: &str| {
+            cache_key(
+                Profile::ReadOnly,
+                "POST",
+                "/api/executeCode",
+                body.as_bytes(),
+            )
+        };
+
+        // The same script may read different session variables
+        assert_ne!(
+            key(r#"{"sessionID":"1","script":"select * from t"}"#),
+            key(r#"{"sessionID":"2","script":"select * from t"}"#)
+        );
+        assert_eq!(
+            key(r#"{"sessionID":"1","client":"a","script":"select * from t"}"#),
+            key(r#"{"sessionID":"1","client":"b","script":"select * from t"}"#)
+        );
+    }
+
+    #[test]
+    fn test_ttl_and_eviction() {
+        let cache = ResponseCache::new(&ResponseCacheConfig {
+            enabled: true,
+            ttl_seconds: 5,
+            max_entries: 2,
+            ..Default::default()
+        });
+        let now = Instant::now();
+
+        assert!(cache.get_at("a", now).is_none());
+        cache.insert_at("a".to_string(), response("1"), now);
+        cache.insert_at("b".to_string(), response("2"), now + Duration::from_secs(1));
+        assert_eq!(cache.get_at("a", now).unwrap().body, "1");
+        assert!(cache.get_at("a", now + Duration::from_secs(5)).is_none());
+
+        // Full: the entry closest to expiry makes room
+        cache.insert_at("c".to_string(), response("3"), now + Duration::from_secs(2));
+        assert_eq!(cache.entries(), 2);
+        assert!(cache.get_at("a", now).is_none());
+        assert!(cache.get_at("b", now).is_some());
+
+        assert_eq!(cache.hits(), 2);
+        assert_eq!(cache.misses(), 3);
+    }
+
+    #[test]
+    fn test_unshared_headers_dropped() {
+        let mut header = ResponseHeader::build(200, None).unwrap();
+        for (name, value) in [
+            ("Content-Type", "application/json"),
+            ("Set-Cookie", "session=abc"),
+            ("Connection", "keep-alive, X-Trace"),
+            ("Keep-Alive", "timeout=5"),
+            ("X-Trace", "1"),
+            ("Transfer-Encoding", "chunked"),
+        ] {
+            header.insert_header(name, value).unwrap();
+        }
+
+        let cached = CachedResponse::new(header, Bytes::from("{}"));
+        let names: Vec<&str> = cached.header.headers.keys().map(|k| k.as_str()).collect();
+        assert_eq!(names, ["content-type", "content-length"]);
+        assert_eq!(cached.header.headers["Content-Length"], "2");
+    }
+
+    #[test]
+    fn test_applies_to_profiles_and_bypass() {
+        let cache = ResponseCache::new(&ResponseCacheConfig::default());
+        let mut req = RequestHeader::build("POST", b"/api/executeCode", None).unwrap();
+
+        assert!(cache.applies_to(Profile::ReadOnly, &req));
+        assert!(!cache.applies_to(Profile::ReadWrite, &req));
+        req.insert_header("X-TPP-Cache-Bypass", "1").unwrap();
+        assert!(!cache.applies_to(Profile::ReadOnly, &req));
+    }
+}
@@ -0,0 +1,195 @@
+use std::fmt;
+
+use pingora::http::ResponseHeader;
+use serde::de::{self, Deserializer, IgnoredAny, MapAccess, Visitor};
+
+use crate::config::ResponseInspectionConfig;
+use crate::token_acquirer::ApiStatus;
+
+/// Application-level error reported in a successful HTTP response
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AppError {
+    pub code: String,
+    pub message: String,
+    /// The message points at the token's session (expired, logged out, ...)
+    pub session_error: bool,
+}
+
+/// Finds DolphinDB errors hidden in HTTP 200 responses
+pub struct ResponseInspector {
+    max_body_bytes: usize,
+    /// Lowercased message fragments indicating a session problem
+    session_error_patterns: Vec<String>,
+}
+
+impl ResponseInspector {
+    pub fn new(config: &ResponseInspectionConfig) -> Self {
+        Self {
+            max_body_bytes: config.max_body_bytes,
+            session_error_patterns: config
+                .session_error_patterns
+                .iter()
+                .map(|p| p.to_ascii_lowercase())
+                .collect(),
+        }
+    }
+
+    /// Only this many bytes at the start of a body are inspected
+    pub fn max_body_bytes(&self) -> usize {
+        self.max_body_bytes
+    }
+
+    /// Whether the body of this response should be buffered for inspection
+    ///
+    /// Only uncompressed JSON success responses are inspected; error statuses
+    /// are already counted from the status code.
+    pub fn wants(&self, resp: &ResponseHeader) -> bool {
+        let header = |name: &str| {
+            resp.headers
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .unwrap_or_default()
+        };
+        resp.status.is_success()
+            && header("Content-Type").contains("json")
+            && header("Content-Encoding").is_empty()
+    }
+
+    /// Parse the start of a response body, returning the error it reports
+    ///
+    /// DolphinDB writes `code` and `message` before the result, so a body cut
+    /// off after them still tells whether the call failed.
+    pub fn inspect(&self, body: &[u8]) -> Option<AppError> {
+        let mut status = ApiStatus::default();
+        // Parsing ends in an error once the status is read or the body is
+        // cut off; the fields read until then are kept
+        let _ =
+            serde_json::Deserializer::from_slice(body).deserialize_map(StatusVisitor(&mut status));
+        if status.code.is_none() || status.is_success() {
+            return None;
+        }
+
+        let message = status.message.clone().unwrap_or_default();
+        let lowered = message.to_ascii_lowercase();
+        let session_error = self
+            .session_error_patterns
+            .iter()
+            .any(|p| lowered.contains(p.as_str()));
+
+        Some(AppError {
+            code: status.code_str(),
+            message,
+            session_error,
+        })
+    }
+}
+
+/// Reads `code` and `message` from a JSON object, stopping once both are
+/// found so the rest of the object is never parsed
+struct StatusVisitor<'a>(&'a mut ApiStatus);
+
+impl<'de> Visitor<'de> for StatusVisitor<'_> {
+    type Value = ();
+
+    fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("a DolphinDB response object")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<(), A::Error> {
+        while let Some(key) = map.next_key::<String>()? {
+            match key.as_str() {
+                "code" => self.0.code = map.next_value()?,
+                "message" => self.0.message = map.next_value()?,
+                _ => {
+                    map.next_value::<IgnoredAny>()?;
+                }
+            }
+            if self.0.code.is_some() && self.0.message.is_some() {
+                return Err(de::Error::custom("status read"));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_inspect_codes_and_session_errors() {
+        let inspector = ResponseInspector::new(&ResponseInspectionConfig::default());
+
+        assert_eq!(inspector.inspect(br#"{"code":"0","message":""}"#), None);
+        assert_eq!(inspector.inspect(br#"{"code":0,"result":[1]}"#), None);
+        assert_eq!(inspector.inspect(br#"[1, 2, 3]"#), None);
+        assert_eq!(inspector.inspect(b"not json"), None);
+
+        let err = inspector
+            .inspect(br#"{"code":"1","message":"Syntax Error: [line #1] ..."}"#)
+            .unwrap();
+        assert_eq!(err.code, "1");
+        assert!(!err.session_error);
+
+        // Only session messages, not every mention of a token or login
+        let err = inspector
+            .inspect(br#"{"code":"1","message":"Cannot recognize the token login"}"#)
+            .unwrap();
+        assert!(!err.session_error);
+
+        let err = inspector
+            .inspect(br#"{"code":1,"message":"The Session is expired"}"#)
+            .unwrap();
+        assert!(err.session_error);
+    }
+
+    #[test]
+    fn test_inspect_cut_off_body() {
+        let inspector = ResponseInspector::new(&ResponseInspectionConfig::default());
+
+        let err = inspector
+            .inspect(br#"{"code":"1","message":"Out of memory","result":[1, 2, 3"#)
+            .unwrap();
+        assert_eq!(err.message, "Out of memory");
+        assert_eq!(
+            inspector.inspect(br#"{"code":"0","message":"","result":[1, 2, 3"#),
+            None
+        );
+        // The status must come before the cut
+        assert_eq!(inspector.inspect(br#"{"result":[1, 2, 3"#), None);
+    }
+
+    #[test]
+    fn test_wants_small_json_success() {
+        let inspector = ResponseInspector::new(&ResponseInspectionConfig {
+            max_body_bytes: 100,
+            ..Default::default()
+        });
+        let resp = |status: u16, headers: &[(&'static str, &'static str)]| {
+            let mut resp = ResponseHeader::build(status, None).unwrap();
+            for (name, value) in headers {
+                resp.insert_header(*name, *value).unwrap();
+            }
+            resp
+        };
+
+        assert!(inspector.wants(&resp(200, &[("Content-Type", "application/json")])));
+        assert!(!inspector.wants(&resp(200, &[("Content-Type", "text/csv")])));
+        assert!(!inspector.wants(&resp(500, &[("Content-Type", "application/json")])));
+        // Large bodies are inspected up to the limit
+        assert!(inspector.wants(&resp(
+            200,
+            &[
+                ("Content-Type", "application/json"),
+                ("Content-Length", "101")
+            ]
+        )));
+        assert!(!inspector.wants(&resp(
+            200,
+            &[
+                ("Content-Type", "application/json"),
+                ("Content-Encoding", "gzip")
+            ]
+        )));
+    }
+}
@@ -0,0 +1,130 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+
+use crate::config::RetryConfig;
+use crate::telemetry::get_metrics;
+
+struct BudgetWindow {
+    start: Instant,
+    requests: u64,
+    retries: u64,
+}
+
+/// Limits retries to a share of recent requests so they cannot amplify an outage
+///
+/// Within each window, `min_retries + ratio * requests` retries are allowed.
+pub struct RetryBudget {
+    ratio: f64,
+    min_retries: u64,
+    window_length: Duration,
+    window: Mutex<BudgetWindow>,
+    /// Number of retries performed
+    retries: AtomicU64,
+    /// Number of retries refused because the budget was spent
+    exhausted: AtomicU64,
+}
+
+impl RetryBudget {
+    pub fn new(config: &RetryConfig) -> Self {
+        Self {
+            ratio: config.budget_ratio,
+            min_retries: config.budget_min_retries,
+            window_length: Duration::from_secs(config.budget_window_seconds),
+            window: Mutex::new(BudgetWindow {
+                start: Instant::now(),
+                requests: 0,
+                retries: 0,
+            }),
+            retries: AtomicU64::new(0),
+            exhausted: AtomicU64::new(0),
+        }
+    }
+
+    /// Count a request towards the budget
+    pub fn record_request(&self) {
+        self.record_request_at(Instant::now());
+    }
+
+    fn record_request_at(&self, now: Instant) {
+        let mut window = self.window.lock();
+        self.roll(&mut window, now);
+        window.requests += 1;
+    }
+
+    /// Withdraw one retry from the budget, returning whether it may proceed
+    pub fn try_retry(&self) -> bool {
+        self.try_retry_at(Instant::now())
+    }
+
+    fn try_retry_at(&self, now: Instant) -> bool {
+        let mut window = self.window.lock();
+        self.roll(&mut window, now);
+
+        let allowed = self.min_retries as f64 + self.ratio * window.requests as f64;
+        if (window.retries as f64) < allowed {
+            window.retries += 1;
+            self.retries.fetch_add(1, Ordering::Relaxed);
+            true
+        } else {
+            self.exhausted.fetch_add(1, Ordering::Relaxed);
+            if let Some(metrics) = get_metrics() {
+                metrics.retry_budget_exhausted.add(1, &[]);
+            }
+            false
+        }
+    }
+
+    fn roll(&self, window: &mut BudgetWindow, now: Instant) {
+        if now.saturating_duration_since(window.start) >= self.window_length {
+            window.start = now;
+            window.requests = 0;
+            window.retries = 0;
+        }
+    }
+
+    /// Get number of retries performed
+    pub fn retries(&self) -> u64 {
+        self.retries.load(Ordering::Relaxed)
+    }
+
+    /// Get number of retries refused by the budget
+    pub fn exhausted(&self) -> u64 {
+        self.exhausted.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_budget_scales_with_requests() {
+        let budget = RetryBudget::new(&RetryConfig {
+            max_retries: 1,
+            budget_ratio: 0.5,
+            budget_min_retries: 1,
+            budget_window_seconds: 10,
+            ..Default::default()
+        });
+        let now = Instant::now();
+
+        // Only the minimum is available without traffic
+        assert!(budget.try_retry_at(now));
+        assert!(!budget.try_retry_at(now));
+
+        // Four requests earn two more retries
+        for _ in 0..4 {
+            budget.record_request_at(now);
+        }
+        assert!(budget.try_retry_at(now));
+        assert!(budget.try_retry_at(now));
+        assert!(!budget.try_retry_at(now));
+        assert_eq!(budget.retries(), 3);
+        assert_eq!(budget.exhausted(), 2);
+
+        // A new window starts over
+        assert!(budget.try_retry_at(now + Duration::from_secs(10)));
+    }
+}
@@ -0,0 +1,313 @@
+use std::collections::{HashMap, HashSet};
+
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::client::ClientId;
+use crate::config::ScriptPolicyConfig;
+
+/// Result of checking a request against the script policy
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PolicyDecision {
+    Allow,
+    Deny { reason: String },
+}
+
+impl PolicyDecision {
+    fn deny(reason: impl Into<String>) -> Self {
+        PolicyDecision::Deny {
+            reason: reason.into(),
+        }
+    }
+}
+
+/// DolphinDB REST request payload
+/// See: https://docs.dolphindb.com/en/API/rest_api.html
+#[derive(Debug, Deserialize)]
+struct RestPayload {
+    /// Function called by the request
+    #[serde(rename = "functionName")]
+    function_name: Option<String>,
+    #[serde(default)]
+    params: Vec<RestParam>,
+    /// Script run by the request
+    script: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct RestParam {
+    name: Option<String>,
+    value: Option<Value>,
+}
+
+/// Per-client rules
+struct ClientRules {
+    /// Lowercased functions the client may call (any if unset)
+    allow: Option<HashSet<String>>,
+    max_script_length: Option<usize>,
+}
+
+/// Allows or denies DolphinDB requests based on the functions they use
+pub struct ScriptPolicy {
+    /// Lowercased function names that may not appear in a request
+    deny: HashSet<String>,
+    max_script_length: Option<usize>,
+    max_body_bytes: usize,
+    overrides: HashMap<String, ClientRules>,
+}
+
+impl ScriptPolicy {
+    pub fn new(config: &ScriptPolicyConfig) -> Self {
+        let lowercase = |names: &[String]| -> HashSet<String> {
+            names.iter().map(|n| n.to_ascii_lowercase()).collect()
+        };
+
+        Self {
+            deny: lowercase(&config.deny_functions),
+            max_script_length: config.max_script_length,
+            max_body_bytes: config.max_body_bytes,
+            overrides: config
+                .overrides
+                .iter()
+                .map(|o| {
+                    let rules = ClientRules {
+                        allow: o.allow_functions.as_deref().map(lowercase),
+                        max_script_length: o.max_script_length,
+                    };
+                    (o.client.clone(), rules)
+                })
+                .collect(),
+        }
+    }
+
+    /// Request bodies larger than this are denied without being parsed
+    pub fn max_body_bytes(&self) -> usize {
+        self.max_body_bytes
+    }
+
+    /// Check a complete request body sent by a client to `path`
+    pub fn check(&self, client: &ClientId, path: &str, body: &[u8]) -> PolicyDecision {
+        if body.len() > self.max_body_bytes {
+            return PolicyDecision::deny(format!(
+                "request body exceeds {} bytes",
+                self.max_body_bytes
+            ));
+        }
+
+        let payload: RestPayload = match serde_json::from_slice(body) {
+            Ok(payload) => payload,
+            Err(e) => return PolicyDecision::deny(format!("unparseable request body: {}", e)),
+        };
+
+        let rules = self.overrides.get(client.as_str());
+        let max_script_length = rules
+            .and_then(|r| r.max_script_length)
+            .or(self.max_script_length);
+
+        // The endpoint and `functionName` are calls like those in the script
+        let mut calls: Vec<String> = path
+            .rsplit('/')
+            .next()
+            .filter(|name| !name.is_empty())
+            .into_iter()
+            .chain(payload.function_name.as_deref())
+            .map(String::from)
+            .collect();
+        let mut identifiers = calls.clone();
+
+        let scripts = payload.script.as_deref().into_iter().chain(
+            payload
+                .params
+                .iter()
+                .filter(|p| p.name.as_deref() == Some("script"))
+                .filter_map(|p| p.value.as_ref()?.as_str()),
+        );
+        for script in scripts {
+            if let Some(max) = max_script_length {
+                if script.len() > max {
+                    return PolicyDecision::deny(format!("script exceeds {} bytes", max));
+                }
+            }
+            for (name, is_call) in scan_identifiers(script) {
+                if is_call {
+                    calls.push(name.to_string());
+                }
+                identifiers.push(name.to_string());
+            }
+        }
+
+        if let Some(denied) = identifiers
+            .iter()
+            .find(|name| self.deny.contains(&name.to_ascii_lowercase()))
+        {
+            return PolicyDecision::deny(format!("function '{}' is not allowed", denied));
+        }
+
+        if let Some(allow) = rules.and_then(|r| r.allow.as_ref()) {
+            if let Some(call) = calls
+                .iter()
+                .find(|name| !allow.contains(&name.to_ascii_lowercase()))
+            {
+                return PolicyDecision::deny(format!(
+                    "function '{}' is not allowed for client '{}'",
+                    call, client
+                ));
+            }
+        }
+
+        PolicyDecision::Allow
+    }
+}
+
+/// Identifiers in a DolphinDB script outside strings, symbols and comments,
+/// with whether each is called (followed by `(`)
+///
+/// Names built at runtime from strings are not found, which is why functions
+/// evaluating strings as code are denied by default.
+fn scan_identifiers(script: &str) -> Vec<(&str, bool)> {
+    let bytes = script.as_bytes();
+    let mut found = Vec::new();
+    let mut i = 0;
+
+    while i < bytes.len() {
+        match bytes[i] {
+            quote @ (b'"' | b'\'') => {
+                i += 1;
+                while i < bytes.len() && bytes[i] != quote {
+                    i += if bytes[i] == b'\\' { 2 } else { 1 };
+                }
+                i += 1;
+            }
+            b'/' if bytes.get(i + 1) == Some(&b'/') => {
+                while i < bytes.len() && bytes[i] != b'\n' {
+                    i += 1;
+                }
+            }
+            b'/' if bytes.get(i + 1) == Some(&b'*') => {
+                i += 2;
+                while i < bytes.len() && !bytes[i..].starts_with(b"*/") {
+                    i += 1;
+                }
+                i += 2;
+            }
+            // Symbol literal such as `sym
+            b'`' => {
+                i += 1;
+                while i < bytes.len() && (bytes[i].is_ascii_alphanumeric() || bytes[i] == b'_') {
+                    i += 1;
+                }
+            }
+            b if b.is_ascii_alphabetic() || b == b'_' => {
+                let start = i;
+                while i < bytes.len() && (bytes[i].is_ascii_alphanumeric() || bytes[i] == b'_') {
+                    i += 1;
+                }
+                // In-place functions such as append! (but not a != b)
+                if bytes.get(i) == Some(&b'!') && bytes.get(i + 1) != Some(&b'=') {
+                    i += 1;
+                }
+                let is_call = script[i..].trim_start().starts_with('(');
+                found.push((&script[start..i], is_call));
+            }
+            _ => i += 1,
+        }
+    }
+
+    found
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ScriptPolicyOverride;
+
+    fn execute_code(script: &str) -> Vec<u8> {
+        serde_json::json!({
+            "sessionID": "0",
+            "functionName": "executeCode",
+            "params": [{"name": "script", "form": "scalar", "type": "string", "value": script}],
+        })
+        .to_string()
+        .into_bytes()
+    }
+
+    #[test]
+    fn test_deny_functions_outside_strings() {
+        let policy = ScriptPolicy::new(&ScriptPolicyConfig {
+            enabled: true,
+            max_script_length: Some(100),
+            ..Default::default()
+        });
+        let client = ClientId::anonymous();
+        let check = |body: &[u8]| policy.check(&client, "/api/executeCode", body);
+
+        assert_eq!(
+            check(&execute_code("select * from t")),
+            PolicyDecision::Allow
+        );
+        assert_eq!(
+            check(&execute_code(r#"print("dropDatabase") // shell()"#)),
+            PolicyDecision::Allow
+        );
+        assert_eq!(
+            check(&execute_code(r#"DropDatabase("dfs://db")"#)),
+            PolicyDecision::deny("function 'DropDatabase' is not allowed")
+        );
+        // Passing a denied function around is as bad as calling it
+        assert!(matches!(
+            check(&execute_code("f = shell; f(`ls)")),
+            PolicyDecision::Deny { .. }
+        ));
+        // A function looked up by name is not seen, so the lookup is denied
+        assert_eq!(
+            check(&execute_code(r#"funcByName("dropDatabase")("dfs://db")"#)),
+            PolicyDecision::deny("function 'funcByName' is not allowed")
+        );
+        assert_eq!(
+            check(&execute_code(&"x".repeat(101))),
+            PolicyDecision::deny("script exceeds 100 bytes")
+        );
+        assert!(matches!(check(b"not json"), PolicyDecision::Deny { .. }));
+        assert!(matches!(
+            policy.check(&client, "/api/login", br#"{"username":"a","password":"b"}"#),
+            PolicyDecision::Deny { .. }
+        ));
+    }
+
+    #[test]
+    fn test_client_allow_list() {
+        let policy = ScriptPolicy::new(&ScriptPolicyConfig {
+            enabled: true,
+            overrides: vec![ScriptPolicyOverride {
+                client: "reports".to_string(),
+                allow_functions: Some(vec![
+                    "executeCode".to_string(),
+                    "loadTable".to_string(),
+                    "select".to_string(),
+                ]),
+                max_script_length: None,
+            }],
+            ..Default::default()
+        });
+        let reports = ClientId::new("reports");
+        let other = ClientId::new("other");
+        let body = execute_code(r#"t = loadTable("dfs://db", `t); append!(t, x)"#);
+
+        assert_eq!(
+            policy.check(&reports, "/api/executeCode", &body),
+            PolicyDecision::deny("function 'append!' is not allowed for client 'reports'")
+        );
+        assert_eq!(
+            policy.check(&other, "/api/executeCode", &body),
+            PolicyDecision::Allow
+        );
+        assert_eq!(
+            policy.check(
+                &reports,
+                "/api/executeCode",
+                &execute_code(r#"loadTable("dfs://db", `t)"#)
+            ),
+            PolicyDecision::Allow
+        );
+    }
+}
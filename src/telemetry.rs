@@ -1,22 +1,61 @@
 use opentelemetry::global;
 use opentelemetry::metrics::{Counter, Gauge, Histogram, Meter, MeterProvider};
+use opentelemetry::propagation::Extractor;
 use opentelemetry::trace::TracerProvider as _;
-use opentelemetry::KeyValue;
-use opentelemetry_otlp::WithExportConfig;
+use opentelemetry::{ContextGuard, KeyValue};
+use opentelemetry_appender_tracing::layer::OpenTelemetryTracingBridge;
+use opentelemetry_otlp::{LogExporter, MetricExporter, Protocol, SpanExporter, WithExportConfig};
+use opentelemetry_sdk::logs::LoggerProvider;
 use opentelemetry_sdk::metrics::{PeriodicReader, SdkMeterProvider};
-use opentelemetry_sdk::trace::TracerProvider;
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use opentelemetry_sdk::trace::{Sampler, TracerProvider};
 use opentelemetry_sdk::{runtime, Resource};
+use parking_lot::Mutex;
+use pingora::http::HMap;
+use prometheus::{Encoder, TextEncoder};
+use std::cell::RefCell;
+use std::collections::HashMap;
 use std::sync::OnceLock;
-use std::time::Duration;
-use tracing::info;
-use tracing_subscriber::layer::SubscriberExt;
+use std::time::{Duration, Instant};
+use tokio::signal::unix::{signal, SignalKind};
+use tracing::span::Id;
+use tracing::{info, warn, Span, Subscriber};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::filter::filter_fn;
+use tracing_subscriber::layer::{self, SubscriberExt};
+use tracing_subscriber::registry::LookupSpan;
 use tracing_subscriber::util::SubscriberInitExt;
-use tracing_subscriber::EnvFilter;
+use tracing_subscriber::{reload, EnvFilter, Layer, Registry};
+
+use crate::config::{LogFormat, OtlpProtocol};
 
 const SERVICE_NAME: &str = "tpp";
 
+/// How long an OTLP export may take
+const EXPORT_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Targets of the exporters' own dependencies, kept out of the OTLP logs so
+/// exporting logs cannot produce more logs to export
+const OTLP_LOGS_EXCLUDED_TARGETS: [&str; 5] = ["opentelemetry", "tonic", "h2", "hyper", "reqwest"];
+
 static METRICS: OnceLock<PoolMetrics> = OnceLock::new();
 static OTEL_RUNTIME: OnceLock<tokio::runtime::Runtime> = OnceLock::new();
+static PROMETHEUS: OnceLock<prometheus::Registry> = OnceLock::new();
+static LOG_FILTER: OnceLock<reload::Handle<EnvFilter, Registry>> = OnceLock::new();
+static LOG_FILTER_STATE: Mutex<LogFilterState> = Mutex::new(LogFilterState {
+    base: String::new(),
+    revert_at: None,
+    generation: 0,
+});
+static LOGGER_PROVIDER: OnceLock<LoggerProvider> = OnceLock::new();
+
+/// Histogram boundaries in seconds for waits and request latencies
+const LATENCY_BUCKETS: [f64; 14] = [
+    0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0,
+];
+
+/// Histogram boundaries in seconds for how long connections hold a token
+const CONNECTION_BUCKETS: [f64; 10] = [0.1, 0.5, 1.0, 5.0, 10.0, 30.0, 60.0, 300.0, 900.0, 3600.0];
 
 /// Metrics for token pool proxy
 pub struct PoolMetrics {
@@ -25,6 +64,10 @@ pub struct PoolMetrics {
     pub tokens_in_use: Gauge<u64>,
     pub tokens_available: Gauge<u64>,
     pub requests_waiting: Gauge<u64>,
+    pub requests_waiting_by_priority: Gauge<u64>,
+    pub cache_entries: Gauge<u64>,
+    pub coalescing_in_flight: Gauge<u64>,
+    pub circuit_breaker_state: Gauge<u64>,
 
     // Operation counters
     pub token_acquisitions: Counter<u64>,
@@ -34,10 +77,24 @@ pub struct PoolMetrics {
     // Latency histograms
     pub acquisition_wait_seconds: Histogram<f64>,
     pub connection_duration_seconds: Histogram<f64>,
+    pub request_duration_seconds: Histogram<f64>,
 
     // HTTP metrics
     pub requests_total: Counter<u64>,
     pub requests_errors: Counter<u64>,
+    pub requests_rate_limited: Counter<u64>,
+    pub rate_limit_allowed: Counter<u64>,
+    pub requests_circuit_rejected: Counter<u64>,
+    pub upstream_retries: Counter<u64>,
+    pub retry_budget_exhausted: Counter<u64>,
+    pub upstream_responses: Counter<u64>,
+    pub upstream_app_errors: Counter<u64>,
+    pub cache_hits: Counter<u64>,
+    pub cache_misses: Counter<u64>,
+    pub requests_coalesced: Counter<u64>,
+
+    // Refresh metrics
+    pub token_refreshes: Counter<u64>,
 }
 
 impl PoolMetrics {
@@ -59,6 +116,24 @@ impl PoolMetrics {
                 .u64_gauge("tpp_requests_waiting")
                 .with_description("Number of requests waiting for a token")
                 .build(),
+            requests_waiting_by_priority: meter
+                .u64_gauge("tpp_requests_waiting_by_priority")
+                .with_description("Number of requests waiting for a token per priority class")
+                .build(),
+            cache_entries: meter
+                .u64_gauge("tpp_cache_entries")
+                .with_description("Number of responses held in the cache")
+                .build(),
+            coalescing_in_flight: meter
+                .u64_gauge("tpp_coalescing_in_flight")
+                .with_description(
+                    "Number of requests in flight that identical requests can wait on",
+                )
+                .build(),
+            circuit_breaker_state: meter
+                .u64_gauge("tpp_circuit_breaker_state")
+                .with_description("Upstream circuit state (1 for the current state)")
+                .build(),
             token_acquisitions: meter
                 .u64_counter("tpp_token_acquisitions_total")
                 .with_description("Total number of token acquisitions")
@@ -74,10 +149,17 @@ impl PoolMetrics {
             acquisition_wait_seconds: meter
                 .f64_histogram("tpp_acquisition_wait_seconds")
                 .with_description("Time spent waiting to acquire a token")
+                .with_boundaries(LATENCY_BUCKETS.to_vec())
                 .build(),
             connection_duration_seconds: meter
                 .f64_histogram("tpp_connection_duration_seconds")
                 .with_description("Connection duration in seconds")
+                .with_boundaries(CONNECTION_BUCKETS.to_vec())
+                .build(),
+            request_duration_seconds: meter
+                .f64_histogram("tpp_request_duration_seconds")
+                .with_description("Time from receiving a request to finishing its response")
+                .with_boundaries(LATENCY_BUCKETS.to_vec())
                 .build(),
             requests_total: meter
                 .u64_counter("tpp_requests_total")
@@ -87,24 +169,299 @@ impl PoolMetrics {
                 .u64_counter("tpp_requests_errors_total")
                 .with_description("Total number of failed HTTP requests")
                 .build(),
+            requests_rate_limited: meter
+                .u64_counter("tpp_requests_rate_limited_total")
+                .with_description("Total number of requests rejected by the rate limiter")
+                .build(),
+            rate_limit_allowed: meter
+                .u64_counter("tpp_rate_limit_allowed_total")
+                .with_description("Total number of requests allowed by the rate limiter")
+                .build(),
+            upstream_retries: meter
+                .u64_counter("tpp_upstream_retries_total")
+                .with_description("Total number of retried upstream connections")
+                .build(),
+            retry_budget_exhausted: meter
+                .u64_counter("tpp_retry_budget_exhausted_total")
+                .with_description(
+                    "Total number of retries refused because the retry budget was spent",
+                )
+                .build(),
+            upstream_responses: meter
+                .u64_counter("tpp_upstream_responses_total")
+                .with_description("Total number of responses received from each upstream node")
+                .build(),
+            upstream_app_errors: meter
+                .u64_counter("tpp_upstream_app_errors_total")
+                .with_description(
+                    "Total number of DolphinDB errors reported in successful responses",
+                )
+                .build(),
+            cache_hits: meter
+                .u64_counter("tpp_cache_hits_total")
+                .with_description("Total number of requests served from the response cache")
+                .build(),
+            cache_misses: meter
+                .u64_counter("tpp_cache_misses_total")
+                .with_description("Total number of cacheable requests sent upstream")
+                .build(),
+            requests_coalesced: meter
+                .u64_counter("tpp_requests_coalesced_total")
+                .with_description(
+                    "Total number of requests answered with the response of an identical request",
+                )
+                .build(),
+            requests_circuit_rejected: meter
+                .u64_counter("tpp_requests_circuit_rejected_total")
+                .with_description("Total number of requests rejected while the circuit was open")
+                .build(),
+            token_refreshes: meter
+                .u64_counter("tpp_token_refreshes_total")
+                .with_description("Total number of token refresh attempts by outcome")
+                .build(),
         }
     }
 }
 
+/// Number of token IDs reported under one `token_bucket` attribute value,
+/// keeping the attribute's cardinality bounded for large pools
+const TOKEN_BUCKET_SIZE: usize = 10;
+
+/// Bucket of token IDs a token is reported under, e.g. "10-19"
+pub fn token_bucket(id: usize) -> String {
+    let start = id / TOKEN_BUCKET_SIZE * TOKEN_BUCKET_SIZE;
+    format!("{}-{}", start, start + TOKEN_BUCKET_SIZE - 1)
+}
+
+/// Class of a response status for the `status_class` attribute, e.g. "2xx"
+/// ("none" if no response was sent)
+pub fn status_class(status: Option<u16>) -> &'static str {
+    match status {
+        Some(100..=199) => "1xx",
+        Some(200..=299) => "2xx",
+        Some(300..=399) => "3xx",
+        Some(400..=499) => "4xx",
+        Some(500..=599) => "5xx",
+        _ => "none",
+    }
+}
+
 pub fn get_metrics() -> Option<&'static PoolMetrics> {
     METRICS.get()
 }
 
+/// Render every instrument in the Prometheus text format, empty until
+/// telemetry is initialized
+pub fn render_metrics() -> String {
+    PROMETHEUS.get().map(encode_metrics).unwrap_or_default()
+}
+
+/// Render the metrics of a registry in the Prometheus text format
+fn encode_metrics(registry: &prometheus::Registry) -> String {
+    let mut buffer = Vec::new();
+    if let Err(e) = TextEncoder::new().encode(&registry.gather(), &mut buffer) {
+        tracing::warn!("Failed to encode metrics: {}", e);
+    }
+    String::from_utf8(buffer).unwrap_or_default()
+}
+
+/// Reads the W3C trace context from request headers
+struct HeaderExtractor<'a>(&'a HMap);
+
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|value| value.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|name| name.as_str()).collect()
+    }
+}
+
+/// Continue the trace a request carries in its `traceparent` and
+/// `tracestate` headers, if any, in `span`
+pub fn set_parent_from(span: &Span, headers: &HMap) {
+    let context =
+        global::get_text_map_propagator(|propagator| propagator.extract(&HeaderExtractor(headers)));
+    span.set_parent(context);
+}
+
+/// Trace context headers that make `span` the parent of an outgoing request,
+/// empty unless spans are exported
+pub fn trace_context(span: &Span) -> HashMap<String, String> {
+    let mut headers = HashMap::new();
+    global::get_text_map_propagator(|propagator| {
+        propagator.inject_context(&span.context(), &mut headers)
+    });
+    headers
+}
+
+thread_local! {
+    /// Contexts of the spans entered on this thread, innermost last
+    static ENTERED_CONTEXTS: RefCell<Vec<ContextGuard>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Makes the OpenTelemetry context of the entered span current, so log
+/// records exported over OTLP carry its trace and span ID
+struct SpanContextLayer;
+
+impl<S> Layer<S> for SpanContextLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_enter(&self, _id: &Id, _ctx: layer::Context<'_, S>) {
+        // The registry has already made the entered span current
+        let guard = Span::current().context().attach();
+        ENTERED_CONTEXTS.with(|contexts| contexts.borrow_mut().push(guard));
+    }
+
+    fn on_exit(&self, _id: &Id, _ctx: layer::Context<'_, S>) {
+        ENTERED_CONTEXTS.with(|contexts| contexts.borrow_mut().pop());
+    }
+}
+
+/// The log filter to return to once a temporary one expires
+struct LogFilterState {
+    base: String,
+    /// When the temporary filter in effect reverts to `base`
+    revert_at: Option<Instant>,
+    /// Bumped on every change, so the timer of a replaced temporary filter
+    /// does not revert a newer one
+    generation: u64,
+}
+
+fn reload_log_filter(filter: &str) -> Result<(), String> {
+    let handle = LOG_FILTER
+        .get()
+        .ok_or_else(|| "telemetry is not initialized".to_string())?;
+    let env_filter = EnvFilter::try_new(filter).map_err(|e| e.to_string())?;
+    handle.reload(env_filter).map_err(|e| e.to_string())
+}
+
+/// Check that a log filter parses, without applying it
+pub fn check_log_filter(filter: &str) -> Result<(), String> {
+    EnvFilter::try_new(filter)
+        .map(drop)
+        .map_err(|e| e.to_string())
+}
+
+/// Replace the log filter at runtime, e.g. `info,tpp=debug`, ending any
+/// temporary one
+pub fn set_log_filter(filter: &str) -> Result<(), String> {
+    reload_log_filter(filter)?;
+    let mut state = LOG_FILTER_STATE.lock();
+    state.base = filter.to_string();
+    state.revert_at = None;
+    state.generation += 1;
+    Ok(())
+}
+
+/// Apply a log filter for `duration`, then return to the one set before
+pub fn set_log_filter_for(filter: &str, duration: Duration) -> Result<(), String> {
+    reload_log_filter(filter)?;
+    let generation = {
+        let mut state = LOG_FILTER_STATE.lock();
+        state.revert_at = Some(Instant::now() + duration);
+        state.generation += 1;
+        state.generation
+    };
+
+    tokio::spawn(async move {
+        tokio::time::sleep(duration).await;
+        revert(Some(generation));
+    });
+    Ok(())
+}
+
+/// End the temporary log filter in effect, returning false if there is none
+pub fn revert_log_filter() -> bool {
+    revert(None)
+}
+
+/// Return to the base log filter, unless the filter changed since
+/// `generation` if given
+fn revert(generation: Option<u64>) -> bool {
+    let base = {
+        let mut state = LOG_FILTER_STATE.lock();
+        if generation.is_some_and(|generation| generation != state.generation)
+            || state.revert_at.take().is_none()
+        {
+            return false;
+        }
+        state.generation += 1;
+        state.base.clone()
+    };
+
+    match reload_log_filter(&base) {
+        Ok(()) => info!("Log filter reverted to '{}'", base),
+        Err(e) => warn!("Failed to revert log filter to '{}': {}", base, e),
+    }
+    true
+}
+
+/// Get the log filter currently in effect
+pub fn log_filter() -> Option<String> {
+    LOG_FILTER
+        .get()
+        .and_then(|handle| handle.with_current(|filter| filter.to_string()).ok())
+}
+
+/// Time left until the temporary log filter in effect reverts, if any
+pub fn log_filter_reverts_in() -> Option<Duration> {
+    LOG_FILTER_STATE
+        .lock()
+        .revert_at
+        .map(|at| at.saturating_duration_since(Instant::now()))
+}
+
+/// Apply `filter` for `duration` on SIGUSR1 and revert early on SIGUSR2
+pub fn spawn_log_filter_signals(
+    filter: String,
+    duration: Duration,
+) -> std::io::Result<tokio::task::JoinHandle<()>> {
+    let mut usr1 = signal(SignalKind::user_defined1())?;
+    let mut usr2 = signal(SignalKind::user_defined2())?;
+
+    Ok(tokio::spawn(async move {
+        loop {
+            tokio::select! {
+                Some(()) = usr1.recv() => match set_log_filter_for(&filter, duration) {
+                    Ok(()) => info!(
+                        "SIGUSR1: log filter '{}' for {}s",
+                        filter,
+                        duration.as_secs()
+                    ),
+                    Err(e) => warn!("SIGUSR1: failed to set log filter '{}': {}", filter, e),
+                },
+                Some(()) = usr2.recv() => {
+                    if !revert_log_filter() {
+                        info!("SIGUSR2: no temporary log filter to revert");
+                    }
+                }
+                else => break,
+            }
+        }
+    }))
+}
+
 pub struct TelemetryConfig {
     pub otlp_endpoint: Option<String>,
+    pub otlp_protocol: OtlpProtocol,
+    pub otlp_logs: bool,
+    pub sampling_ratio: f64,
     pub log_filter: String,
+    pub log_format: LogFormat,
 }
 
 impl Default for TelemetryConfig {
     fn default() -> Self {
         Self {
             otlp_endpoint: None,
+            otlp_protocol: OtlpProtocol::default(),
+            otlp_logs: false,
+            sampling_ratio: 1.0,
             log_filter: "info".to_string(),
+            log_format: LogFormat::default(),
         }
     }
 }
@@ -122,46 +479,128 @@ fn create_resource() -> Resource {
     ])
 }
 
+/// URL a signal is sent to over OTLP/HTTP, e.g. `<endpoint>/v1/traces`
+fn otlp_http_url(endpoint: &str, signal: &str) -> String {
+    format!("{}/v1/{}", endpoint.trim_end_matches('/'), signal)
+}
+
 fn init_tracer_provider(
     endpoint: &str,
+    config: &TelemetryConfig,
 ) -> Result<TracerProvider, opentelemetry::trace::TraceError> {
-    let exporter = opentelemetry_otlp::SpanExporter::builder()
-        .with_tonic()
-        .with_endpoint(endpoint)
-        .with_timeout(Duration::from_secs(3))
-        .build()?;
+    let exporter = match config.otlp_protocol {
+        OtlpProtocol::Grpc => SpanExporter::builder()
+            .with_tonic()
+            .with_endpoint(endpoint)
+            .with_timeout(EXPORT_TIMEOUT)
+            .build()?,
+        OtlpProtocol::HttpProtobuf => SpanExporter::builder()
+            .with_http()
+            .with_protocol(Protocol::HttpBinary)
+            .with_endpoint(otlp_http_url(endpoint, "traces"))
+            .with_timeout(EXPORT_TIMEOUT)
+            .build()?,
+    };
+
+    // Follow the sampling decision of a client's trace, sample new ones by ratio
+    let sampler = Sampler::ParentBased(Box::new(Sampler::TraceIdRatioBased(config.sampling_ratio)));
 
     let provider = TracerProvider::builder()
         .with_resource(create_resource())
+        .with_sampler(sampler)
         .with_batch_exporter(exporter, runtime::Tokio)
         .build();
 
     Ok(provider)
 }
 
-fn init_meter_provider(
+fn init_logger_provider(
     endpoint: &str,
+    protocol: OtlpProtocol,
+) -> Result<LoggerProvider, opentelemetry_sdk::logs::LogError> {
+    let exporter = match protocol {
+        OtlpProtocol::Grpc => LogExporter::builder()
+            .with_tonic()
+            .with_endpoint(endpoint)
+            .with_timeout(EXPORT_TIMEOUT)
+            .build()?,
+        OtlpProtocol::HttpProtobuf => LogExporter::builder()
+            .with_http()
+            .with_protocol(Protocol::HttpBinary)
+            .with_endpoint(otlp_http_url(endpoint, "logs"))
+            .with_timeout(EXPORT_TIMEOUT)
+            .build()?,
+    };
+
+    let provider = LoggerProvider::builder()
+        .with_resource(create_resource())
+        .with_batch_exporter(exporter, runtime::Tokio)
+        .build();
+
+    Ok(provider)
+}
+
+/// Build the meter provider, read by the Prometheus registry served on
+/// `/metrics` and, given an endpoint, pushed over OTLP under the same names
+fn init_meter_provider(
+    otlp: Option<(&str, OtlpProtocol)>,
 ) -> Result<SdkMeterProvider, opentelemetry_sdk::metrics::MetricError> {
-    let exporter = opentelemetry_otlp::MetricExporter::builder()
-        .with_tonic()
-        .with_endpoint(endpoint)
-        .with_timeout(Duration::from_secs(3))
-        .build()?;
+    let registry = prometheus::Registry::new();
+    let provider = meter_provider(&registry, otlp)?;
+    let _ = PROMETHEUS.set(registry);
+    Ok(provider)
+}
 
-    let reader = PeriodicReader::builder(exporter, runtime::Tokio)
-        .with_interval(Duration::from_secs(10))
-        .build();
+/// Build a meter provider read by `registry`
+fn meter_provider(
+    registry: &prometheus::Registry,
+    otlp: Option<(&str, OtlpProtocol)>,
+) -> Result<SdkMeterProvider, opentelemetry_sdk::metrics::MetricError> {
+    let prometheus = opentelemetry_prometheus::exporter()
+        .with_registry(registry.clone())
+        .without_counter_suffixes()
+        .without_units()
+        .without_scope_info()
+        .build()?;
 
-    let provider = SdkMeterProvider::builder()
+    let mut builder = SdkMeterProvider::builder()
         .with_resource(create_resource())
-        .with_reader(reader)
-        .build();
+        .with_reader(prometheus);
+
+    if let Some((endpoint, protocol)) = otlp {
+        let exporter = match protocol {
+            OtlpProtocol::Grpc => MetricExporter::builder()
+                .with_tonic()
+                .with_endpoint(endpoint)
+                .with_timeout(EXPORT_TIMEOUT)
+                .build()?,
+            OtlpProtocol::HttpProtobuf => MetricExporter::builder()
+                .with_http()
+                .with_protocol(Protocol::HttpBinary)
+                .with_endpoint(otlp_http_url(endpoint, "metrics"))
+                .with_timeout(EXPORT_TIMEOUT)
+                .build()?,
+        };
+
+        let reader = PeriodicReader::builder(exporter, runtime::Tokio)
+            .with_interval(Duration::from_secs(10))
+            .build();
+        builder = builder.with_reader(reader);
+    }
 
-    Ok(provider)
+    Ok(builder.build())
 }
 
 pub fn init_telemetry(config: TelemetryConfig) -> Result<(), Box<dyn std::error::Error>> {
-    let env_filter = EnvFilter::new(&config.log_filter);
+    let (env_filter, filter_handle) = reload::Layer::new(EnvFilter::new(&config.log_filter));
+    let _ = LOG_FILTER.set(filter_handle);
+    LOG_FILTER_STATE.lock().base = config.log_filter.clone();
+    global::set_text_map_propagator(TraceContextPropagator::new());
+
+    let fmt_layer = match config.log_format {
+        LogFormat::Text => tracing_subscriber::fmt::layer().boxed(),
+        LogFormat::Json => tracing_subscriber::fmt::layer().json().boxed(),
+    };
 
     match &config.otlp_endpoint {
         Some(endpoint) => {
@@ -173,12 +612,24 @@ pub fn init_telemetry(config: TelemetryConfig) -> Result<(), Box<dyn std::error:
 
             // Initialize OpenTelemetry within the runtime context
             let endpoint_clone = endpoint.clone();
-            let (tracer_provider, meter_provider) = rt.block_on(async {
-                let tracer_provider = init_tracer_provider(&endpoint_clone)
+            let (tracer_provider, meter_provider, logger_provider) = rt.block_on(async {
+                let tracer_provider = init_tracer_provider(&endpoint_clone, &config)
                     .map_err(|e| format!("Failed to init tracer: {}", e))?;
-                let meter_provider = init_meter_provider(&endpoint_clone)
-                    .map_err(|e| format!("Failed to init meter: {}", e))?;
-                Ok::<_, Box<dyn std::error::Error>>((tracer_provider, meter_provider))
+                let meter_provider =
+                    init_meter_provider(Some((&endpoint_clone, config.otlp_protocol)))
+                        .map_err(|e| format!("Failed to init meter: {}", e))?;
+                let logger_provider = if config.otlp_logs {
+                    let provider = init_logger_provider(&endpoint_clone, config.otlp_protocol)
+                        .map_err(|e| format!("Failed to init logger: {}", e))?;
+                    Some(provider)
+                } else {
+                    None
+                };
+                Ok::<_, Box<dyn std::error::Error>>((
+                    tracer_provider,
+                    meter_provider,
+                    logger_provider,
+                ))
             })?;
 
             // Store the runtime to keep it alive
@@ -198,22 +649,38 @@ pub fn init_telemetry(config: TelemetryConfig) -> Result<(), Box<dyn std::error:
             // Set up tracing subscriber with OpenTelemetry layer
             let otel_layer = tracing_opentelemetry::layer().with_tracer(tracer);
 
+            // Log records carry the trace and span of the event they came from
+            let logs_layer = logger_provider.as_ref().map(|provider| {
+                OpenTelemetryTracingBridge::new(provider).with_filter(filter_fn(|metadata| {
+                    !OTLP_LOGS_EXCLUDED_TARGETS
+                        .iter()
+                        .any(|target| metadata.target().starts_with(target))
+                }))
+            });
+            let span_context_layer = logs_layer.as_ref().map(|_| SpanContextLayer);
+            if let Some(provider) = logger_provider {
+                let _ = LOGGER_PROVIDER.set(provider);
+            }
+
             tracing_subscriber::registry()
                 .with(env_filter)
-                .with(tracing_subscriber::fmt::layer())
+                .with(fmt_layer)
                 .with(otel_layer)
+                .with(span_context_layer)
+                .with(logs_layer)
                 .init();
 
             info!(
                 endpoint = %endpoint,
+                protocol = ?config.otlp_protocol,
+                logs = config.otlp_logs,
+                sampling_ratio = config.sampling_ratio,
                 "OpenTelemetry initialized with OTLP export"
             );
         }
         None => {
-            // Initialize without OpenTelemetry - just tracing-subscriber for console logging
-            let meter_provider = SdkMeterProvider::builder()
-                .with_resource(create_resource())
-                .build();
+            // Initialize without OTLP export - metrics are only served on `/metrics`
+            let meter_provider = init_meter_provider(None)?;
 
             global::set_meter_provider(meter_provider.clone());
 
@@ -222,7 +689,7 @@ pub fn init_telemetry(config: TelemetryConfig) -> Result<(), Box<dyn std::error:
 
             tracing_subscriber::registry()
                 .with(env_filter)
-                .with(tracing_subscriber::fmt::layer())
+                .with(fmt_layer)
                 .init();
 
             info!("Telemetry initialized without OTLP export");
@@ -234,4 +701,140 @@ pub fn init_telemetry(config: TelemetryConfig) -> Result<(), Box<dyn std::error:
 
 pub fn shutdown_telemetry() {
     global::shutdown_tracer_provider();
+    if let Some(provider) = LOGGER_PROVIDER.get() {
+        let _ = provider.shutdown();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_metric_attribute_values() {
+        assert_eq!(token_bucket(0), "0-9");
+        assert_eq!(token_bucket(9), "0-9");
+        assert_eq!(token_bucket(42), "40-49");
+
+        assert_eq!(status_class(Some(200)), "2xx");
+        assert_eq!(status_class(Some(504)), "5xx");
+        assert_eq!(status_class(Some(0)), "none");
+        assert_eq!(status_class(None), "none");
+    }
+
+    #[tokio::test]
+    async fn test_temporary_log_filter_reverts() {
+        let (layer, handle) = reload::Layer::new(EnvFilter::new("info"));
+        let _subscriber = tracing_subscriber::registry().with(layer);
+        let _ = LOG_FILTER.set(handle);
+        set_log_filter("info").unwrap();
+
+        set_log_filter_for("tpp=debug", Duration::from_millis(50)).unwrap();
+        assert_eq!(log_filter().as_deref(), Some("tpp=debug"));
+        assert!(log_filter_reverts_in().is_some());
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(log_filter().as_deref(), Some("info"));
+        assert!(log_filter_reverts_in().is_none());
+
+        // The timer of a replaced filter leaves the newer one alone
+        set_log_filter_for("debug", Duration::from_millis(50)).unwrap();
+        set_log_filter("warn").unwrap();
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(log_filter().as_deref(), Some("warn"));
+        assert!(!revert_log_filter());
+
+        set_log_filter_for("debug", Duration::from_secs(300)).unwrap();
+        assert!(revert_log_filter());
+        assert_eq!(log_filter().as_deref(), Some("warn"));
+    }
+
+    #[test]
+    fn test_otlp_http_url() {
+        assert_eq!(
+            otlp_http_url("http://collector:4318", "traces"),
+            "http://collector:4318/v1/traces"
+        );
+        assert_eq!(
+            otlp_http_url("http://collector:4318/", "logs"),
+            "http://collector:4318/v1/logs"
+        );
+    }
+
+    #[test]
+    fn test_render_metrics_uses_instrument_names() {
+        let registry = prometheus::Registry::new();
+        let provider = meter_provider(&registry, None).unwrap();
+        let metrics = PoolMetrics::new(&provider.meter(SERVICE_NAME));
+
+        let attrs = [KeyValue::new("pool", "read_write")];
+        metrics.requests_total.add(1, &attrs);
+        metrics.request_duration_seconds.record(0.02, &attrs);
+
+        let text = encode_metrics(&registry);
+        assert!(text.contains("tpp_requests_total{pool=\"read_write\"} 1"));
+        assert!(text
+            .contains("tpp_request_duration_seconds_bucket{pool=\"read_write\",le=\"0.025\"} 1"));
+        assert!(text.contains("tpp_request_duration_seconds_count{pool=\"read_write\"} 1"));
+    }
+
+    #[test]
+    fn test_trace_context_is_continued() {
+        global::set_text_map_propagator(TraceContextPropagator::new());
+        let tracer = TracerProvider::builder().build().tracer(SERVICE_NAME);
+        let subscriber =
+            tracing_subscriber::registry().with(tracing_opentelemetry::layer().with_tracer(tracer));
+
+        let trace_id = "4bf92f3577b34da6a3ce929d0e0e4736";
+        let mut headers = HMap::new();
+        headers.insert(
+            "traceparent",
+            format!("00-{}-00f067aa0ba902b7-01", trace_id)
+                .parse()
+                .unwrap(),
+        );
+
+        let forwarded = tracing::subscriber::with_default(subscriber, || {
+            let span = tracing::info_span!("request");
+            set_parent_from(&span, &headers);
+            trace_context(&span)
+        });
+
+        // Same trace, with the proxy's span as the parent
+        let traceparent = &forwarded["traceparent"];
+        assert!(traceparent.starts_with(&format!("00-{}-", trace_id)));
+        assert!(!traceparent.contains("00f067aa0ba902b7"));
+
+        // Nothing to forward without a trace
+        assert!(trace_context(&Span::none()).is_empty());
+    }
+
+    #[test]
+    fn test_entered_span_context_is_current() {
+        use opentelemetry::trace::TraceContextExt;
+
+        let tracer = TracerProvider::builder().build().tracer(SERVICE_NAME);
+        let subscriber = tracing_subscriber::registry()
+            .with(tracing_opentelemetry::layer().with_tracer(tracer))
+            .with(SpanContextLayer);
+
+        tracing::subscriber::with_default(subscriber, || {
+            let current = || {
+                opentelemetry::Context::current()
+                    .span()
+                    .span_context()
+                    .clone()
+            };
+            let outer = tracing::info_span!("outer");
+            let inner = outer.in_scope(|| tracing::info_span!("inner"));
+
+            outer.in_scope(|| {
+                assert_eq!(current(), outer.context().span().span_context().clone());
+                inner.in_scope(|| {
+                    assert_eq!(current(), inner.context().span().span_context().clone());
+                });
+                assert_eq!(current(), outer.context().span().span_context().clone());
+            });
+            assert!(!current().is_valid());
+        });
+    }
 }
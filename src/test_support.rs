@@ -0,0 +1,243 @@
+//! Helpers for tests that run the proxy against a local mock DolphinDB
+
+use std::net::{SocketAddr, TcpListener as StdTcpListener, TcpStream as StdTcpStream};
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures_util::{SinkExt, StreamExt};
+use parking_lot::Mutex;
+use pingora::apps::HttpServerOptions;
+use pingora::server::Server;
+use pingora_proxy::http_proxy_service;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_tungstenite::tungstenite::handshake::server::{Request, Response};
+
+use crate::proxy::TokenPoolProxy;
+
+/// Response returned by a [`MockUpstream`]
+pub struct MockResponse {
+    pub status: u16,
+    pub body: String,
+    /// Delay before the response is written
+    pub delay: Duration,
+}
+
+impl MockResponse {
+    pub fn ok(body: impl Into<String>) -> Self {
+        Self {
+            status: 200,
+            body: body.into(),
+            delay: Duration::ZERO,
+        }
+    }
+}
+
+type Handler = dyn Fn(&str) -> MockResponse + Send + Sync;
+
+/// HTTP/1.1 server standing in for DolphinDB
+pub struct MockUpstream {
+    pub addr: SocketAddr,
+    /// Raw request heads received, in order
+    pub requests: Arc<Mutex<Vec<String>>>,
+    /// Request bodies received, in order
+    pub bodies: Arc<Mutex<Vec<Vec<u8>>>>,
+}
+
+impl MockUpstream {
+    /// Upstream answering every request with 200 and the raw request head as body
+    pub async fn echo() -> Self {
+        Self::start(|head| MockResponse::ok(head)).await
+    }
+
+    pub async fn start<F>(handler: F) -> Self
+    where
+        F: Fn(&str) -> MockResponse + Send + Sync + 'static,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let bodies = Arc::new(Mutex::new(Vec::new()));
+        let handler: Arc<Handler> = Arc::new(handler);
+
+        let (seen, seen_bodies) = (requests.clone(), bodies.clone());
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(serve(
+                    stream,
+                    handler.clone(),
+                    seen.clone(),
+                    seen_bodies.clone(),
+                ));
+            }
+        });
+
+        Self {
+            addr,
+            requests,
+            bodies,
+        }
+    }
+
+    /// Address as "host:port"
+    pub fn address(&self) -> String {
+        self.addr.to_string()
+    }
+
+    pub fn request_count(&self) -> usize {
+        self.requests.lock().len()
+    }
+}
+
+/// Serve keep-alive requests on one connection until the peer closes it
+async fn serve(
+    mut stream: TcpStream,
+    handler: Arc<Handler>,
+    seen: Arc<Mutex<Vec<String>>>,
+    bodies: Arc<Mutex<Vec<Vec<u8>>>>,
+) {
+    let mut buf = Vec::new();
+    loop {
+        let Some(head_end) = read_head(&mut stream, &mut buf).await else {
+            return;
+        };
+        let head = String::from_utf8_lossy(&buf[..head_end]).to_string();
+        let body_len = content_length(&head);
+        while buf.len() < head_end + body_len {
+            let mut chunk = [0u8; 4096];
+            match stream.read(&mut chunk).await {
+                Ok(0) | Err(_) => return,
+                Ok(n) => buf.extend_from_slice(&chunk[..n]),
+            }
+        }
+        let body = buf.drain(..head_end + body_len).skip(head_end).collect();
+
+        seen.lock().push(head.clone());
+        bodies.lock().push(body);
+        let response = handler(&head);
+        tokio::time::sleep(response.delay).await;
+
+        let raw = format!(
+            "HTTP/1.1 {} Mock\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+            response.status,
+            response.body.len(),
+            response.body
+        );
+        if stream.write_all(raw.as_bytes()).await.is_err() {
+            return;
+        }
+    }
+}
+
+/// Read until the end of a request head, returning its length including the blank line
+async fn read_head(stream: &mut TcpStream, buf: &mut Vec<u8>) -> Option<usize> {
+    loop {
+        if let Some(pos) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            return Some(pos + 4);
+        }
+        let mut chunk = [0u8; 4096];
+        match stream.read(&mut chunk).await {
+            Ok(0) | Err(_) => return None,
+            Ok(n) => buf.extend_from_slice(&chunk[..n]),
+        }
+    }
+}
+
+fn content_length(head: &str) -> usize {
+    head.lines()
+        .filter_map(|line| line.split_once(':'))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("content-length"))
+        .and_then(|(_, value)| value.trim().parse().ok())
+        .unwrap_or(0)
+}
+
+/// WebSocket server echoing every message back, standing in for DolphinDB streaming
+pub struct MockWebSocket {
+    pub addr: SocketAddr,
+    /// Authorization header of each upgrade request, in order
+    pub authorizations: Arc<Mutex<Vec<Option<String>>>>,
+}
+
+impl MockWebSocket {
+    pub async fn echo() -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let authorizations = Arc::new(Mutex::new(Vec::new()));
+
+        let seen = authorizations.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let seen = seen.clone();
+                tokio::spawn(async move {
+                    // The error type is fixed by tungstenite's callback
+                    #[allow(clippy::result_large_err)]
+                    let record = |req: &Request, resp: Response| {
+                        let authorization = req
+                            .headers()
+                            .get("authorization")
+                            .and_then(|value| value.to_str().ok())
+                            .map(str::to_string);
+                        seen.lock().push(authorization);
+                        Ok(resp)
+                    };
+                    let Ok(mut ws) = tokio_tungstenite::accept_hdr_async(stream, record).await
+                    else {
+                        return;
+                    };
+                    while let Some(Ok(msg)) = ws.next().await {
+                        if (msg.is_text() || msg.is_binary()) && ws.send(msg).await.is_err() {
+                            return;
+                        }
+                    }
+                });
+            }
+        });
+
+        Self {
+            addr,
+            authorizations,
+        }
+    }
+
+    /// Address as "host:port"
+    pub fn address(&self) -> String {
+        self.addr.to_string()
+    }
+}
+
+/// Run the proxy on a Pingora server in a background thread and return its address
+pub fn spawn_proxy(proxy: TokenPoolProxy) -> SocketAddr {
+    spawn(proxy, false)
+}
+
+/// Like [`spawn_proxy`], also accepting HTTP/2 with prior knowledge (h2c)
+pub fn spawn_h2c_proxy(proxy: TokenPoolProxy) -> SocketAddr {
+    spawn(proxy, true)
+}
+
+fn spawn(proxy: TokenPoolProxy, h2c: bool) -> SocketAddr {
+    let addr = StdTcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+
+    let mut server = Server::new(None).unwrap();
+    server.bootstrap();
+    let mut service = http_proxy_service(&server.configuration, proxy);
+    if let Some(app) = service.app_logic_mut() {
+        let mut options = HttpServerOptions::default();
+        options.h2c = h2c;
+        app.server_options = Some(options);
+    }
+    service.add_tcp(&addr.to_string());
+    server.add_service(service);
+    std::thread::spawn(move || server.run_forever());
+
+    for _ in 0..100 {
+        if StdTcpStream::connect(addr).is_ok() {
+            return addr;
+        }
+        std::thread::sleep(Duration::from_millis(20));
+    }
+    panic!("proxy did not start listening on {}", addr);
+}
@@ -0,0 +1,403 @@
+use std::fs;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use async_trait::async_trait;
+use parking_lot::RwLock;
+use pingora::listeners::tls::TlsSettings;
+use pingora::listeners::TlsAccept;
+use pingora::prelude::HttpPeer;
+use pingora::protocols::tls::{CaType, TlsRef};
+use pingora::tls::ext;
+use pingora::tls::pkey::{PKey, Private};
+use pingora::tls::ssl::SslVerifyMode;
+use pingora::tls::x509::{X509Name, X509};
+use pingora::utils::tls::CertKey as PeerCertKey;
+use tracing::{error, info, warn};
+
+use crate::config::{ListenerTlsConfig, UpstreamConfig};
+use crate::error::{Result, TppError};
+
+/// A certificate chain and its private key
+struct CertKey {
+    /// Leaf certificate first, then intermediates
+    chain: Vec<X509>,
+    key: PKey<Private>,
+}
+
+impl CertKey {
+    fn load(cert_path: &str, key_path: &str) -> Result<Self> {
+        let cert_pem = fs::read(cert_path).map_err(|e| {
+            TppError::Tls(format!("Failed to read certificate '{}': {}", cert_path, e))
+        })?;
+        let key_pem = fs::read(key_path)
+            .map_err(|e| TppError::Tls(format!("Failed to read key '{}': {}", key_path, e)))?;
+
+        let chain = X509::stack_from_pem(&cert_pem)
+            .map_err(|e| TppError::Tls(format!("Invalid certificate '{}': {}", cert_path, e)))?;
+        if chain.is_empty() {
+            return Err(TppError::Tls(format!(
+                "No certificate found in '{}'",
+                cert_path
+            )));
+        }
+        let key = PKey::private_key_from_pem(&key_pem)
+            .map_err(|e| TppError::Tls(format!("Invalid key '{}': {}", key_path, e)))?;
+
+        Ok(Self { chain, key })
+    }
+}
+
+/// Listener certificate, reloaded when its files change
+///
+/// Served through the handshake callback so reloads apply to new connections
+/// without restarting the listener.
+#[derive(Clone)]
+pub struct ListenerCerts {
+    cert_path: String,
+    key_path: String,
+    current: Arc<RwLock<Arc<CertKey>>>,
+    /// Modification times of the certificate and key when last loaded
+    loaded_mtimes: Arc<RwLock<(Option<SystemTime>, Option<SystemTime>)>>,
+}
+
+impl ListenerCerts {
+    pub fn load(cert_path: &str, key_path: &str) -> Result<Self> {
+        let mtimes = (modified(cert_path), modified(key_path));
+        let cert = CertKey::load(cert_path, key_path)?;
+        Ok(Self {
+            cert_path: cert_path.to_string(),
+            key_path: key_path.to_string(),
+            current: Arc::new(RwLock::new(Arc::new(cert))),
+            loaded_mtimes: Arc::new(RwLock::new(mtimes)),
+        })
+    }
+
+    /// Reload the certificate if either file changed since it was loaded
+    ///
+    /// Returns whether a new certificate was installed. On error the current
+    /// certificate stays in use.
+    pub fn reload_if_changed(&self) -> Result<bool> {
+        let mtimes = (modified(&self.cert_path), modified(&self.key_path));
+        if *self.loaded_mtimes.read() == mtimes {
+            return Ok(false);
+        }
+
+        let cert = CertKey::load(&self.cert_path, &self.key_path)?;
+        *self.current.write() = Arc::new(cert);
+        *self.loaded_mtimes.write() = mtimes;
+        Ok(true)
+    }
+
+    /// Serial number of the leaf certificate currently served
+    pub fn serial(&self) -> Option<String> {
+        let cert = self.current.read().clone();
+        cert.chain[0]
+            .serial_number()
+            .to_bn()
+            .and_then(|bn| bn.to_hex_str())
+            .map(|hex| hex.to_string())
+            .ok()
+    }
+}
+
+fn modified(path: &str) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+#[async_trait]
+impl TlsAccept for ListenerCerts {
+    async fn certificate_callback(&self, ssl: &mut TlsRef) {
+        let cert = self.current.read().clone();
+
+        if let Err(e) = ext::ssl_use_certificate(ssl, &cert.chain[0]) {
+            error!("Failed to use listener certificate: {}", e);
+            return;
+        }
+        for intermediate in &cert.chain[1..] {
+            if let Err(e) = ext::ssl_add_chain_cert(ssl, intermediate) {
+                error!("Failed to add intermediate certificate: {}", e);
+                return;
+            }
+        }
+        if let Err(e) = ext::ssl_use_private_key(ssl, &cert.key) {
+            error!("Failed to use listener private key: {}", e);
+        }
+    }
+}
+
+/// Build the TLS settings for the proxy listener
+///
+/// Returns the settings together with the certificate handle used for reloads.
+pub fn listener_settings(config: &ListenerTlsConfig) -> Result<(TlsSettings, ListenerCerts)> {
+    let certs = ListenerCerts::load(&config.cert_path, &config.key_path)?;
+
+    let mut settings = TlsSettings::with_callbacks(Box::new(certs.clone()))
+        .map_err(|e| TppError::Tls(e.to_string()))?;
+
+    if let Some(ref ca_path) = config.client_ca_path {
+        settings
+            .set_ca_file(ca_path)
+            .map_err(|e| TppError::Tls(format!("Invalid client CA '{}': {}", ca_path, e)))?;
+        let ca_names = X509Name::load_client_ca_file(ca_path)
+            .map_err(|e| TppError::Tls(format!("Invalid client CA '{}': {}", ca_path, e)))?;
+        settings.set_client_ca_list(ca_names);
+
+        let mode = if config.require_client_cert {
+            SslVerifyMode::PEER | SslVerifyMode::FAIL_IF_NO_PEER_CERT
+        } else {
+            SslVerifyMode::PEER
+        };
+        settings.set_verify(mode);
+    }
+
+    Ok((settings, certs))
+}
+
+/// Spawn a task that reloads the listener certificate when its files change
+pub fn spawn_cert_reloader(
+    certs: ListenerCerts,
+    interval: Duration,
+) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        ticker.tick().await;
+        loop {
+            ticker.tick().await;
+            match certs.reload_if_changed() {
+                Ok(true) => info!(
+                    "Reloaded listener certificate from '{}' (serial: {})",
+                    certs.cert_path,
+                    certs.serial().unwrap_or_default()
+                ),
+                Ok(false) => {}
+                Err(e) => warn!("Keeping current listener certificate: {}", e),
+            }
+        }
+    })
+}
+
+/// TLS settings for connections to DolphinDB
+///
+/// Applied to both the proxied connections and the login client so they
+/// trust, present and verify the same certificates.
+#[derive(Clone)]
+pub struct UpstreamTls {
+    /// Server name sent in SNI and verified against the certificate of
+    /// every node (each node's own host if unset)
+    sni: Option<String>,
+    /// Verify the server certificate and hostname
+    verify: bool,
+    /// Custom CA bundle (system roots if unset)
+    ca_pem: Option<Vec<u8>>,
+    ca: Option<Arc<CaType>>,
+    /// Client certificate and PKCS#8 key for mutual TLS
+    identity_pem: Option<(Vec<u8>, Vec<u8>)>,
+    client_cert_key: Option<Arc<PeerCertKey>>,
+}
+
+impl UpstreamTls {
+    pub fn load(config: &UpstreamConfig) -> Result<Self> {
+        let (ca_pem, ca) = match config.ca_path {
+            Some(ref path) => {
+                let pem = fs::read(path).map_err(|e| {
+                    TppError::Tls(format!("Failed to read upstream CA '{}': {}", path, e))
+                })?;
+                let certs = X509::stack_from_pem(&pem)
+                    .map_err(|e| TppError::Tls(format!("Invalid upstream CA '{}': {}", path, e)))?;
+                (Some(pem), Some(Arc::new(certs.into_boxed_slice())))
+            }
+            None => (None, None),
+        };
+
+        let (identity_pem, client_cert_key) =
+            match (&config.client_cert_path, &config.client_key_path) {
+                (Some(cert_path), Some(key_path)) => {
+                    let cert = CertKey::load(cert_path, key_path)?;
+                    let mut cert_pem = Vec::new();
+                    for c in &cert.chain {
+                        cert_pem.extend(c.to_pem().map_err(|e| {
+                            TppError::Tls(format!("Invalid certificate '{}': {}", cert_path, e))
+                        })?);
+                    }
+                    // The login client only accepts PKCS#8 keys
+                    let key_pem = cert
+                        .key
+                        .private_key_to_pem_pkcs8()
+                        .map_err(|e| TppError::Tls(format!("Invalid key '{}': {}", key_path, e)))?;
+                    (
+                        Some((cert_pem, key_pem)),
+                        Some(Arc::new(PeerCertKey::new(cert.chain, cert.key))),
+                    )
+                }
+                _ => (None, None),
+            };
+
+        Ok(Self {
+            sni: config.sni.clone(),
+            verify: !config.insecure_skip_verify,
+            ca_pem,
+            ca,
+            identity_pem,
+            client_cert_key,
+        })
+    }
+
+    /// Server name sent in SNI instead of the node's host, if configured
+    pub fn sni(&self) -> Option<&str> {
+        self.sni.as_deref()
+    }
+
+    /// Apply these settings to a peer created for the proxied request
+    ///
+    /// The peer keeps the SNI of its own node unless `upstream.sni` is set,
+    /// which all nodes then share.
+    pub fn apply_to_peer(&self, peer: &mut HttpPeer) {
+        if let Some(ref sni) = self.sni {
+            peer.sni = sni.clone();
+        }
+        peer.options.verify_cert = self.verify;
+        peer.options.verify_hostname = self.verify;
+        peer.options.ca = self.ca.clone();
+        peer.client_cert_key = self.client_cert_key.clone();
+    }
+
+    /// Apply these settings to the login client
+    pub fn apply_to_client(
+        &self,
+        mut builder: reqwest::ClientBuilder,
+    ) -> Result<reqwest::ClientBuilder> {
+        if let Some(ref pem) = self.ca_pem {
+            let certs = reqwest::Certificate::from_pem_bundle(pem)
+                .map_err(|e| TppError::Tls(format!("Invalid upstream CA: {}", e)))?;
+            for cert in certs {
+                builder = builder.add_root_certificate(cert);
+            }
+        }
+
+        if let Some((ref cert_pem, ref key_pem)) = self.identity_pem {
+            let identity = reqwest::Identity::from_pkcs8_pem(cert_pem, key_pem).map_err(|e| {
+                TppError::Tls(format!("Invalid upstream client certificate: {}", e))
+            })?;
+            builder = builder.identity(identity);
+        }
+
+        if !self.verify {
+            builder = builder
+                .danger_accept_invalid_certs(true)
+                .danger_accept_invalid_hostnames(true);
+        }
+
+        Ok(builder)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use openssl::asn1::Asn1Time;
+    use openssl::bn::BigNum;
+    use openssl::hash::MessageDigest;
+    use openssl::rsa::Rsa;
+    use openssl::x509::X509NameBuilder;
+
+    /// Write a self-signed certificate with the given serial and its key
+    fn write_cert(dir: &std::path::Path, serial: u32) -> (String, String) {
+        let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        let mut name = X509NameBuilder::new().unwrap();
+        name.append_entry_by_text("CN", "localhost").unwrap();
+        let name = name.build();
+
+        let mut builder = X509::builder().unwrap();
+        builder.set_version(2).unwrap();
+        builder
+            .set_serial_number(&BigNum::from_u32(serial).unwrap().to_asn1_integer().unwrap())
+            .unwrap();
+        builder.set_subject_name(&name).unwrap();
+        builder.set_issuer_name(&name).unwrap();
+        builder.set_pubkey(&key).unwrap();
+        builder
+            .set_not_before(&Asn1Time::days_from_now(0).unwrap())
+            .unwrap();
+        builder
+            .set_not_after(&Asn1Time::days_from_now(1).unwrap())
+            .unwrap();
+        builder.sign(&key, MessageDigest::sha256()).unwrap();
+
+        let cert_path = dir.join("cert.pem");
+        let key_path = dir.join("key.pem");
+        fs::write(&cert_path, builder.build().to_pem().unwrap()).unwrap();
+        fs::write(&key_path, key.private_key_to_pem_pkcs8().unwrap()).unwrap();
+        (
+            cert_path.to_string_lossy().to_string(),
+            key_path.to_string_lossy().to_string(),
+        )
+    }
+
+    #[test]
+    fn test_reload_on_change() {
+        let dir = std::env::temp_dir().join(format!("tpp-tls-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        let (cert_path, key_path) = write_cert(&dir, 1);
+        let certs = ListenerCerts::load(&cert_path, &key_path).unwrap();
+        assert_eq!(certs.serial().as_deref(), Some("01"));
+        assert!(!certs.reload_if_changed().unwrap());
+
+        // Make sure the modification time moves even on coarse filesystems
+        std::thread::sleep(Duration::from_millis(1100));
+        write_cert(&dir, 2);
+        assert!(certs.reload_if_changed().unwrap());
+        assert_eq!(certs.serial().as_deref(), Some("02"));
+
+        // A broken file keeps the current certificate
+        std::thread::sleep(Duration::from_millis(1100));
+        fs::write(&cert_path, "not a certificate").unwrap();
+        assert!(certs.reload_if_changed().is_err());
+        assert_eq!(certs.serial().as_deref(), Some("02"));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_upstream_tls_applies_to_peer() {
+        let dir = std::env::temp_dir().join(format!("tpp-upstream-tls-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let (cert_path, key_path) = write_cert(&dir, 7);
+
+        let upstream = UpstreamConfig {
+            host: "10.0.0.5".to_string(),
+            port: 8848,
+            tls: true,
+            ca_path: Some(cert_path.clone()),
+            client_cert_path: Some(cert_path),
+            client_key_path: Some(key_path),
+            sni: Some("dolphindb.internal".to_string()),
+            insecure_skip_verify: false,
+            timeouts: Default::default(),
+            nodes: Vec::new(),
+        };
+        let tls = UpstreamTls::load(&upstream).unwrap();
+        assert_eq!(tls.sni(), Some("dolphindb.internal"));
+        assert!(tls.apply_to_client(reqwest::Client::builder()).is_ok());
+
+        let mut peer = HttpPeer::new(upstream.address(), true, upstream.host.clone());
+        tls.apply_to_peer(&mut peer);
+        assert_eq!(peer.sni, "dolphindb.internal");
+        assert!(peer.options.verify_cert);
+        assert_eq!(peer.options.ca.as_ref().unwrap().len(), 1);
+        assert!(peer.client_cert_key.is_some());
+
+        // Without a configured name, each node is verified by its own host
+        let tls = UpstreamTls::load(&UpstreamConfig {
+            sni: None,
+            ..upstream
+        })
+        .unwrap();
+        let mut peer = HttpPeer::new("10.0.0.6:8848", true, "10.0.0.6".to_string());
+        tls.apply_to_peer(&mut peer);
+        assert_eq!(peer.sni, "10.0.0.6");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
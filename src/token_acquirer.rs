@@ -1,10 +1,14 @@
+use std::net::{SocketAddr, ToSocketAddrs};
+
 use reqwest::Client;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
-use tracing::{error, info, warn};
+use tracing::{error, info, instrument, warn, Span};
 
-use crate::config::Credential;
+use crate::config::{Credential, UpstreamConfig};
 use crate::error::{Result, TppError};
+use crate::telemetry::trace_context;
+use crate::tls::UpstreamTls;
 
 /// DolphinDB login request body
 #[derive(Debug, Serialize)]
@@ -13,26 +17,19 @@ struct LoginRequest {
     password: String,
 }
 
-/// DolphinDB login response
+/// Status fields common to DolphinDB REST responses
 /// See: https://docs.dolphindb.com/en/API/rest_api.html
-#[derive(Debug, Deserialize)]
-#[allow(dead_code)]
-struct LoginResponse {
-    /// Session identifier
-    session: Option<Value>,
-    /// Authenticated username
-    user: Option<String>,
+#[derive(Debug, Default, Deserialize)]
+pub(crate) struct ApiStatus {
     /// 0 for success, 1 for failure (can be string or number)
-    code: Option<Value>,
+    pub code: Option<Value>,
     /// Error description (empty on success)
-    message: Option<String>,
-    /// Array containing the user token on success
-    result: Option<Vec<String>>,
+    pub message: Option<String>,
 }
 
-impl LoginResponse {
-    /// Check if login was successful (code == 0 or "0")
-    fn is_success(&self) -> bool {
+impl ApiStatus {
+    /// Check if the call was successful (code == 0 or "0")
+    pub fn is_success(&self) -> bool {
         match &self.code {
             Some(Value::Number(n)) => n.as_i64() == Some(0),
             Some(Value::String(s)) => s == "0",
@@ -41,14 +38,30 @@ impl LoginResponse {
     }
 
     /// Get code as string for error messages
-    fn code_str(&self) -> String {
+    pub fn code_str(&self) -> String {
         match &self.code {
+            Some(Value::String(s)) => s.clone(),
             Some(v) => v.to_string(),
             None => "unknown".to_string(),
         }
     }
 }
 
+/// DolphinDB login response
+#[derive(Debug, Deserialize)]
+#[allow(dead_code)]
+struct LoginResponse {
+    /// Session identifier
+    session: Option<Value>,
+    /// Authenticated username
+    user: Option<String>,
+    /// Result code and error message
+    #[serde(flatten)]
+    status: ApiStatus,
+    /// Array containing the user token on success
+    result: Option<Vec<String>>,
+}
+
 /// Acquires tokens from DolphinDB by calling the login API
 #[derive(Clone)]
 pub struct TokenAcquirer {
@@ -69,25 +82,55 @@ impl TokenAcquirer {
         Self { client, login_url }
     }
 
+    /// Create a token acquirer for the upstream, using the same TLS settings
+    /// as proxied connections
+    pub fn from_upstream(upstream: &UpstreamConfig, tls: Option<&UpstreamTls>) -> Result<Self> {
+        let mut builder = Client::builder().timeout(std::time::Duration::from_secs(30));
+        let mut base_url = upstream.base_url();
+
+        if let Some(tls) = tls {
+            builder = tls.apply_to_client(builder)?;
+
+            // Connect to the configured host while sending and verifying the SNI name
+            if let Some(sni) = tls.sni().filter(|sni| *sni != upstream.host) {
+                let addrs: Vec<SocketAddr> = upstream.address().to_socket_addrs()?.collect();
+                builder = builder.resolve_to_addrs(sni, &addrs);
+                base_url = format!("https://{}:{}", sni, upstream.port);
+            }
+        }
+
+        let client = builder
+            .build()
+            .map_err(|e| TppError::Tls(format!("Failed to create login client: {}", e)))?;
+        let login_url = format!("{}/api/login", base_url);
+
+        Ok(Self { client, login_url })
+    }
+
     /// Login with a single credential and return the token
+    #[instrument(
+        name = "login",
+        skip_all,
+        fields(otel.kind = "client", tpp.user = %credential.username)
+    )]
     pub async fn login(&self, credential: &Credential) -> Result<String> {
         let request = LoginRequest {
             username: credential.username.clone(),
             password: credential.password.clone(),
         };
 
-        let response = self
-            .client
-            .post(&self.login_url)
-            .json(&request)
-            .send()
-            .await
-            .map_err(|e| {
-                TppError::TokenPool(format!(
-                    "Failed to send login request for user '{}': {}",
-                    credential.username, e
-                ))
-            })?;
+        // Let DolphinDB's side of the login join the trace
+        let mut builder = self.client.post(&self.login_url).json(&request);
+        for (name, value) in trace_context(&Span::current()) {
+            builder = builder.header(name, value);
+        }
+
+        let response = builder.send().await.map_err(|e| {
+            TppError::TokenPool(format!(
+                "Failed to send login request for user '{}': {}",
+                credential.username, e
+            ))
+        })?;
 
         if !response.status().is_success() {
             return Err(TppError::TokenPool(format!(
@@ -105,8 +148,9 @@ impl TokenAcquirer {
         })?;
 
         // Check result code (0 = success, 1 = failure in DolphinDB)
-        if !login_response.is_success() {
+        if !login_response.status.is_success() {
             let msg = login_response
+                .status
                 .message
                 .clone()
                 .unwrap_or_else(|| "Unknown error".to_string());
@@ -114,7 +158,7 @@ impl TokenAcquirer {
                 "Login failed for user '{}': {} (code: {})",
                 credential.username,
                 msg,
-                login_response.code_str()
+                login_response.status.code_str()
             )));
         }
 
@@ -179,6 +223,7 @@ impl TokenAcquirer {
     }
 
     /// Refresh a single token
+    #[instrument(name = "refresh", skip_all, fields(tpp.user = %credential.username))]
     pub async fn refresh(&self, credential: &Credential) -> Result<String> {
         info!("Refreshing token for user '{}'", credential.username);
         self.login(credential).await
@@ -1,14 +1,18 @@
-use std::sync::atomic::{AtomicU64, Ordering};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
 use std::sync::Arc;
 use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-use async_channel::{Receiver, Sender};
 use dashmap::DashMap;
-use parking_lot::RwLock;
-use tokio::sync::Notify;
+use opentelemetry::KeyValue;
+use parking_lot::{Mutex, RwLock};
+use serde::Serialize;
+use tokio::sync::{oneshot, Notify};
 use tracing::{debug, info, warn};
 
-use crate::config::Credential;
+use crate::client::ClientId;
+use crate::config::{ClientsConfig, Credential, Priority};
+use crate::telemetry::{get_metrics, token_bucket};
 
 /// A single token in the pool
 #[derive(Clone, Debug)]
@@ -31,10 +35,16 @@ pub struct TokenMeta {
     pub use_count: AtomicU64,
     /// Number of errors encountered with this token
     pub error_count: AtomicU64,
+    /// Number of application errors DolphinDB reported in successful responses
+    pub app_error_count: AtomicU64,
     /// Last time this token was used (unix timestamp)
     pub last_used: AtomicU64,
     /// Whether this token needs refresh
     pub needs_refresh: AtomicU64, // 0 = no, 1 = yes
+    /// Whether a refresh of this token is in progress
+    pub refreshing: AtomicBool,
+    /// Whether the last refresh of this token failed
+    pub refresh_failed: AtomicBool,
 }
 
 impl TokenMeta {
@@ -45,8 +55,11 @@ impl TokenMeta {
             credential,
             use_count: AtomicU64::new(0),
             error_count: AtomicU64::new(0),
+            app_error_count: AtomicU64::new(0),
             last_used: AtomicU64::new(0),
             needs_refresh: AtomicU64::new(0),
+            refreshing: AtomicBool::new(false),
+            refresh_failed: AtomicBool::new(false),
         }
     }
 
@@ -83,6 +96,21 @@ impl TokenMeta {
         *self.value.write() = new_value;
         *self.acquired_at.write() = Instant::now();
         self.needs_refresh.store(0, Ordering::Relaxed);
+        self.refreshing.store(false, Ordering::Relaxed);
+        self.refresh_failed.store(false, Ordering::Relaxed);
+    }
+
+    /// Where the token stands in its refresh cycle
+    fn refresh_state(&self, ttl: Option<Duration>) -> RefreshState {
+        if self.refreshing.load(Ordering::Relaxed) {
+            RefreshState::Refreshing
+        } else if self.refresh_failed.load(Ordering::Relaxed) {
+            RefreshState::Failed
+        } else if self.needs_refresh() || ttl.is_some_and(|ttl| self.is_expired(ttl)) {
+            RefreshState::Pending
+        } else {
+            RefreshState::Current
+        }
     }
 
     /// Get current token value
@@ -91,72 +119,424 @@ impl TokenMeta {
     }
 }
 
-/// Token pool with semaphore-like semantics using async channels
+/// Where a token stands in its refresh cycle
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RefreshState {
+    /// Within its TTL and not marked for refresh
+    Current,
+    /// Expired or marked for refresh, waiting for the refresher
+    Pending,
+    /// Being refreshed right now
+    Refreshing,
+    /// The last refresh failed; the old value is still in use
+    Failed,
+}
+
+/// Snapshot of a token for operators, without its value
+#[derive(Debug, Clone, Serialize)]
+pub struct TokenStats {
+    pub id: usize,
+    /// First characters of the token value, the rest masked
+    pub prefix: String,
+    /// DolphinDB user the token was acquired with
+    pub username: String,
+    /// Seconds since the token was acquired or last refreshed
+    pub age_seconds: u64,
+    /// Seconds until the token is due for refresh, if the TTL is known
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ttl_remaining_seconds: Option<u64>,
+    pub use_count: u64,
+    pub error_count: u64,
+    pub app_error_count: u64,
+    /// Unix timestamp of the last use, if used at all
+    pub last_used: Option<u64>,
+    pub in_use: bool,
+    /// Client currently holding the token
+    pub holder: Option<String>,
+    /// Whether the token is withheld from clients
+    pub quarantined: bool,
+    pub refresh: RefreshState,
+}
+
+/// Number of token characters shown unmasked
+const TOKEN_PREFIX_CHARS: usize = 4;
+
+/// Show the first characters of a token value and mask the rest
+fn mask_token(value: &str) -> String {
+    let prefix: String = value.chars().take(TOKEN_PREFIX_CHARS).collect();
+    format!("{}****", prefix)
+}
+
+/// Per-client limits on concurrently held tokens
+#[derive(Clone, Debug, Default)]
+pub struct ClientQuotas {
+    /// Limit for clients without an explicit entry (unlimited if unset)
+    pub default_max: Option<usize>,
+    /// Explicit per-client limits
+    pub per_client: HashMap<ClientId, usize>,
+}
+
+impl ClientQuotas {
+    pub fn from_config(config: &ClientsConfig) -> Self {
+        Self {
+            default_max: config.max_tokens_per_client,
+            per_client: config
+                .entries
+                .iter()
+                .filter_map(|e| e.max_tokens.map(|max| (ClientId::new(&e.id), max)))
+                .collect(),
+        }
+    }
+
+    /// Maximum number of tokens the client may hold at once
+    pub fn limit(&self, client: &ClientId) -> usize {
+        self.per_client
+            .get(client)
+            .copied()
+            .or(self.default_max)
+            .unwrap_or(usize::MAX)
+    }
+}
+
+/// A request waiting for a token
+struct Waiter {
+    id: u64,
+    tx: oneshot::Sender<usize>,
+}
+
+/// Waiters of one priority class
+#[derive(Default)]
+struct ClassQueue {
+    /// Waiters per client, served in FIFO order within a client
+    queues: HashMap<ClientId, VecDeque<Waiter>>,
+    /// Clients with waiters, served round-robin
+    rotation: VecDeque<ClientId>,
+}
+
+impl ClassQueue {
+    fn has_waiters(&self, client: &ClientId) -> bool {
+        self.queues.get(client).is_some_and(|q| !q.is_empty())
+    }
+
+    fn push(&mut self, client: &ClientId, waiter: Waiter) {
+        let queue = self.queues.entry(client.clone()).or_default();
+        if queue.is_empty() {
+            self.rotation.push_back(client.clone());
+        }
+        queue.push_back(waiter);
+    }
+
+    /// Remove a waiter that gave up, returning whether it was still queued
+    fn remove(&mut self, client: &ClientId, waiter_id: u64) -> bool {
+        let Some(queue) = self.queues.get_mut(client) else {
+            return false;
+        };
+        match queue.iter().position(|w| w.id == waiter_id) {
+            Some(pos) => {
+                queue.remove(pos);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// Scheduling state, guarded by a single mutex
+#[derive(Default)]
+struct PoolState {
+    /// Idle token IDs
+    free: VecDeque<usize>,
+    /// Client currently holding each token
+    holders: HashMap<usize, ClientId>,
+    /// Number of tokens held per client
+    held: HashMap<ClientId, usize>,
+    /// Waiters per priority class, indexed by `Priority::index`
+    classes: [ClassQueue; Priority::COUNT],
+    quotas: ClientQuotas,
+    /// Free tokens that only high-priority waiters may take
+    reserved_high: usize,
+    next_waiter_id: u64,
+    /// Tokens withheld from clients until unquarantined
+    quarantined: HashSet<usize>,
+    /// Tokens removed by a resize while held, dropped once released
+    retired: HashSet<usize>,
+}
+
+impl PoolState {
+    fn held_by(&self, client: &ClientId) -> usize {
+        self.held.get(client).copied().unwrap_or(0)
+    }
+
+    fn has_quota(&self, client: &ClientId) -> bool {
+        self.held_by(client) < self.quotas.limit(client)
+    }
+
+    /// Whether a free token may go to a waiter of this priority
+    fn can_take(&self, priority: Priority) -> bool {
+        let reserved = match priority {
+            Priority::High => 0,
+            _ => self.reserved_high,
+        };
+        self.free.len() > reserved
+    }
+
+    fn assign(&mut self, token_id: usize, client: ClientId) {
+        *self.held.entry(client.clone()).or_insert(0) += 1;
+        self.holders.insert(token_id, client);
+    }
+
+    fn unassign(&mut self, token_id: usize) {
+        if let Some(client) = self.holders.remove(&token_id) {
+            if let Some(count) = self.held.get_mut(&client) {
+                *count -= 1;
+                if *count == 0 {
+                    self.held.remove(&client);
+                }
+            }
+        }
+    }
+
+    /// Take back a token from its holder, freeing it unless it is withheld
+    fn put_back(&mut self, token_id: usize) {
+        self.unassign(token_id);
+        if self.retired.remove(&token_id) || self.quarantined.contains(&token_id) {
+            return;
+        }
+        self.free.push_back(token_id);
+        self.dispatch();
+    }
+
+    /// Hand free tokens to waiters, highest priority first
+    fn dispatch(&mut self) {
+        for priority in Priority::ALL {
+            self.dispatch_class(priority);
+        }
+    }
+
+    /// Round-robin free tokens across clients of one class with quota left
+    fn dispatch_class(&mut self, priority: Priority) {
+        let mut skipped = 0;
+        while self.can_take(priority) && skipped < self.classes[priority.index()].rotation.len() {
+            let class = &mut self.classes[priority.index()];
+            let Some(client) = class.rotation.pop_front() else {
+                break;
+            };
+
+            if !self.has_quota(&client) {
+                self.classes[priority.index()].rotation.push_back(client);
+                skipped += 1;
+                continue;
+            }
+
+            let class = &mut self.classes[priority.index()];
+            let Some(waiter) = class.queues.get_mut(&client).and_then(|q| q.pop_front()) else {
+                class.queues.remove(&client);
+                continue;
+            };
+
+            let token_id = self.free.pop_front().expect("free checked above");
+            match waiter.tx.send(token_id) {
+                Ok(()) => {
+                    self.assign(token_id, client.clone());
+                    skipped = 0;
+                }
+                // Waiter gave up; keep the token for the next one
+                Err(token_id) => self.free.push_front(token_id),
+            }
+
+            let class = &mut self.classes[priority.index()];
+            if class.has_waiters(&client) {
+                class.rotation.push_back(client);
+            } else {
+                class.queues.remove(&client);
+            }
+        }
+    }
+}
+
+/// Token pool with per-client quotas and fair (round-robin) waiter ordering
 pub struct TokenPool {
-    /// Channel to receive available tokens (just IDs)
-    available_rx: Receiver<usize>,
-    /// Channel to return tokens
-    return_tx: Sender<usize>,
-    /// Total number of tokens in the pool
-    total_count: usize,
+    /// Name the pool is reported under in metrics
+    name: RwLock<String>,
+    /// Scheduling state (free tokens, holders, waiters)
+    state: Mutex<PoolState>,
+    /// Credential tokens added by a resize are acquired with
+    credential: Credential,
+    /// ID of the next token added to the pool
+    next_token_id: AtomicUsize,
+    /// Refreshes that failed since the last successful one
+    refresh_failures: AtomicU64,
     /// Number of tokens currently in use
     in_use: AtomicU64,
     /// Number of requests waiting for a token
     waiting: AtomicU64,
+    /// Number of requests waiting per priority class
+    waiting_by_priority: [AtomicU64; Priority::COUNT],
     /// Token metadata (id -> metadata)
     token_meta: DashMap<usize, TokenMeta>,
     /// Notify for refresh task
     refresh_notify: Arc<Notify>,
 }
 
+/// Removes a cancelled waiter from the queue, or returns a token it was handed
+struct PendingAcquire<'a> {
+    pool: &'a TokenPool,
+    client: ClientId,
+    priority: Priority,
+    waiter_id: u64,
+    rx: oneshot::Receiver<usize>,
+    done: bool,
+}
+
+impl Drop for PendingAcquire<'_> {
+    fn drop(&mut self) {
+        if self.done {
+            return;
+        }
+        self.pool.stop_waiting(self.priority);
+
+        {
+            let mut state = self.pool.state.lock();
+            let removed = state.classes[self.priority.index()].remove(&self.client, self.waiter_id);
+
+            // Already dispatched to us: give the token back
+            if !removed {
+                if let Ok(token_id) = self.rx.try_recv() {
+                    state.put_back(token_id);
+                }
+            }
+        }
+        self.pool.record_state();
+    }
+}
+
 impl TokenPool {
     /// Create a new token pool from tokens and a single credential (used for all tokens)
     pub fn new(tokens: Vec<String>, credential: Credential) -> Arc<Self> {
         let total_count = tokens.len();
         info!("Creating token pool with {} tokens", total_count);
 
-        // Create bounded channel with capacity = number of tokens
-        let (tx, rx) = async_channel::bounded(total_count);
-
-        // Initialize metadata and populate channel with token IDs
+        // Initialize metadata and mark all tokens as free
         let token_meta = DashMap::new();
+        let mut state = PoolState::default();
         for (id, value) in tokens.into_iter().enumerate() {
             token_meta.insert(id, TokenMeta::new(value, credential.clone()));
-            // Send token ID to channel
-            tx.try_send(id).expect("Channel should have capacity");
+            state.free.push_back(id);
         }
 
         Arc::new(Self {
-            available_rx: rx,
-            return_tx: tx,
-            total_count,
+            name: RwLock::new("default".to_string()),
+            state: Mutex::new(state),
+            credential,
+            next_token_id: AtomicUsize::new(total_count),
+            refresh_failures: AtomicU64::new(0),
             in_use: AtomicU64::new(0),
             waiting: AtomicU64::new(0),
+            waiting_by_priority: Default::default(),
             token_meta,
             refresh_notify: Arc::new(Notify::new()),
         })
     }
 
+    /// Set the name the pool is reported under in metrics
+    pub fn set_name(&self, name: &str) {
+        *self.name.write() = name.to_string();
+        self.record_state();
+    }
+
+    /// Get the name the pool is reported under in metrics
+    pub fn name(&self) -> String {
+        self.name.read().clone()
+    }
+
+    /// Replace the per-client quotas
+    pub fn set_quotas(&self, quotas: ClientQuotas) {
+        let mut state = self.state.lock();
+        state.quotas = quotas;
+        state.dispatch();
+    }
+
+    /// Set how many free tokens are held back for high-priority waiters
+    pub fn set_reserved_high(&self, reserved: usize) {
+        let mut state = self.state.lock();
+        state.reserved_high = reserved;
+        state.dispatch();
+    }
+
+    /// How many free tokens are held back for high-priority waiters
+    pub fn reserved_high(&self) -> usize {
+        self.state.lock().reserved_high
+    }
+
     /// Acquire a token from the pool, waiting indefinitely if none available
     pub async fn acquire(&self) -> Token {
-        // Increment waiting counter
+        self.acquire_for(&ClientId::anonymous(), Priority::Normal)
+            .await
+    }
+
+    /// Acquire a token on behalf of a client, waiting indefinitely if none is
+    /// available to its priority class or the client is at its quota
+    pub async fn acquire_for(&self, client: &ClientId, priority: Priority) -> Token {
+        let start = Instant::now();
+        let (waiter_id, rx) = {
+            let mut state = self.state.lock();
+
+            // Fast path: a free token, quota left and no earlier waiters of ours
+            let queued = state.classes[priority.index()].has_waiters(client);
+            if !queued && state.has_quota(client) && state.can_take(priority) {
+                if let Some(token_id) = state.free.pop_front() {
+                    state.assign(token_id, client.clone());
+                    drop(state);
+                    return self.checkout(token_id, start.elapsed());
+                }
+            }
+
+            let (tx, rx) = oneshot::channel();
+            let waiter_id = state.next_waiter_id;
+            state.next_waiter_id += 1;
+            state.classes[priority.index()].push(client, Waiter { id: waiter_id, tx });
+            (waiter_id, rx)
+        };
+
+        // Increment waiting counters
         self.waiting.fetch_add(1, Ordering::Relaxed);
+        self.waiting_by_priority[priority.index()].fetch_add(1, Ordering::Relaxed);
+        self.record_state();
 
         debug!(
-            "Waiting for token (in_use: {}, waiting: {})",
+            "Client '{}' waiting for {} priority token (in_use: {}, waiting: {})",
+            client,
+            priority,
             self.in_use.load(Ordering::Relaxed),
             self.waiting.load(Ordering::Relaxed)
         );
 
         // Wait for a token ID (blocks if pool is exhausted)
-        let token_id = self
-            .available_rx
-            .recv()
+        let mut pending = PendingAcquire {
+            pool: self,
+            client: client.clone(),
+            priority,
+            waiter_id,
+            rx,
+            done: false,
+        };
+        let token_id = (&mut pending.rx)
             .await
-            .expect("Channel closed unexpectedly");
+            .expect("Token pool dropped while waiting");
+        pending.done = true;
+
+        self.stop_waiting(priority);
+        self.checkout(token_id, start.elapsed())
+    }
 
-        // Update counters
+    fn stop_waiting(&self, priority: Priority) {
         self.waiting.fetch_sub(1, Ordering::Relaxed);
+        self.waiting_by_priority[priority.index()].fetch_sub(1, Ordering::Relaxed);
+    }
+
+    /// Record usage of a token handed to a caller after waiting for it
+    fn checkout(&self, token_id: usize, waited: Duration) -> Token {
         self.in_use.fetch_add(1, Ordering::Relaxed);
 
         // Get token value and record usage
@@ -174,6 +554,16 @@ impl TokenPool {
             self.available()
         );
 
+        if let Some(metrics) = get_metrics() {
+            metrics
+                .token_acquisitions
+                .add(1, &self.token_attrs(token_id));
+            metrics
+                .acquisition_wait_seconds
+                .record(waited.as_secs_f64(), &[KeyValue::new("pool", self.name())]);
+        }
+        self.record_state();
+
         Token {
             value,
             id: token_id,
@@ -187,15 +577,49 @@ impl TokenPool {
         // Decrement in_use counter
         self.in_use.fetch_sub(1, Ordering::Relaxed);
 
-        // Return token ID to the channel
-        if let Err(e) = self.return_tx.try_send(token_id) {
-            warn!("Failed to return token #{}: {}", token_id, e);
-        } else {
-            debug!(
-                "Released token #{} (in_use: {}, available: {})",
-                token_id,
-                self.in_use.load(Ordering::Relaxed),
-                self.available()
+        self.state.lock().put_back(token_id);
+
+        debug!(
+            "Released token #{} (in_use: {}, available: {})",
+            token_id,
+            self.in_use.load(Ordering::Relaxed),
+            self.available()
+        );
+
+        if let Some(metrics) = get_metrics() {
+            metrics.token_releases.add(1, &self.token_attrs(token_id));
+        }
+        self.record_state();
+    }
+
+    /// Attributes identifying a token on metrics
+    fn token_attrs(&self, token_id: usize) -> [KeyValue; 2] {
+        [
+            KeyValue::new("pool", self.name()),
+            KeyValue::new("token_bucket", token_bucket(token_id)),
+        ]
+    }
+
+    /// Report the pool state gauges
+    fn record_state(&self) {
+        let Some(metrics) = get_metrics() else {
+            return;
+        };
+
+        let attrs = [KeyValue::new("pool", self.name())];
+        metrics.tokens_total.record(self.total() as u64, &attrs);
+        metrics.tokens_in_use.record(self.in_use(), &attrs);
+        metrics
+            .tokens_available
+            .record(self.available() as u64, &attrs);
+        metrics.requests_waiting.record(self.waiting(), &attrs);
+        for priority in Priority::ALL {
+            metrics.requests_waiting_by_priority.record(
+                self.waiting_by_priority(priority),
+                &[
+                    KeyValue::new("pool", self.name()),
+                    KeyValue::new("priority", priority.as_str()),
+                ],
             );
         }
     }
@@ -210,25 +634,197 @@ impl TokenPool {
                 meta.error_count.load(Ordering::Relaxed)
             );
         }
+        if let Some(metrics) = get_metrics() {
+            metrics.token_errors.add(1, &self.token_attrs(token.id));
+        }
+    }
+
+    /// Record an application error DolphinDB reported for a token's request
+    pub fn mark_app_error(&self, token: &Token) {
+        if let Some(meta) = self.token_meta.get(&token.id) {
+            meta.app_error_count.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Get number of application errors recorded across all tokens
+    pub fn app_errors(&self) -> u64 {
+        self.token_meta
+            .iter()
+            .map(|entry| entry.value().app_error_count.load(Ordering::Relaxed))
+            .sum()
     }
 
     /// Mark token as needing refresh (e.g., got 401)
-    pub fn mark_needs_refresh(&self, token_id: usize) {
-        if let Some(meta) = self.token_meta.get(&token_id) {
-            meta.mark_needs_refresh();
-            info!("Token #{} marked for refresh", token_id);
-            self.refresh_notify.notify_one();
+    ///
+    /// Returns false if the pool has no such token.
+    pub fn mark_needs_refresh(&self, token_id: usize) -> bool {
+        let Some(meta) = self.token_meta.get(&token_id) else {
+            return false;
+        };
+        meta.mark_needs_refresh();
+        info!("Token #{} marked for refresh", token_id);
+        self.refresh_notify.notify_one();
+        true
+    }
+
+    /// Mark every token as needing refresh, returning how many were marked
+    pub fn mark_all_need_refresh(&self) -> usize {
+        let mut marked = 0;
+        for entry in self.token_meta.iter() {
+            entry.value().mark_needs_refresh();
+            marked += 1;
+        }
+        info!("All {} tokens marked for refresh", marked);
+        self.refresh_notify.notify_one();
+        marked
+    }
+
+    /// Withhold a token from clients; a token in use is withheld once released
+    ///
+    /// Returns false if the pool has no such token.
+    pub fn quarantine(&self, token_id: usize) -> bool {
+        if !self.token_meta.contains_key(&token_id) {
+            return false;
+        }
+        {
+            let mut state = self.state.lock();
+            state.quarantined.insert(token_id);
+            state.free.retain(|&id| id != token_id);
+        }
+        warn!("Token #{} quarantined", token_id);
+        self.record_state();
+        true
+    }
+
+    /// Hand a quarantined token to clients again
+    ///
+    /// Returns false if the token was not quarantined.
+    pub fn unquarantine(&self, token_id: usize) -> bool {
+        {
+            let mut state = self.state.lock();
+            if !state.quarantined.remove(&token_id) {
+                return false;
+            }
+            if !state.holders.contains_key(&token_id) {
+                state.free.push_back(token_id);
+                state.dispatch();
+            }
+        }
+        info!("Token #{} released from quarantine", token_id);
+        self.record_state();
+        true
+    }
+
+    /// Get number of quarantined tokens
+    pub fn quarantined(&self) -> usize {
+        self.state.lock().quarantined.len()
+    }
+
+    /// Get number of tokens clients can be served with: not quarantined and
+    /// not left stale by a failed refresh
+    pub fn usable(&self) -> usize {
+        let quarantined = self.state.lock().quarantined.clone();
+        self.token_meta
+            .iter()
+            .filter(|entry| {
+                !quarantined.contains(entry.key())
+                    && !entry.value().refresh_failed.load(Ordering::Relaxed)
+            })
+            .count()
+    }
+
+    /// Get the credential tokens added to the pool are acquired with
+    pub fn credential(&self) -> Credential {
+        self.credential.clone()
+    }
+
+    /// Add newly acquired tokens to the pool, returning their IDs
+    pub fn add_tokens(&self, tokens: Vec<String>) -> Vec<usize> {
+        let ids: Vec<usize> = tokens
+            .into_iter()
+            .map(|value| {
+                let id = self.next_token_id.fetch_add(1, Ordering::Relaxed);
+                self.token_meta
+                    .insert(id, TokenMeta::new(value, self.credential.clone()));
+                id
+            })
+            .collect();
+
+        {
+            let mut state = self.state.lock();
+            state.free.extend(&ids);
+            state.dispatch();
+        }
+        info!("Added {} tokens, pool size {}", ids.len(), self.total());
+        self.record_state();
+        ids
+    }
+
+    /// Remove tokens until at most `size` are left, returning the removed IDs
+    ///
+    /// Idle tokens go first, highest ID first. A removed token that is in use
+    /// is dropped once its holder releases it.
+    pub fn shrink_to(&self, size: usize) -> Vec<usize> {
+        let removed = {
+            let mut state = self.state.lock();
+            let mut ids: Vec<usize> = self.token_meta.iter().map(|entry| *entry.key()).collect();
+            ids.sort_unstable_by_key(|id| (state.holders.contains_key(id), usize::MAX - id));
+
+            let excess = ids.len().saturating_sub(size);
+            let removed: Vec<usize> = ids.into_iter().take(excess).collect();
+            for &id in &removed {
+                self.token_meta.remove(&id);
+                state.quarantined.remove(&id);
+                if state.holders.contains_key(&id) {
+                    state.retired.insert(id);
+                } else {
+                    state.free.retain(|&free| free != id);
+                }
+            }
+            removed
+        };
+
+        if !removed.is_empty() {
+            info!(
+                "Removed {} tokens, pool size {}",
+                removed.len(),
+                self.total()
+            );
+            self.record_state();
         }
+        removed
     }
 
     /// Update a token's value after refresh
     pub fn update_token(&self, token_id: usize, new_value: String) {
         if let Some(meta) = self.token_meta.get(&token_id) {
             meta.update(new_value);
+            self.refresh_failures.store(0, Ordering::Relaxed);
             info!("Token #{} refreshed", token_id);
         }
     }
 
+    /// Record that a refresh of the token has started
+    pub fn start_refresh(&self, token_id: usize) {
+        if let Some(meta) = self.token_meta.get(&token_id) {
+            meta.refreshing.store(true, Ordering::Relaxed);
+        }
+    }
+
+    /// Record that a refresh of the token failed, keeping its old value
+    pub fn fail_refresh(&self, token_id: usize) {
+        if let Some(meta) = self.token_meta.get(&token_id) {
+            meta.refreshing.store(false, Ordering::Relaxed);
+            meta.refresh_failed.store(true, Ordering::Relaxed);
+            self.refresh_failures.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Get number of refreshes that failed since the last successful one
+    pub fn refresh_failures(&self) -> u64 {
+        self.refresh_failures.load(Ordering::Relaxed)
+    }
+
     /// Get credential for a token (for refresh)
     pub fn get_credential(&self, token_id: usize) -> Option<Credential> {
         self.token_meta.get(&token_id).map(|m| m.credential.clone())
@@ -259,7 +855,7 @@ impl TokenPool {
 
     /// Get total number of tokens in the pool
     pub fn total(&self) -> usize {
-        self.total_count
+        self.token_meta.len()
     }
 
     /// Get number of tokens currently in use
@@ -269,7 +865,17 @@ impl TokenPool {
 
     /// Get number of available tokens
     pub fn available(&self) -> usize {
-        self.available_rx.len()
+        self.state.lock().free.len()
+    }
+
+    /// Get number of requests of a priority class waiting for a token
+    pub fn waiting_by_priority(&self, priority: Priority) -> u64 {
+        self.waiting_by_priority[priority.index()].load(Ordering::Relaxed)
+    }
+
+    /// Get number of tokens currently held by a client
+    pub fn held_by(&self, client: &ClientId) -> usize {
+        self.state.lock().held_by(client)
     }
 
     /// Get number of requests waiting for a token
@@ -287,6 +893,48 @@ impl TokenPool {
             )
         })
     }
+
+    /// Get a snapshot of every token, ordered by ID
+    ///
+    /// Given the token TTL, the time left until each token is due for
+    /// refresh is included.
+    pub fn token_stats(&self, ttl: Option<Duration>) -> Vec<TokenStats> {
+        let (holders, quarantined) = {
+            let state = self.state.lock();
+            (state.holders.clone(), state.quarantined.clone())
+        };
+
+        let mut stats: Vec<TokenStats> = self
+            .token_meta
+            .iter()
+            .map(|entry| {
+                let id = *entry.key();
+                let meta = entry.value();
+                let age = meta.acquired_at.read().elapsed();
+                let last_used = meta.last_used.load(Ordering::Relaxed);
+                let holder = holders.get(&id).map(ClientId::to_string);
+
+                TokenStats {
+                    id,
+                    prefix: mask_token(&meta.value.read()),
+                    username: meta.credential.username.clone(),
+                    age_seconds: age.as_secs(),
+                    ttl_remaining_seconds: ttl.map(|ttl| ttl.saturating_sub(age).as_secs()),
+                    use_count: meta.use_count.load(Ordering::Relaxed),
+                    error_count: meta.error_count.load(Ordering::Relaxed),
+                    app_error_count: meta.app_error_count.load(Ordering::Relaxed),
+                    last_used: (last_used > 0).then_some(last_used),
+                    in_use: holder.is_some(),
+                    holder,
+                    quarantined: quarantined.contains(&id),
+                    refresh: meta.refresh_state(ttl),
+                }
+            })
+            .collect();
+
+        stats.sort_by_key(|s| s.id);
+        stats
+    }
 }
 
 #[cfg(test)]
@@ -348,4 +996,170 @@ mod tests {
         let t2 = pool.acquire().await;
         assert_eq!(t2.value, "new_token");
     }
+
+    #[tokio::test]
+    async fn test_client_quota() {
+        let pool = TokenPool::new(
+            vec!["t1".to_string(), "t2".to_string(), "t3".to_string()],
+            make_cred(),
+        );
+        pool.set_quotas(ClientQuotas {
+            default_max: Some(1),
+            per_client: HashMap::new(),
+        });
+
+        let batch = ClientId::new("batch");
+        let t1 = pool.acquire_for(&batch, Priority::Normal).await;
+        assert_eq!(pool.held_by(&batch), 1);
+
+        // Over quota: waits even though tokens are free
+        let blocked = tokio::time::timeout(
+            Duration::from_millis(50),
+            pool.acquire_for(&batch, Priority::Normal),
+        );
+        assert!(blocked.await.is_err());
+        assert_eq!(pool.waiting(), 0);
+        assert_eq!(pool.available(), 2);
+
+        // Other clients are unaffected
+        let t2 = pool
+            .acquire_for(&ClientId::new("interactive"), Priority::Normal)
+            .await;
+        assert_eq!(pool.available(), 1);
+
+        pool.release(t1);
+        pool.release(t2);
+        assert_eq!(pool.held_by(&batch), 0);
+        assert_eq!(pool.available(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_fair_waiter_order() {
+        let pool = TokenPool::new(vec!["only".to_string()], make_cred());
+        let batch = ClientId::new("batch");
+        let interactive = ClientId::new("interactive");
+
+        let held = pool.acquire_for(&batch, Priority::Normal).await;
+
+        // Batch queues two waiters before the interactive client arrives
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        for client in [batch.clone(), batch.clone(), interactive.clone()] {
+            let pool = pool.clone();
+            let tx = tx.clone();
+            tokio::spawn(async move {
+                let token = pool.acquire_for(&client, Priority::Normal).await;
+                tx.send(client).unwrap();
+                tokio::time::sleep(Duration::from_millis(10)).await;
+                pool.release(token);
+            });
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(pool.waiting(), 3);
+
+        pool.release(held);
+        let order: Vec<ClientId> = vec![
+            rx.recv().await.unwrap(),
+            rx.recv().await.unwrap(),
+            rx.recv().await.unwrap(),
+        ];
+        assert_eq!(order, vec![batch.clone(), interactive, batch]);
+    }
+
+    #[tokio::test]
+    async fn test_priority_order_and_reservation() {
+        let pool = TokenPool::new(vec!["t1".to_string(), "t2".to_string()], make_cred());
+        pool.set_reserved_high(1);
+        let client = ClientId::new("c");
+
+        // Normal traffic cannot take the reserved token
+        let t1 = pool.acquire_for(&client, Priority::Normal).await;
+        let blocked = tokio::time::timeout(
+            Duration::from_millis(50),
+            pool.acquire_for(&client, Priority::Batch),
+        );
+        assert!(blocked.await.is_err());
+        let high = pool.acquire_for(&client, Priority::High).await;
+        assert_eq!(pool.available(), 0);
+
+        // Queue batch before normal; normal is served first on release
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        for priority in [Priority::Batch, Priority::Normal] {
+            let pool = pool.clone();
+            let tx = tx.clone();
+            let client = client.clone();
+            tokio::spawn(async move {
+                let token = pool.acquire_for(&client, priority).await;
+                tx.send(priority).unwrap();
+                pool.release(token);
+            });
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(pool.waiting_by_priority(Priority::Batch), 1);
+        assert_eq!(pool.waiting_by_priority(Priority::Normal), 1);
+
+        // Releasing one token leaves only the reserved one free
+        pool.release(high);
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert_eq!(pool.waiting(), 2);
+
+        pool.release(t1);
+        assert_eq!(rx.recv().await.unwrap(), Priority::Normal);
+        assert_eq!(rx.recv().await.unwrap(), Priority::Batch);
+    }
+
+    #[tokio::test]
+    async fn test_token_stats() {
+        let pool = TokenPool::new(
+            vec!["abcdef123456".to_string(), "xy".to_string()],
+            make_cred(),
+        );
+        let client = ClientId::new("etl");
+        let token = pool.acquire_for(&client, Priority::Normal).await;
+        pool.mark_needs_refresh(1);
+
+        let stats = pool.token_stats(Some(Duration::from_secs(3600)));
+        assert_eq!(stats.len(), 2);
+        assert_eq!(stats[0].prefix, "abcd****");
+        assert_eq!(stats[0].username, "testuser");
+        assert_eq!(stats[0].use_count, 1);
+        assert!(stats[0].last_used.is_some());
+        assert!(stats[0].in_use);
+        assert_eq!(stats[0].holder.as_deref(), Some("etl"));
+        assert!(stats[0].ttl_remaining_seconds.unwrap() > 3590);
+        assert_eq!(stats[0].refresh, RefreshState::Current);
+        assert_eq!(stats[1].prefix, "xy****");
+        assert!(!stats[1].in_use);
+        assert_eq!(stats[1].last_used, None);
+        assert_eq!(stats[1].refresh, RefreshState::Pending);
+
+        pool.start_refresh(1);
+        assert_eq!(pool.token_stats(None)[1].refresh, RefreshState::Refreshing);
+        pool.fail_refresh(1);
+        assert_eq!(pool.token_stats(None)[1].refresh, RefreshState::Failed);
+        pool.update_token(1, "new-token".to_string());
+        assert_eq!(pool.token_stats(None)[1].refresh, RefreshState::Current);
+
+        pool.release(token);
+        assert_eq!(pool.token_stats(None)[0].holder, None);
+    }
+
+    #[tokio::test]
+    async fn test_usable_tokens() {
+        let pool = TokenPool::new(
+            vec!["t0".to_string(), "t1".to_string(), "t2".to_string()],
+            make_cred(),
+        );
+        assert_eq!(pool.usable(), 3);
+
+        assert!(pool.quarantine(0));
+        pool.fail_refresh(1);
+        pool.fail_refresh(1);
+        assert_eq!(pool.usable(), 1);
+        assert_eq!(pool.refresh_failures(), 2);
+
+        pool.update_token(1, "t1-new".to_string());
+        assert!(pool.unquarantine(0));
+        assert_eq!(pool.usable(), 3);
+        assert_eq!(pool.refresh_failures(), 0);
+    }
 }
@@ -1,9 +1,11 @@
 use std::sync::Arc;
 use std::time::Duration;
 
+use opentelemetry::KeyValue;
 use tokio::time::{interval, timeout};
 use tracing::{debug, error, info, warn};
 
+use crate::telemetry::get_metrics;
 use crate::token_acquirer::TokenAcquirer;
 use crate::token_pool::TokenPool;
 
@@ -96,17 +98,34 @@ impl TokenRefresher {
         };
 
         // Try to refresh with timeout
-        match timeout(Duration::from_secs(30), self.acquirer.refresh(&credential)).await {
-            Ok(Ok(new_token)) => {
-                self.pool.update_token(token_id, new_token);
-                info!("Successfully refreshed token #{}", token_id);
-            }
-            Ok(Err(e)) => {
-                error!("Failed to refresh token #{}: {}", token_id, e);
-            }
-            Err(_) => {
-                error!("Timeout refreshing token #{}", token_id);
-            }
+        self.pool.start_refresh(token_id);
+        let outcome =
+            match timeout(Duration::from_secs(30), self.acquirer.refresh(&credential)).await {
+                Ok(Ok(new_token)) => {
+                    self.pool.update_token(token_id, new_token);
+                    info!("Successfully refreshed token #{}", token_id);
+                    "success"
+                }
+                Ok(Err(e)) => {
+                    error!("Failed to refresh token #{}: {}", token_id, e);
+                    self.pool.fail_refresh(token_id);
+                    "failure"
+                }
+                Err(_) => {
+                    error!("Timeout refreshing token #{}", token_id);
+                    self.pool.fail_refresh(token_id);
+                    "timeout"
+                }
+            };
+
+        if let Some(metrics) = get_metrics() {
+            metrics.token_refreshes.add(
+                1,
+                &[
+                    KeyValue::new("pool", self.pool.name()),
+                    KeyValue::new("outcome", outcome),
+                ],
+            );
         }
     }
 }
@@ -0,0 +1,106 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use parking_lot::RwLock;
+use tokio::net::TcpStream;
+use tokio::time::{interval, timeout};
+use tracing::{info, warn};
+
+use crate::config::ReadinessConfig;
+
+/// Background check that the upstream nodes accept connections
+pub struct UpstreamProbe {
+    nodes: Vec<String>,
+    interval: Duration,
+    timeout: Duration,
+    /// Outcome of the last probe, unset until the first one finishes
+    status: RwLock<Option<Result<(), String>>>,
+}
+
+impl UpstreamProbe {
+    pub fn new(nodes: Vec<String>, config: &ReadinessConfig) -> Self {
+        Self {
+            nodes,
+            interval: Duration::from_secs(config.upstream_check_interval_seconds),
+            timeout: Duration::from_secs(config.upstream_check_timeout_seconds),
+            status: RwLock::new(None),
+        }
+    }
+
+    /// Outcome of the last probe: fine if any node accepted a connection
+    pub fn status(&self) -> Result<(), String> {
+        self.status
+            .read()
+            .clone()
+            .unwrap_or_else(|| Err("not probed yet".to_string()))
+    }
+
+    /// Probe every node until one accepts a connection
+    pub async fn probe(&self) {
+        let mut errors = Vec::new();
+        for node in &self.nodes {
+            match timeout(self.timeout, TcpStream::connect(node.as_str())).await {
+                Ok(Ok(_)) => {
+                    errors.clear();
+                    break;
+                }
+                Ok(Err(e)) => errors.push(format!("{}: {}", node, e)),
+                Err(_) => errors.push(format!("{}: timed out", node)),
+            }
+        }
+
+        let status = if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors.join(", "))
+        };
+
+        let previous = self.status.write().replace(status.clone());
+        match (previous, status) {
+            (Some(Ok(())) | None, Err(e)) => warn!("Upstream unreachable: {}", e),
+            (Some(Err(_)), Ok(())) => info!("Upstream reachable again"),
+            _ => {}
+        }
+    }
+
+    /// Probe the upstream periodically
+    pub async fn run(self: Arc<Self>) {
+        let mut ticker = interval(self.interval);
+        loop {
+            ticker.tick().await;
+            self.probe().await;
+        }
+    }
+}
+
+/// Spawn the upstream probe as a background task
+pub fn spawn_upstream_probe(probe: Arc<UpstreamProbe>) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(probe.run())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_probe_reports_reachability() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let alive = listener.local_addr().unwrap().to_string();
+        let dead = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .to_string();
+        let config = ReadinessConfig::default();
+
+        let probe = UpstreamProbe::new(vec![dead.clone()], &config);
+        assert!(probe.status().is_err());
+        probe.probe().await;
+        assert!(probe.status().unwrap_err().contains(&dead));
+
+        // Any reachable node will do
+        let probe = UpstreamProbe::new(vec![dead, alive], &config);
+        probe.probe().await;
+        assert!(probe.status().is_ok());
+    }
+}